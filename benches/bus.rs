@@ -0,0 +1,72 @@
+//! Port I/O exit dispatch: the time a vCPU thread takes to handle an `IoIn` or `IoOut` exit
+//! through the `Arc<Bus>` it shares with the other vCPU threads, from finding the device of the
+//! port to running its access. Run with `cargo bench --bench bus`.
+
+use std::hint::black_box;
+use std::sync::{Arc, Barrier};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use kvm_box::devices::{BootTimes, Bus, BusDevice, PostCodeDevice};
+
+/// Ports between device bases, and ports each device covers.
+const STRIDE: u16 = 0x10;
+const DEVICE_LEN: u64 = 8;
+/// Exits each vCPU thread handles.
+const EXITS: u32 = 1_000_000;
+
+fn io_bus(count: u16) -> Arc<Bus> {
+    let times = Arc::new(BootTimes::new(Instant::now()));
+    let mut bus = Bus::new();
+    for i in 0..count {
+        let device = BusDevice::PostCode(PostCodeDevice::new(times.clone()));
+        bus.insert(Arc::new(device), u64::from(i * STRIDE), DEVICE_LEN)
+            .expect("failed to insert device");
+    }
+
+    Arc::new(bus)
+}
+
+/// Runs `exit` on the ports of every device in turn from `vcpus` threads at once, and returns
+/// the mean time per exit a thread sees.
+fn measure(bus: &Arc<Bus>, count: u16, vcpus: usize, exit: fn(&Bus, u16) -> bool) -> Duration {
+    let start = Arc::new(Barrier::new(vcpus));
+    let threads: Vec<_> = (0..vcpus)
+        .map(|_| {
+            let bus = bus.clone();
+            let start = start.clone();
+            thread::spawn(move || {
+                start.wait();
+                let begin = Instant::now();
+                for i in 0..EXITS {
+                    let port = (i % u32::from(count)) as u16 * STRIDE;
+                    assert!(exit(&bus, black_box(port)));
+                }
+                begin.elapsed()
+            })
+        })
+        .collect();
+
+    let total: Duration = threads
+        .into_iter()
+        .map(|t| t.join().expect("vcpu thread panicked"))
+        .sum();
+    total / (EXITS * vcpus as u32)
+}
+
+fn main() {
+    for count in [4, 64] {
+        let bus = io_bus(count);
+        for vcpus in [1, 4] {
+            // Every exit writes the same code so the device does not log.
+            let io_out = measure(&bus, count, vcpus, |bus, port| bus.io_out(port, &[0x42]));
+            let io_in = measure(&bus, count, vcpus, |bus, port| {
+                bus.io_in(port, &mut [0u8; 1])
+            });
+            println!(
+                "{:>3} devices, {} vcpus: IoOut {:>8?}/exit, IoIn {:>8?}/exit",
+                count, vcpus, io_out, io_in
+            );
+        }
+    }
+}
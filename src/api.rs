@@ -0,0 +1,160 @@
+use std::io::{BufRead, BufReader, Write};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use log::{error, info};
+
+use crate::snapshot::SnapshotType;
+use crate::socket::SocketUrl;
+
+/// Requests accepted on the API socket, one per line.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ApiRequest {
+    /// Stop all vCPUs and freeze the guest clock.
+    Pause,
+    /// Let the vCPUs run again.
+    Resume,
+    /// Write a snapshot of the paused VM into a directory.
+    Snapshot {
+        path: PathBuf,
+        snapshot_type: SnapshotType,
+    },
+    /// Report the number of guest pages dirtied since the last snapshot.
+    DirtyPages,
+    /// Report the MAC address and vsock CID of the VM.
+    Identity,
+    /// Live migrate the VM to a destination waiting on `url`.
+    Migrate { url: SocketUrl },
+    /// Write an ELF core file of the guest.
+    CoreDump { path: PathBuf },
+}
+
+impl std::str::FromStr for ApiRequest {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let mut words = s.split_whitespace();
+        let request = match words.next() {
+            Some("pause") => ApiRequest::Pause,
+            Some("resume") => ApiRequest::Resume,
+            Some("snapshot") => {
+                let path = words
+                    .next()
+                    .ok_or(anyhow::anyhow!("usage: snapshot PATH [full|diff]"))?;
+                let snapshot_type = match words.next() {
+                    Some(t) => t.parse()?,
+                    None => SnapshotType::Full,
+                };
+
+                ApiRequest::Snapshot {
+                    path: path.into(),
+                    snapshot_type,
+                }
+            }
+            Some("dirty-pages") => ApiRequest::DirtyPages,
+            Some("identity") => ApiRequest::Identity,
+            Some("migrate") => {
+                let url = words
+                    .next()
+                    .ok_or(anyhow::anyhow!("usage: migrate unix:PATH|tcp:IP:PORT"))?;
+
+                ApiRequest::Migrate {
+                    url: SocketUrl::parse_numeric(url)?,
+                }
+            }
+            Some("coredump") => {
+                let path = words
+                    .next()
+                    .ok_or(anyhow::anyhow!("usage: coredump PATH"))?;
+
+                ApiRequest::CoreDump { path: path.into() }
+            }
+            Some(cmd) => anyhow::bail!("unknown request: {}", cmd),
+            None => anyhow::bail!("empty request"),
+        };
+
+        if words.next().is_some() {
+            anyhow::bail!("unexpected arguments for request: {}", s.trim())
+        }
+
+        Ok(request)
+    }
+}
+
+/// A line based control socket. Each connection carries one request and gets one response
+/// line, either `ok` or `error: <reason>`.
+#[derive(Debug)]
+pub struct ApiServer {
+    listener: UnixListener,
+    path: PathBuf,
+}
+
+impl ApiServer {
+    const CLIENT_TIMEOUT: Duration = Duration::from_secs(1);
+
+    pub fn bind<P: AsRef<Path>>(path: P) -> Result<ApiServer> {
+        let path = path.as_ref().to_path_buf();
+
+        // A socket file left over by a previous run would make bind() fail.
+        if path.exists() {
+            std::fs::remove_file(&path).context("failed to remove stale api socket")?;
+        }
+
+        let listener = UnixListener::bind(&path).context("failed to bind api socket")?;
+        info!("api server listening on {}", path.display());
+
+        Ok(ApiServer { listener, path })
+    }
+
+    pub fn listener(&self) -> &UnixListener {
+        &self.listener
+    }
+
+    /// Accepts one connection and answers its request with `handler`.
+    pub fn handle_connection<F>(&self, handler: F) -> Result<()>
+    where
+        F: FnOnce(ApiRequest) -> Result<String>,
+    {
+        let (stream, _) = self
+            .listener
+            .accept()
+            .context("failed to accept api connection")?;
+        stream.set_read_timeout(Some(Self::CLIENT_TIMEOUT))?;
+        stream.set_write_timeout(Some(Self::CLIENT_TIMEOUT))?;
+
+        let mut line = String::new();
+        BufReader::new(&stream)
+            .read_line(&mut line)
+            .context("failed to read api request")?;
+
+        let response = line.parse::<ApiRequest>().and_then(|request| {
+            info!("api request: {:?}", request);
+            handler(request)
+        });
+
+        Self::respond(&stream, response)
+    }
+
+    fn respond(mut stream: &UnixStream, response: Result<String>) -> Result<()> {
+        let line = match response {
+            Ok(body) if body.is_empty() => String::from("ok\n"),
+            Ok(body) => format!("ok {}\n", body),
+            Err(e) => {
+                error!("api request failed: {:#}", e);
+                format!("error: {:#}\n", e)
+            }
+        };
+
+        stream
+            .write_all(line.as_bytes())
+            .context("failed to write api response")
+    }
+}
+
+impl Drop for ApiServer {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
@@ -15,13 +15,23 @@ pub struct BootSourceConfig {
 }
 
 impl BootSourceConfig {
-    pub fn to_kernel_cmdline(&self) -> Result<(Cmdline, usize)> {
-        let cmdline_str = match self.boot_args.as_ref() {
+    /// The split irqchip has no PIC, its guests get their interrupts from the IOAPIC only and
+    /// `noapic` is dropped from their cmdline.
+    pub fn to_kernel_cmdline(&self, irqchip_mode: IrqChipMode) -> Result<(Cmdline, usize)> {
+        let mut cmdline_str = match self.boot_args.as_ref() {
             None => super::DEFAULT_KERNEL_CMDLINE,
             Some(str) => str.as_str(),
-        };
+        }
+        .to_string();
+        if irqchip_mode == IrqChipMode::Split {
+            cmdline_str = cmdline_str
+                .split_whitespace()
+                .filter(|arg| *arg != "noapic")
+                .collect::<Vec<_>>()
+                .join(" ");
+        }
 
-        let cmdline = Cmdline::try_from(cmdline_str, super::layout::CMDLINE_MAX_SIZE)?;
+        let cmdline = Cmdline::try_from(&cmdline_str, super::layout::CMDLINE_MAX_SIZE)?;
 
         let size = cmdline
             .as_cstring()
@@ -39,3 +49,482 @@ pub struct InitrdConfig {
     /// Size of initrd in guest memory
     pub size: usize,
 }
+
+/// Virtual CPU topology exposed to the guest.
+///
+/// The number of vCPUs created for the microvm is `sockets * cores * threads`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CpuTopology {
+    /// Number of CPU packages.
+    pub sockets: u8,
+    /// Number of cores per package.
+    pub cores: u8,
+    /// Number of hardware threads per core.
+    pub threads: u8,
+}
+
+impl Default for CpuTopology {
+    fn default() -> Self {
+        CpuTopology {
+            sockets: 1,
+            cores: 1,
+            threads: 1,
+        }
+    }
+}
+
+impl CpuTopology {
+    /// Total number of vCPUs described by this topology.
+    pub fn vcpu_count(&self) -> u8 {
+        self.sockets * self.cores * self.threads
+    }
+
+    /// Number of logical processors sharing one package.
+    pub fn threads_per_package(&self) -> u8 {
+        self.cores * self.threads
+    }
+
+    /// Width of the SMT field in the x2APIC ID.
+    pub fn thread_shift(&self) -> u32 {
+        Self::field_width(self.threads)
+    }
+
+    /// Width of the SMT and core fields together in the x2APIC ID.
+    pub fn core_shift(&self) -> u32 {
+        self.thread_shift() + Self::field_width(self.cores)
+    }
+
+    /// Returns the APIC ID of the vCPU with the given index.
+    ///
+    /// vCPUs are numbered threads first, then cores, then sockets, and every level gets a
+    /// power-of-two sized field in the APIC ID, the same as on real hardware.
+    pub fn apic_id(&self, cpu_index: u8) -> u32 {
+        let cpu_index = u32::from(cpu_index);
+        let threads = u32::from(self.threads);
+        let cores = u32::from(self.cores);
+
+        let thread = cpu_index % threads;
+        let core = (cpu_index / threads) % cores;
+        let socket = cpu_index / (threads * cores);
+
+        (socket << self.core_shift()) | (core << self.thread_shift()) | thread
+    }
+
+    fn field_width(count: u8) -> u32 {
+        u32::from(count).next_power_of_two().trailing_zeros()
+    }
+}
+
+impl std::str::FromStr for CpuTopology {
+    type Err = anyhow::Error;
+
+    /// Parses a topology from a `sockets=N,cores=N,threads=N` string. Omitted keys default to 1.
+    fn from_str(s: &str) -> Result<Self> {
+        let mut topology = CpuTopology::default();
+
+        for item in s.split(',').filter(|item| !item.is_empty()) {
+            let (key, value) = item
+                .split_once('=')
+                .ok_or(anyhow::anyhow!("invalid topology item: {}", item))?;
+
+            let value: u8 = value
+                .parse()
+                .map_err(|_| anyhow::anyhow!("invalid topology value: {}", item))?;
+            if value == 0 {
+                anyhow::bail!("topology value must be greater than zero: {}", item)
+            }
+
+            match key {
+                "sockets" => topology.sockets = value,
+                "cores" => topology.cores = value,
+                "threads" => topology.threads = value,
+                _ => anyhow::bail!("unknown topology key: {}", key),
+            }
+        }
+
+        let vcpu_count =
+            u32::from(topology.sockets) * u32::from(topology.cores) * u32::from(topology.threads);
+        if vcpu_count > u32::from(super::MAX_SUPPORTED_CPUS) {
+            anyhow::bail!(
+                "topology describes {} vcpus, at most {} are supported",
+                vcpu_count,
+                super::MAX_SUPPORTED_CPUS
+            )
+        }
+
+        Ok(topology)
+    }
+}
+
+/// KVM paravirtual features advertised to the guest in the `KVM_CPUID_FEATURES` leaf.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KvmPvFeatures {
+    /// kvmclock clocksource, including the stable bit.
+    pub kvmclock: bool,
+    /// Asynchronous page faults.
+    pub async_pf: bool,
+    /// Steal time accounting.
+    pub steal_time: bool,
+    /// Paravirtual end of interrupt.
+    pub pv_eoi: bool,
+    /// Paravirtual spinlock unhalt.
+    pub pv_unhalt: bool,
+    /// Paravirtual TLB flush.
+    pub pv_tlb_flush: bool,
+    /// Paravirtual send IPI hypercall.
+    pub pv_send_ipi: bool,
+}
+
+impl Default for KvmPvFeatures {
+    fn default() -> Self {
+        KvmPvFeatures {
+            kvmclock: true,
+            async_pf: true,
+            steal_time: true,
+            pv_eoi: true,
+            pv_unhalt: true,
+            pv_tlb_flush: true,
+            pv_send_ipi: true,
+        }
+    }
+}
+
+impl KvmPvFeatures {
+    const NAMES: [&'static str; 7] = [
+        "kvmclock",
+        "async-pf",
+        "steal-time",
+        "pv-eoi",
+        "pv-unhalt",
+        "pv-tlb-flush",
+        "pv-send-ipi",
+    ];
+
+    fn none() -> Self {
+        KvmPvFeatures {
+            kvmclock: false,
+            async_pf: false,
+            steal_time: false,
+            pv_eoi: false,
+            pv_unhalt: false,
+            pv_tlb_flush: false,
+            pv_send_ipi: false,
+        }
+    }
+
+    fn flag_mut(&mut self, name: &str) -> Option<&mut bool> {
+        match name {
+            "kvmclock" => Some(&mut self.kvmclock),
+            "async-pf" => Some(&mut self.async_pf),
+            "steal-time" => Some(&mut self.steal_time),
+            "pv-eoi" => Some(&mut self.pv_eoi),
+            "pv-unhalt" => Some(&mut self.pv_unhalt),
+            "pv-tlb-flush" => Some(&mut self.pv_tlb_flush),
+            "pv-send-ipi" => Some(&mut self.pv_send_ipi),
+            _ => None,
+        }
+    }
+
+    /// The flags of the features, in the order of `NAMES`.
+    fn flags(&self) -> [bool; 7] {
+        [
+            self.kvmclock,
+            self.async_pf,
+            self.steal_time,
+            self.pv_eoi,
+            self.pv_unhalt,
+            self.pv_tlb_flush,
+            self.pv_send_ipi,
+        ]
+    }
+
+    /// Names of the enabled features, in the same syntax accepted by `from_str`.
+    pub fn enabled_names(&self) -> Vec<&'static str> {
+        Self::NAMES
+            .iter()
+            .zip(self.flags())
+            .filter_map(|(name, enabled)| enabled.then_some(*name))
+            .collect()
+    }
+
+    /// The features as a mask with a bit per feature in the order of `NAMES`, as saved in the
+    /// vm state.
+    pub fn bits(&self) -> u32 {
+        self.flags()
+            .iter()
+            .enumerate()
+            .fold(0, |bits, (i, &enabled)| bits | (u32::from(enabled) << i))
+    }
+
+    /// The inverse of `bits`, unknown bits are an error.
+    pub fn from_bits(bits: u32) -> Result<Self> {
+        if bits >> Self::NAMES.len() != 0 {
+            anyhow::bail!("unknown pv feature bits {:#x}", bits)
+        }
+
+        let mut features = KvmPvFeatures::none();
+        for (i, name) in Self::NAMES.iter().enumerate() {
+            if let Some(flag) = features.flag_mut(name) {
+                *flag = bits & (1 << i) != 0;
+            }
+        }
+
+        Ok(features)
+    }
+}
+
+impl std::str::FromStr for KvmPvFeatures {
+    type Err = anyhow::Error;
+
+    /// Parses a comma separated feature list, e.g. `kvmclock,steal-time`. `none` disables every
+    /// feature and `default` selects the default set. A feature prefixed with `-` is removed
+    /// from the set built so far, so `default,-async-pf` keeps everything but async PF. A list
+    /// of removals only starts from the default set, `-async-pf` is the same as the former.
+    fn from_str(s: &str) -> Result<Self> {
+        let items: Vec<_> = s.split(',').filter(|item| !item.is_empty()).collect();
+        let removals_only = !items.is_empty() && items.iter().all(|item| item.starts_with('-'));
+        let mut features = if removals_only {
+            KvmPvFeatures::default()
+        } else {
+            KvmPvFeatures::none()
+        };
+
+        for item in items {
+            match item {
+                "none" => features = KvmPvFeatures::none(),
+                "default" => features = KvmPvFeatures::default(),
+                _ => {
+                    let (name, enabled) = match item.strip_prefix('-') {
+                        Some(name) => (name, false),
+                        None => (item, true),
+                    };
+
+                    let flag = features.flag_mut(name).ok_or(anyhow::anyhow!(
+                        "unknown pv feature: {}, expected one of {}",
+                        name,
+                        Self::NAMES.join(",")
+                    ))?;
+                    *flag = enabled;
+                }
+            }
+        }
+
+        Ok(features)
+    }
+}
+
+/// Configuration applied to every vCPU of the microvm.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct VcpuConfig {
+    /// Sockets, cores and threads exposed to the guest.
+    pub topology: CpuTopology,
+    /// KVM paravirtual features exposed to the guest.
+    pub pv_features: KvmPvFeatures,
+    /// Guest TSC frequency in kHz. The host frequency is kept if this is not set.
+    pub tsc_khz: Option<u32>,
+}
+
+/// Backing store of the guest RAM.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub enum MemoryBackend {
+    /// Private anonymous memory.
+    #[default]
+    Anon,
+    /// Shared memory from an anonymous memfd, so that the fd can be handed to other processes.
+    Memfd,
+    /// Shared mapping of a file, created if it does not exist.
+    File(std::path::PathBuf),
+    /// Private mapping of the memory file of a full snapshot. The file is never written, clones
+    /// of one snapshot share its pages until they write to them.
+    Template(std::path::PathBuf),
+}
+
+impl MemoryBackend {
+    /// Whether the guest memory reads as zeroes until it is written. File and template backends
+    /// map the existing contents of their file.
+    pub fn starts_zeroed(&self) -> bool {
+        matches!(self, MemoryBackend::Anon | MemoryBackend::Memfd)
+    }
+}
+
+impl std::str::FromStr for MemoryBackend {
+    type Err = anyhow::Error;
+
+    /// Parses `anon`, `memfd` or `file:PATH`.
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "anon" => Ok(MemoryBackend::Anon),
+            "memfd" => Ok(MemoryBackend::Memfd),
+            _ => match s.strip_prefix("file:") {
+                Some(path) if !path.is_empty() => Ok(MemoryBackend::File(path.into())),
+                _ => anyhow::bail!(
+                    "invalid memory backend: {}, expected anon|memfd|file:PATH",
+                    s
+                ),
+            },
+        }
+    }
+}
+
+/// Huge page size used to back the guest RAM.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HugePageSize {
+    Size2M,
+    Size1G,
+}
+
+impl HugePageSize {
+    pub fn bytes(&self) -> usize {
+        match self {
+            HugePageSize::Size2M => 2 << 20,
+            HugePageSize::Size1G => 1 << 30,
+        }
+    }
+
+    /// Page size encoding used by the MAP_HUGE_* and MFD_HUGE_* flags.
+    pub fn shift(&self) -> i32 {
+        self.bytes().trailing_zeros() as i32
+    }
+}
+
+impl std::str::FromStr for HugePageSize {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "2M" => Ok(HugePageSize::Size2M),
+            "1G" => Ok(HugePageSize::Size1G),
+            _ => anyhow::bail!("invalid huge page size: {}, expected 2M|1G", s),
+        }
+    }
+}
+
+/// Configuration of the guest RAM.
+#[derive(Debug, Clone, Default)]
+pub struct MemoryConfig {
+    /// Size of the guest RAM in bytes.
+    pub ram_size: u64,
+    /// Where the guest RAM comes from.
+    pub backend: MemoryBackend,
+    /// Back the guest RAM with huge pages of this size.
+    pub hugepages: Option<HugePageSize>,
+    /// Populate the whole guest RAM at startup instead of on first touch.
+    pub prefault: bool,
+    /// Lock the guest RAM in host memory.
+    pub mlock: bool,
+    /// Let KSM merge identical guest pages (MADV_MERGEABLE).
+    pub merge: bool,
+    /// Exclude the guest RAM from VMM core dumps (MADV_DONTDUMP).
+    pub dontdump: bool,
+}
+
+/// Where the interrupt controllers are emulated.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum IrqChipMode {
+    /// PIC, IOAPIC and PIT in KVM.
+    #[default]
+    Kernel,
+    /// Only the local APICs in KVM, the IOAPIC in the VMM delivers interrupts as MSIs. There is
+    /// no PIC and no PIT.
+    Split,
+}
+
+impl std::str::FromStr for IrqChipMode {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "kernel" => Ok(IrqChipMode::Kernel),
+            "split" => Ok(IrqChipMode::Split),
+            _ => anyhow::bail!("invalid irqchip mode: {}, expected kernel|split", s),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_topology_from_str() {
+        let topology: CpuTopology = "sockets=2,cores=3,threads=2".parse().unwrap();
+        assert_eq!(
+            topology,
+            CpuTopology {
+                sockets: 2,
+                cores: 3,
+                threads: 2
+            }
+        );
+        assert_eq!(topology.vcpu_count(), 12);
+        assert_eq!(topology.threads_per_package(), 6);
+
+        // Omitted keys default to 1.
+        let topology: CpuTopology = "cores=4".parse().unwrap();
+        assert_eq!(
+            (topology.sockets, topology.cores, topology.threads),
+            (1, 4, 1)
+        );
+        assert_eq!("".parse::<CpuTopology>().unwrap(), CpuTopology::default());
+
+        for invalid in [
+            "cores",
+            "cores=x",
+            "cores=0",
+            "cores=256",
+            "dies=2",
+            "sockets=4,cores=4,threads=4",
+        ] {
+            assert!(invalid.parse::<CpuTopology>().is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn test_topology_apic_id() {
+        let topology = CpuTopology::default();
+        assert_eq!((topology.thread_shift(), topology.core_shift()), (0, 0));
+        assert_eq!(topology.apic_id(0), 0);
+
+        // 1 bit of threads and 2 bits of cores, the IDs of the 4th core are left unused.
+        let topology: CpuTopology = "sockets=2,cores=3,threads=2".parse().unwrap();
+        assert_eq!((topology.thread_shift(), topology.core_shift()), (1, 3));
+        let apic_ids: Vec<u32> = (0..topology.vcpu_count())
+            .map(|cpu_index| topology.apic_id(cpu_index))
+            .collect();
+        assert_eq!(apic_ids, [0, 1, 2, 3, 4, 5, 8, 9, 10, 11, 12, 13]);
+    }
+
+    #[test]
+    fn test_pv_features_bits() {
+        assert_eq!(KvmPvFeatures::default().bits(), 0x7f);
+        assert_eq!(KvmPvFeatures::none().bits(), 0);
+
+        let features: KvmPvFeatures = "-async-pf,-pv-send-ipi".parse().unwrap();
+        assert_eq!(features.bits(), 0x3d);
+        assert_eq!(KvmPvFeatures::from_bits(0x3d).unwrap(), features);
+        assert!(KvmPvFeatures::from_bits(0x80).is_err());
+    }
+
+    #[test]
+    fn test_pv_features_from_str() {
+        let features: KvmPvFeatures = "kvmclock,steal-time".parse().unwrap();
+        assert_eq!(features.enabled_names(), ["kvmclock", "steal-time"]);
+
+        let features: KvmPvFeatures = "default,-async-pf".parse().unwrap();
+        assert!(!features.async_pf);
+        assert_eq!(
+            features.enabled_names().len(),
+            KvmPvFeatures::NAMES.len() - 1
+        );
+
+        // Removals alone apply to the default set.
+        let removed: KvmPvFeatures = "-async-pf".parse().unwrap();
+        assert_eq!(removed, features);
+
+        let features: KvmPvFeatures = "none,kvmclock,-kvmclock".parse().unwrap();
+        assert_eq!(features, KvmPvFeatures::none());
+        assert_eq!("".parse::<KvmPvFeatures>().unwrap(), KvmPvFeatures::none());
+
+        assert!("kvmclock,bogus".parse::<KvmPvFeatures>().is_err());
+    }
+}
@@ -2,10 +2,16 @@ pub mod x86_64;
 pub use x86_64::*;
 
 pub mod config;
-pub use config::{BootSourceConfig, InitrdConfig};
+pub use config::{
+    BootSourceConfig, CpuTopology, HugePageSize, InitrdConfig, IrqChipMode, KvmPvFeatures,
+    MemoryBackend, MemoryConfig, VcpuConfig,
+};
 
 /// Default (smallest) memory page size for the supported architectures.
 pub const PAGE_SIZE: usize = 4096;
 
+/// Maximum number of vCPUs, bounded by the room for the MP table in the EBDA.
+pub const MAX_SUPPORTED_CPUS: u8 = 32;
+
 pub const DEFAULT_KERNEL_CMDLINE: &str =
     "console=ttyS0 noapic noacpi reboot=k panic=1 pci=off nomodule";
@@ -0,0 +1,36 @@
+use anyhow::{Context, Result};
+use kvm_bindings::kvm_clock_data;
+use kvm_ioctls::{VcpuFd, VmFd};
+
+/// APIC bus frequency advertised in the timing leaf. KVM emulates the local APIC timer with a
+/// 1 GHz bus clock.
+pub const KVM_APIC_BUS_KHZ: u32 = 1_000_000;
+
+/// Sets the guest TSC frequency of `vcpu` when `tsc_khz` is given and returns the frequency the
+/// vCPU runs at afterwards.
+pub fn init_tsc(vcpu: &VcpuFd, tsc_khz: Option<u32>) -> Result<u32> {
+    if let Some(tsc_khz) = tsc_khz {
+        vcpu.set_tsc_khz(tsc_khz)
+            .context("failed to set tsc frequency")?;
+    }
+
+    vcpu.get_tsc_khz().context("failed to get tsc frequency")
+}
+
+/// Saves the kvmclock of the VM, e.g. before pausing it or taking a snapshot.
+pub fn save_clock(vm: &VmFd) -> Result<kvm_clock_data> {
+    vm.get_clock().context("failed to get kvmclock")
+}
+
+/// Restores a kvmclock previously returned by `save_clock`.
+///
+/// The flags reported by KVM_GET_CLOCK are cleared so that the guest clock resumes exactly where
+/// it was saved, instead of being advanced by the host realtime that elapsed in between.
+pub fn restore_clock(vm: &VmFd, clock: &kvm_clock_data) -> Result<()> {
+    let clock = kvm_clock_data {
+        clock: clock.clock,
+        ..Default::default()
+    };
+
+    vm.set_clock(&clock).context("failed to set kvmclock")
+}
@@ -0,0 +1,304 @@
+use std::fs::OpenOptions;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+use std::sync::Arc;
+
+use anyhow::{Context, Result};
+use kvm_bindings::{kvm_msr_entry, kvm_regs, kvm_segment, kvm_sregs, Msrs};
+use kvm_ioctls::VcpuFd;
+use vm_memory::{GuestMemory, GuestMemoryRegion, MemoryRegionAddress, WriteVolatile};
+
+use crate::arch::memory::GuestMemoryMmap;
+
+// ELF64 core file layout, see the System V ABI and the kernel's include/uapi/linux/elf.h.
+const EHDR_SIZE: usize = 64;
+const PHDR_SIZE: usize = 56;
+const ET_CORE: u16 = 4;
+const EM_X86_64: u16 = 62;
+const PT_LOAD: u32 = 1;
+const PT_NOTE: u32 = 4;
+const PF_X: u32 = 1;
+const PF_W: u32 = 2;
+const PF_R: u32 = 4;
+
+const NT_PRSTATUS: u32 = 1;
+/// Size of `struct elf_prstatus` on x86-64, with `pr_reg` at `PRSTATUS_REGS`.
+const PRSTATUS_SIZE: usize = 336;
+const PRSTATUS_PID: usize = 32;
+const PRSTATUS_REGS: usize = 112;
+
+/// QEMU's per-CPU note, which the crash utility reads CR3 and the segment state from.
+/// See `QEMUCPUState` in QEMU's target/i386/arch_dump.c.
+const QEMU_NOTE_TYPE: u32 = 0;
+const QEMU_CPUSTATE_VERSION: u32 = 1;
+const QEMU_CPUSTATE_SIZE: u32 = 440;
+
+const MSR_KERNEL_GS_BASE: u32 = 0xc000_0102;
+
+/// Segments start on a page boundary, which lets debuggers map them.
+const SEGMENT_ALIGN: u64 = 0x1000;
+
+/// Writes an ELF core file of the guest to `path`: one PT_LOAD segment per guest memory
+/// region at its guest physical address, and a PT_NOTE segment with an NT_PRSTATUS and a
+/// QEMU CPU state note per vCPU. The vCPUs must be out of KVM_RUN.
+pub fn write_core_dump(
+    path: &Path,
+    guest_mem: &GuestMemoryMmap,
+    vcpus: &[Arc<VcpuFd>],
+) -> Result<()> {
+    let mut notes = Vec::new();
+    for (cpu_index, vcpu) in vcpus.iter().enumerate() {
+        let regs = vcpu.get_regs().context("failed to get regs")?;
+        let sregs = vcpu.get_sregs().context("failed to get sregs")?;
+        let kernel_gs_base = kernel_gs_base(vcpu)?;
+
+        write_note(
+            &mut notes,
+            "CORE",
+            NT_PRSTATUS,
+            &prstatus(cpu_index, &regs, &sregs),
+        );
+        write_note(
+            &mut notes,
+            "QEMU",
+            QEMU_NOTE_TYPE,
+            &qemu_cpu_state(&regs, &sregs, kernel_gs_base),
+        );
+    }
+
+    let phnum = 1 + guest_mem.num_regions();
+    let notes_offset = (EHDR_SIZE + phnum * PHDR_SIZE) as u64;
+
+    let mut headers = Vec::new();
+    write_ehdr(&mut headers, phnum as u16);
+    write_phdr(
+        &mut headers,
+        PT_NOTE,
+        0,
+        notes_offset,
+        0,
+        notes.len() as u64,
+    );
+
+    let mut offset = align_up(notes_offset + notes.len() as u64, SEGMENT_ALIGN);
+    let mut segments = Vec::new();
+    for region in guest_mem.iter() {
+        let addr = region.start_addr().0;
+        write_phdr(
+            &mut headers,
+            PT_LOAD,
+            PF_R | PF_W | PF_X,
+            offset,
+            addr,
+            region.len(),
+        );
+
+        segments.push((offset, region));
+        offset = align_up(offset + region.len(), SEGMENT_ALIGN);
+    }
+
+    let file = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .open(path)
+        .with_context(|| format!("failed to create core file {}", path.display()))?;
+    let mut writer = BufWriter::new(file);
+
+    writer.write_all(&headers)?;
+    writer.write_all(&notes)?;
+    let mut file = writer
+        .into_inner()
+        .map_err(|e| e.into_error())
+        .context("failed to write core file headers")?;
+
+    for (offset, region) in segments {
+        let slice = region
+            .get_slice(MemoryRegionAddress(0), region.len() as usize)
+            .context("failed to get guest memory slice")?;
+
+        std::io::Seek::seek(&mut file, std::io::SeekFrom::Start(offset))?;
+        file.write_all_volatile(&slice)
+            .context("failed to write guest memory")?;
+    }
+
+    file.sync_all().context("failed to sync core file")?;
+
+    Ok(())
+}
+
+fn write_ehdr(buf: &mut Vec<u8>, phnum: u16) {
+    // Magic, 64-bit, little endian, version 1, System V ABI.
+    buf.extend_from_slice(b"\x7fELF\x02\x01\x01\x00");
+    buf.extend_from_slice(&[0u8; 8]);
+    buf.extend_from_slice(&ET_CORE.to_le_bytes());
+    buf.extend_from_slice(&EM_X86_64.to_le_bytes());
+    buf.extend_from_slice(&1u32.to_le_bytes());
+    // Entry point, program and section header offsets.
+    buf.extend_from_slice(&0u64.to_le_bytes());
+    buf.extend_from_slice(&(EHDR_SIZE as u64).to_le_bytes());
+    buf.extend_from_slice(&0u64.to_le_bytes());
+    // Flags, header sizes and counts.
+    buf.extend_from_slice(&0u32.to_le_bytes());
+    buf.extend_from_slice(&(EHDR_SIZE as u16).to_le_bytes());
+    buf.extend_from_slice(&(PHDR_SIZE as u16).to_le_bytes());
+    buf.extend_from_slice(&phnum.to_le_bytes());
+    buf.extend_from_slice(&[0u8; 6]);
+}
+
+fn write_phdr(buf: &mut Vec<u8>, p_type: u32, flags: u32, offset: u64, addr: u64, size: u64) {
+    buf.extend_from_slice(&p_type.to_le_bytes());
+    buf.extend_from_slice(&flags.to_le_bytes());
+    buf.extend_from_slice(&offset.to_le_bytes());
+    // Guest memory is identity mapped for debuggers that only look at virtual addresses.
+    buf.extend_from_slice(&addr.to_le_bytes());
+    buf.extend_from_slice(&addr.to_le_bytes());
+    buf.extend_from_slice(&size.to_le_bytes());
+    buf.extend_from_slice(&size.to_le_bytes());
+    let align = if p_type == PT_LOAD { SEGMENT_ALIGN } else { 4 };
+    buf.extend_from_slice(&align.to_le_bytes());
+}
+
+fn write_note(buf: &mut Vec<u8>, name: &str, note_type: u32, desc: &[u8]) {
+    let namesz = name.len() + 1;
+
+    buf.extend_from_slice(&(namesz as u32).to_le_bytes());
+    buf.extend_from_slice(&(desc.len() as u32).to_le_bytes());
+    buf.extend_from_slice(&note_type.to_le_bytes());
+    buf.extend_from_slice(name.as_bytes());
+    buf.resize(
+        buf.len() + align_up(namesz as u64, 4) as usize - name.len(),
+        0,
+    );
+    buf.extend_from_slice(desc);
+    buf.resize(align_up(buf.len() as u64, 4) as usize, 0);
+}
+
+/// `struct elf_prstatus`, with the thread id set to the vCPU index plus one.
+fn prstatus(cpu_index: usize, regs: &kvm_regs, sregs: &kvm_sregs) -> Vec<u8> {
+    let mut desc = vec![0u8; PRSTATUS_SIZE];
+    desc[PRSTATUS_PID..PRSTATUS_PID + 4].copy_from_slice(&(cpu_index as u32 + 1).to_le_bytes());
+
+    // `struct user_regs_struct`, orig_rax is reported as rax.
+    let user_regs = [
+        regs.r15,
+        regs.r14,
+        regs.r13,
+        regs.r12,
+        regs.rbp,
+        regs.rbx,
+        regs.r11,
+        regs.r10,
+        regs.r9,
+        regs.r8,
+        regs.rax,
+        regs.rcx,
+        regs.rdx,
+        regs.rsi,
+        regs.rdi,
+        regs.rax,
+        regs.rip,
+        u64::from(sregs.cs.selector),
+        regs.rflags,
+        regs.rsp,
+        u64::from(sregs.ss.selector),
+        sregs.fs.base,
+        sregs.gs.base,
+        u64::from(sregs.ds.selector),
+        u64::from(sregs.es.selector),
+        u64::from(sregs.fs.selector),
+        u64::from(sregs.gs.selector),
+    ];
+    for (index, value) in user_regs.iter().enumerate() {
+        let offset = PRSTATUS_REGS + index * 8;
+        desc[offset..offset + 8].copy_from_slice(&value.to_le_bytes());
+    }
+
+    desc
+}
+
+fn qemu_cpu_state(regs: &kvm_regs, sregs: &kvm_sregs, kernel_gs_base: u64) -> Vec<u8> {
+    let mut desc = Vec::new();
+    desc.extend_from_slice(&QEMU_CPUSTATE_VERSION.to_le_bytes());
+    desc.extend_from_slice(&QEMU_CPUSTATE_SIZE.to_le_bytes());
+
+    for value in [
+        regs.rax,
+        regs.rbx,
+        regs.rcx,
+        regs.rdx,
+        regs.rsi,
+        regs.rdi,
+        regs.rsp,
+        regs.rbp,
+        regs.r8,
+        regs.r9,
+        regs.r10,
+        regs.r11,
+        regs.r12,
+        regs.r13,
+        regs.r14,
+        regs.r15,
+        regs.rip,
+        regs.rflags,
+    ] {
+        desc.extend_from_slice(&value.to_le_bytes());
+    }
+
+    for segment in [
+        &sregs.cs, &sregs.ds, &sregs.es, &sregs.fs, &sregs.gs, &sregs.ss, &sregs.ldt, &sregs.tr,
+    ] {
+        write_qemu_segment(
+            &mut desc,
+            u32::from(segment.selector),
+            segment.limit,
+            segment_flags(segment),
+            segment.base,
+        );
+    }
+    for table in [&sregs.gdt, &sregs.idt] {
+        write_qemu_segment(&mut desc, 0, u32::from(table.limit), 0, table.base);
+    }
+
+    for cr in [sregs.cr0, 0, sregs.cr2, sregs.cr3, sregs.cr4] {
+        desc.extend_from_slice(&cr.to_le_bytes());
+    }
+    desc.extend_from_slice(&kernel_gs_base.to_le_bytes());
+
+    desc
+}
+
+fn write_qemu_segment(buf: &mut Vec<u8>, selector: u32, limit: u32, flags: u32, base: u64) {
+    buf.extend_from_slice(&selector.to_le_bytes());
+    buf.extend_from_slice(&limit.to_le_bytes());
+    buf.extend_from_slice(&flags.to_le_bytes());
+    buf.extend_from_slice(&0u32.to_le_bytes());
+    buf.extend_from_slice(&base.to_le_bytes());
+}
+
+/// The attribute bits of a segment descriptor's high dword, the way QEMU keeps them.
+fn segment_flags(segment: &kvm_segment) -> u32 {
+    (u32::from(segment.type_) << 8)
+        | (u32::from(segment.s) << 12)
+        | (u32::from(segment.dpl) << 13)
+        | (u32::from(segment.present) << 15)
+        | (u32::from(segment.avl) << 20)
+        | (u32::from(segment.l) << 21)
+        | (u32::from(segment.db) << 22)
+        | (u32::from(segment.g) << 23)
+}
+
+fn kernel_gs_base(vcpu: &VcpuFd) -> Result<u64> {
+    let mut msrs = Msrs::from_entries(&[kvm_msr_entry {
+        index: MSR_KERNEL_GS_BASE,
+        ..Default::default()
+    }])
+    .context("failed to build msrs")?;
+    vcpu.get_msrs(&mut msrs).context("failed to get msrs")?;
+
+    Ok(msrs.as_slice()[0].data)
+}
+
+fn align_up(value: u64, align: u64) -> u64 {
+    value.div_ceil(align) * align
+}
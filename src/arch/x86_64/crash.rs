@@ -0,0 +1,160 @@
+use std::fmt::Write;
+
+use anyhow::{Context, Result};
+use kvm_bindings::{kvm_segment, kvm_sregs};
+use kvm_ioctls::VcpuFd;
+
+use crate::arch::memory::GuestMemoryMmap;
+use crate::arch::regs::EFER_LMA;
+use crate::symbols::KernelSymbols;
+
+/// Instruction bytes shown before and from RIP.
+const CODE_BYTES_BEFORE: usize = 32;
+const CODE_BYTES_AFTER: usize = 32;
+/// Stack words shown from RSP.
+const STACK_WORDS: usize = 32;
+
+/// Describes the state of a vCPU that stopped on a fatal exit: general purpose, segment and
+/// control registers, the instruction bytes around RIP and the top of the stack. Code
+/// addresses are symbolized when `symbols` are given.
+pub fn crash_report(
+    vcpu: &VcpuFd,
+    guest_mem: &GuestMemoryMmap,
+    symbols: Option<&KernelSymbols>,
+) -> Result<String> {
+    let regs = vcpu.get_regs().context("failed to get regs")?;
+    let sregs = vcpu.get_sregs().context("failed to get sregs")?;
+    let symbolize = |addr: u64| {
+        symbols
+            .and_then(|symbols| symbols.symbolize(addr))
+            .map_or(String::new(), |symbol| format!(" <{}>", symbol))
+    };
+
+    let mut report = String::new();
+    writeln!(
+        report,
+        "RIP: {:04x}:{:#018x}{}",
+        sregs.cs.selector,
+        regs.rip,
+        symbolize(regs.rip)
+    )?;
+    writeln!(
+        report,
+        "RSP: {:04x}:{:#018x} EFLAGS: {:#010x}",
+        sregs.ss.selector, regs.rsp, regs.rflags
+    )?;
+    for line in [
+        [("RAX", regs.rax), ("RBX", regs.rbx), ("RCX", regs.rcx)],
+        [("RDX", regs.rdx), ("RSI", regs.rsi), ("RDI", regs.rdi)],
+        [("RBP", regs.rbp), ("R08", regs.r8), ("R09", regs.r9)],
+        [("R10", regs.r10), ("R11", regs.r11), ("R12", regs.r12)],
+        [("R13", regs.r13), ("R14", regs.r14), ("R15", regs.r15)],
+    ] {
+        let line: Vec<_> = line
+            .iter()
+            .map(|(name, value)| format!("{}: {:#018x}", name, value))
+            .collect();
+        writeln!(report, "{}", line.join(" "))?;
+    }
+
+    for (name, segment) in [
+        ("CS", &sregs.cs),
+        ("SS", &sregs.ss),
+        ("DS", &sregs.ds),
+        ("ES", &sregs.es),
+        ("FS", &sregs.fs),
+        ("GS", &sregs.gs),
+        ("TR", &sregs.tr),
+        ("LDT", &sregs.ldt),
+    ] {
+        writeln!(report, "{:<3}: {}", name, format_segment(segment))?;
+    }
+    writeln!(
+        report,
+        "GDT: base={:#018x} limit={:#06x} IDT: base={:#018x} limit={:#06x}",
+        sregs.gdt.base, sregs.gdt.limit, sregs.idt.base, sregs.idt.limit
+    )?;
+    writeln!(
+        report,
+        "CR0: {:#018x} CR2: {:#018x} CR3: {:#018x}",
+        sregs.cr0, sregs.cr2, sregs.cr3
+    )?;
+    writeln!(
+        report,
+        "CR4: {:#018x} CR8: {:#018x} EFER: {:#018x}",
+        sregs.cr4, sregs.cr8, sregs.efer
+    )?;
+
+    let rip = linear_address(&sregs, &sregs.cs, regs.rip);
+    writeln!(report, "Code: {}", code_bytes(guest_mem, &sregs, rip))?;
+
+    writeln!(report, "Stack:")?;
+    let rsp = linear_address(&sregs, &sregs.ss, regs.rsp);
+    let mut stack = [0u8; STACK_WORDS * 8];
+    match crate::arch::paging::read_virtual(guest_mem, &sregs, rsp, &mut stack) {
+        Ok(()) => {
+            for (index, word) in stack.chunks_exact(8).enumerate() {
+                let word = u64::from_le_bytes(word.try_into().unwrap());
+                writeln!(
+                    report,
+                    "  {:#018x}: {:#018x}{}",
+                    regs.rsp.wrapping_add(index as u64 * 8),
+                    word,
+                    symbolize(word)
+                )?;
+            }
+        }
+        Err(e) => writeln!(report, "  unreadable: {:#}", e)?,
+    }
+
+    Ok(report)
+}
+
+fn format_segment(segment: &kvm_segment) -> String {
+    format!(
+        "{:04x} base={:#018x} limit={:#010x} type={:x} dpl={} present={} l={} db={}",
+        segment.selector,
+        segment.base,
+        segment.limit,
+        segment.type_,
+        segment.dpl,
+        segment.present,
+        segment.l,
+        segment.db
+    )
+}
+
+/// Adds the segment base outside of 64-bit mode, where it is ignored.
+fn linear_address(sregs: &kvm_sregs, segment: &kvm_segment, offset: u64) -> u64 {
+    if sregs.efer & EFER_LMA != 0 && sregs.cs.l != 0 {
+        offset
+    } else {
+        segment.base.wrapping_add(offset) & 0xffff_ffff
+    }
+}
+
+/// Formats the instruction bytes around `rip` like the kernel's oops, the byte at `rip` in
+/// angle brackets. The bytes before RIP are left out when they are not mapped.
+fn code_bytes(guest_mem: &GuestMemoryMmap, sregs: &kvm_sregs, rip: u64) -> String {
+    let mut after = [0u8; CODE_BYTES_AFTER];
+    if let Err(e) = crate::arch::paging::read_virtual(guest_mem, sregs, rip, &mut after) {
+        return format!("unreadable: {:#}", e);
+    }
+
+    let mut before = [0u8; CODE_BYTES_BEFORE];
+    let before = match crate::arch::paging::read_virtual(
+        guest_mem,
+        sregs,
+        rip.wrapping_sub(CODE_BYTES_BEFORE as u64),
+        &mut before,
+    ) {
+        Ok(()) => &before[..],
+        Err(_) => &[],
+    };
+
+    let mut bytes: Vec<_> = before.iter().map(|byte| format!("{:02x}", byte)).collect();
+    bytes.push(format!("<{:02x}>", after[0]));
+    bytes.extend(after[1..].iter().map(|byte| format!("{:02x}", byte)));
+
+    bytes.join(" ")
+}
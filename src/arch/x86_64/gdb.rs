@@ -0,0 +1,256 @@
+use anyhow::{Context, Result};
+use kvm_bindings::{
+    kvm_debug_exit_arch, kvm_guest_debug, kvm_regs, KVM_GUESTDBG_ENABLE, KVM_GUESTDBG_SINGLESTEP,
+    KVM_GUESTDBG_USE_HW_BP, KVM_GUESTDBG_USE_SW_BP,
+};
+use kvm_ioctls::VcpuFd;
+
+/// Debug exceptions, see the Intel SDM Vol. 3, 18.2.
+const DB_VECTOR: u32 = 1;
+const BP_VECTOR: u32 = 3;
+const DR6_BS: u64 = 1 << 14;
+/// DR7 bit 10 reads as one, bit 9 (GE) asks for exact breakpoint reporting.
+const DR7_FIXED: u64 = 0x600;
+
+/// Number of debug address registers, which limits the hardware breakpoints.
+pub const MAX_HW_BREAKPOINTS: usize = 4;
+
+/// Software breakpoint instruction.
+pub const INT3: u8 = 0xcc;
+
+/// Number of registers in the `g` packet, see `read_registers`.
+const GDB_GPR_COUNT: usize = 17;
+const GDB_EFLAGS: usize = 17;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HwBreakpointKind {
+    Execute,
+    Write,
+    Access,
+}
+
+/// Breakpoint or watchpoint backed by a debug address register.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HwBreakpoint {
+    pub addr: u64,
+    pub kind: HwBreakpointKind,
+    /// Watched length, 1, 2, 4 or 8 bytes. Always 1 for execution breakpoints.
+    pub len: u64,
+}
+
+impl HwBreakpoint {
+    /// DR7 enable, type and length bits of debug register `index`.
+    fn dr7_bits(&self, index: usize) -> Result<u64> {
+        let rw = match self.kind {
+            HwBreakpointKind::Execute => 0b00,
+            HwBreakpointKind::Write => 0b01,
+            HwBreakpointKind::Access => 0b11,
+        };
+
+        let len = match (self.kind, self.len) {
+            (HwBreakpointKind::Execute, _) | (_, 1) => 0b00,
+            (_, 2) => 0b01,
+            (_, 8) => 0b10,
+            (_, 4) => 0b11,
+            (_, len) => anyhow::bail!("unsupported watchpoint length {}", len),
+        };
+
+        Ok((1 << (index * 2)) | (rw << (16 + index * 4)) | (len << (18 + index * 4)))
+    }
+}
+
+/// Why a vCPU left KVM_RUN with KVM_EXIT_DEBUG.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DebugExit {
+    /// An int3 at `pc` was executed.
+    SwBreakpoint {
+        pc: u64,
+    },
+    /// Debug register `index` matched.
+    HwBreakpoint {
+        index: usize,
+    },
+    /// A single step completed.
+    Step,
+    Unknown,
+}
+
+impl From<&kvm_debug_exit_arch> for DebugExit {
+    fn from(arch: &kvm_debug_exit_arch) -> Self {
+        match arch.exception {
+            BP_VECTOR => DebugExit::SwBreakpoint { pc: arch.pc },
+            DB_VECTOR if arch.dr6 & DR6_BS != 0 => DebugExit::Step,
+            DB_VECTOR if arch.dr6 & 0xf != 0 => DebugExit::HwBreakpoint {
+                index: arch.dr6.trailing_zeros() as usize,
+            },
+            _ => DebugExit::Unknown,
+        }
+    }
+}
+
+/// Enables KVM_SET_GUEST_DEBUG on a vCPU: int3 and the given debug registers exit to
+/// userspace, and with `single_step` the next instruction does too.
+pub fn set_guest_debug(
+    vcpu: &VcpuFd,
+    hw_breakpoints: &[HwBreakpoint],
+    single_step: bool,
+) -> Result<()> {
+    if hw_breakpoints.len() > MAX_HW_BREAKPOINTS {
+        anyhow::bail!("too many hardware breakpoints")
+    }
+
+    let mut debug = kvm_guest_debug {
+        control: KVM_GUESTDBG_ENABLE | KVM_GUESTDBG_USE_SW_BP,
+        ..Default::default()
+    };
+
+    if !hw_breakpoints.is_empty() {
+        debug.control |= KVM_GUESTDBG_USE_HW_BP;
+        debug.arch.debugreg[7] = DR7_FIXED;
+
+        for (index, bp) in hw_breakpoints.iter().enumerate() {
+            debug.arch.debugreg[index] = bp.addr;
+            debug.arch.debugreg[7] |= bp.dr7_bits(index)?;
+        }
+    }
+
+    if single_step {
+        debug.control |= KVM_GUESTDBG_SINGLESTEP;
+    }
+
+    vcpu.set_guest_debug(&debug)
+        .context("failed to set guest debug")
+}
+
+pub fn clear_guest_debug(vcpu: &VcpuFd) -> Result<()> {
+    vcpu.set_guest_debug(&kvm_guest_debug::default())
+        .context("failed to clear guest debug")
+}
+
+/// Delivers an int3 that was intercepted by KVM_GUESTDBG_USE_SW_BP to the guest, for
+/// breakpoints the guest placed itself.
+pub fn inject_breakpoint(vcpu: &VcpuFd) -> Result<()> {
+    let mut events = vcpu
+        .get_vcpu_events()
+        .context("failed to get vcpu events")?;
+
+    events.exception.injected = 1;
+    events.exception.nr = BP_VECTOR as u8;
+    events.exception.has_error_code = 0;
+
+    vcpu.set_vcpu_events(&events)
+        .context("failed to inject breakpoint exception")
+}
+
+fn gdb_gprs(regs: &kvm_regs) -> [u64; GDB_GPR_COUNT] {
+    [
+        regs.rax, regs.rbx, regs.rcx, regs.rdx, regs.rsi, regs.rdi, regs.rbp, regs.rsp, regs.r8,
+        regs.r9, regs.r10, regs.r11, regs.r12, regs.r13, regs.r14, regs.r15, regs.rip,
+    ]
+}
+
+fn gdb_gprs_mut(regs: &mut kvm_regs) -> [&mut u64; GDB_GPR_COUNT] {
+    [
+        &mut regs.rax,
+        &mut regs.rbx,
+        &mut regs.rcx,
+        &mut regs.rdx,
+        &mut regs.rsi,
+        &mut regs.rdi,
+        &mut regs.rbp,
+        &mut regs.rsp,
+        &mut regs.r8,
+        &mut regs.r9,
+        &mut regs.r10,
+        &mut regs.r11,
+        &mut regs.r12,
+        &mut regs.r13,
+        &mut regs.r14,
+        &mut regs.r15,
+        &mut regs.rip,
+    ]
+}
+
+/// Returns the registers in the layout of the GDB `g` packet for x86-64: the general purpose
+/// registers and rip as 64-bit values, then eflags, cs, ss, ds, es, fs and gs as 32-bit
+/// values. The x87 and SSE registers that follow in GDB's layout are not sent, GDB shows them
+/// as unavailable.
+pub fn read_registers(vcpu: &VcpuFd) -> Result<Vec<u8>> {
+    let regs = vcpu.get_regs().context("failed to get regs")?;
+    let sregs = vcpu.get_sregs().context("failed to get sregs")?;
+
+    let mut data = Vec::new();
+    for reg in gdb_gprs(&regs) {
+        data.extend_from_slice(&reg.to_le_bytes());
+    }
+
+    data.extend_from_slice(&(regs.rflags as u32).to_le_bytes());
+    for segment in [sregs.cs, sregs.ss, sregs.ds, sregs.es, sregs.fs, sregs.gs] {
+        data.extend_from_slice(&u32::from(segment.selector).to_le_bytes());
+    }
+
+    Ok(data)
+}
+
+/// Writes the registers of a `G` packet. Segment selectors are ignored, loading them would
+/// need the matching descriptors.
+pub fn write_registers(vcpu: &VcpuFd, data: &[u8]) -> Result<()> {
+    let mut regs = vcpu.get_regs().context("failed to get regs")?;
+
+    for (index, reg) in gdb_gprs_mut(&mut regs).into_iter().enumerate() {
+        if let Some(value) = read_le(data, index * 8, 8) {
+            *reg = value;
+        }
+    }
+    if let Some(eflags) = read_le(data, GDB_GPR_COUNT * 8, 4) {
+        regs.rflags = eflags;
+    }
+
+    vcpu.set_regs(&regs).context("failed to set regs")
+}
+
+/// Reads register `index` of the `g` layout, `None` for registers not in it.
+pub fn read_register(vcpu: &VcpuFd, index: usize) -> Result<Option<Vec<u8>>> {
+    let data = read_registers(vcpu)?;
+
+    let range = match index {
+        0..=16 => index * 8..index * 8 + 8,
+        // eflags and the six segment selectors.
+        GDB_EFLAGS..=23 => {
+            let start = GDB_GPR_COUNT * 8 + (index - GDB_EFLAGS) * 4;
+            start..start + 4
+        }
+        _ => return Ok(None),
+    };
+
+    Ok(data.get(range).map(|bytes| bytes.to_vec()))
+}
+
+/// Writes one general purpose register, rip or eflags. Returns false for other registers.
+pub fn write_register(vcpu: &VcpuFd, index: usize, data: &[u8]) -> Result<bool> {
+    let mut regs = vcpu.get_regs().context("failed to get regs")?;
+
+    match index {
+        0..=16 => {
+            let value = read_le(data, 0, 8).ok_or(anyhow::anyhow!("short register value"))?;
+            *gdb_gprs_mut(&mut regs)[index] = value;
+        }
+        GDB_EFLAGS => {
+            regs.rflags = read_le(data, 0, 4).ok_or(anyhow::anyhow!("short register value"))?;
+        }
+        _ => return Ok(false),
+    }
+
+    vcpu.set_regs(&regs).context("failed to set regs")?;
+    Ok(true)
+}
+
+fn read_le(data: &[u8], offset: usize, len: usize) -> Option<u64> {
+    let bytes = data.get(offset..offset + len)?;
+    Some(
+        bytes
+            .iter()
+            .rev()
+            .fold(0, |value, &byte| (value << 8) | u64::from(byte)),
+    )
+}
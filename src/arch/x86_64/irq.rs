@@ -1,8 +1,32 @@
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+
 use anyhow::{Context, Result};
-use kvm_bindings::{kvm_pit_config, KVM_PIT_SPEAKER_DUMMY};
+use kvm_bindings::{
+    kvm_enable_cap, kvm_irq_routing, kvm_irq_routing_entry, kvm_irq_routing_entry__bindgen_ty_1,
+    kvm_irq_routing_msi, kvm_pit_config, KVM_CAP_SPLIT_IRQCHIP, KVM_IRQ_ROUTING_MSI,
+    KVM_PIT_SPEAKER_DUMMY,
+};
 use kvm_ioctls::VmFd;
 
-pub fn init_irqchip(vm: &VmFd) -> Result<()> {
+use crate::arch::IrqChipMode;
+use crate::devices::ioapic::{MsiMessage, MsiRouting, IOAPIC_NUM_PINS};
+
+pub fn init_irqchip(vm: &VmFd, mode: IrqChipMode) -> Result<()> {
+    if mode == IrqChipMode::Split {
+        // KVM keeps the local APICs and sends EOIs of the IOAPIC pins to the VMM. It has no PIT
+        // without its PIC.
+        let cap = kvm_enable_cap {
+            cap: KVM_CAP_SPLIT_IRQCHIP,
+            args: [u64::from(IOAPIC_NUM_PINS), 0, 0, 0],
+            ..Default::default()
+        };
+        vm.enable_cap(&cap)
+            .context("failed to enable split irq chip")?;
+
+        return Ok(());
+    }
+
     vm.create_irq_chip().context("failed to create irq chip")?;
 
     let pit_config = kvm_pit_config {
@@ -15,3 +39,88 @@ pub fn init_irqchip(vm: &VmFd) -> Result<()> {
 
     Ok(())
 }
+
+/// The GSI routing table of the split irqchip, what irqfds raise. The GSIs have no route until
+/// the userspace IOAPIC sets an MSI route for its pins, it raises them itself. The in-kernel
+/// irqchip keeps the default routes of KVM to its PIC and IOAPIC pins.
+///
+/// Every change replaces the whole table in KVM, from any thread.
+#[derive(Debug)]
+pub struct GsiRouting {
+    vm: Arc<VmFd>,
+    routes: Mutex<BTreeMap<u32, MsiMessage>>,
+}
+
+impl GsiRouting {
+    pub fn new(vm: Arc<VmFd>) -> Self {
+        GsiRouting {
+            vm,
+            routes: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    fn commit(&self, routes: &BTreeMap<u32, MsiMessage>) -> Result<()> {
+        let entries: Vec<_> = routes
+            .iter()
+            .map(|(&gsi, msi)| kvm_irq_routing_entry {
+                gsi,
+                type_: KVM_IRQ_ROUTING_MSI,
+                u: kvm_irq_routing_entry__bindgen_ty_1 {
+                    msi: kvm_irq_routing_msi {
+                        address_lo: msi.address as u32,
+                        address_hi: (msi.address >> 32) as u32,
+                        data: msi.data,
+                        ..Default::default()
+                    },
+                },
+                ..Default::default()
+            })
+            .collect();
+
+        // The entries are a flexible array member, allocated right after the header.
+        let header_size = std::mem::size_of::<kvm_irq_routing>();
+        let size = header_size + entries.len() * std::mem::size_of::<kvm_irq_routing_entry>();
+        let mut routing = Vec::new();
+        routing.resize_with(size.div_ceil(header_size), kvm_irq_routing::default);
+        routing[0].nr = u32::try_from(entries.len())?;
+        // SAFETY: `routing` has room for `entries.len()` entries after the header.
+        unsafe {
+            routing[0]
+                .entries
+                .as_mut_slice(entries.len())
+                .copy_from_slice(&entries);
+        }
+
+        self.vm
+            .set_gsi_routing(&routing[0])
+            .context("failed to set gsi routing")
+    }
+}
+
+impl MsiRouting for GsiRouting {
+    /// Replaces what `gsi` was routed to.
+    fn set_msi_route(&self, gsi: u32, msi: MsiMessage) -> Result<()> {
+        let mut routes = self.routes.lock().expect("Poisoned lock");
+        if routes.insert(gsi, msi) == Some(msi) {
+            return Ok(());
+        }
+
+        self.commit(&routes)
+    }
+
+    fn remove_route(&self, gsi: u32) -> Result<()> {
+        let mut routes = self.routes.lock().expect("Poisoned lock");
+        if routes.remove(&gsi).is_none() {
+            return Ok(());
+        }
+
+        self.commit(&routes)
+    }
+
+    fn raise(&self, gsi: u32) -> Result<()> {
+        // MSI routes send on the line going high and ignore it going low.
+        self.vm
+            .set_irq_line(gsi, true)
+            .with_context(|| format!("failed to raise gsi {}", gsi))
+    }
+}
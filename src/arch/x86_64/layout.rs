@@ -14,3 +14,9 @@ pub const ZERO_PAGE_START: u64 = 0x7000;
 pub const CMDLINE_START: u64 = 0x20000;
 /// Kernel command line maximum size.
 pub const CMDLINE_MAX_SIZE: usize = 2048;
+
+/// Default physical address of the local APIC.
+pub const APIC_DEFAULT_PHYS_BASE: u32 = 0xfee0_0000;
+
+/// Default physical address of the IOAPIC.
+pub const IO_APIC_DEFAULT_PHYS_BASE: u32 = 0xfec0_0000;
@@ -1,15 +1,36 @@
+use std::ffi::CString;
+use std::fs::{File, OpenOptions};
+use std::num::NonZeroUsize;
+use std::os::fd::{AsRawFd, FromRawFd};
+use std::path::Path;
+
 use anyhow::{Context, Result};
 use kvm_bindings::{kvm_userspace_memory_region, KVM_MEM_LOG_DIRTY_PAGES};
 use kvm_ioctls::VmFd;
-use vm_memory::{GuestAddress, GuestMemory, GuestMemoryMmap};
+use log::{info, warn};
+use vm_memory::bitmap::AtomicBitmap;
+use vm_memory::mmap::MmapRegionBuilder;
+use vm_memory::{FileOffset, GuestAddress, GuestMemory, GuestMemoryRegion};
+
+use crate::arch::{HugePageSize, MemoryBackend, MemoryConfig};
+
+/// Guest memory whose bitmap records the pages written by the VMM itself, e.g. by device
+/// emulation, since KVM only logs the writes done by the guest.
+pub type GuestMemoryMmap = vm_memory::GuestMemoryMmap<AtomicBitmap>;
+pub type GuestRegionMmap = vm_memory::GuestRegionMmap<AtomicBitmap>;
 
 const RAM_BASE: u64 = 0;
 
-pub fn create_guest_memory(vm: &VmFd, ram_size: u64) -> Result<GuestMemoryMmap> {
+/// Filesystem magic of hugetlbfs, see the kernel's include/uapi/linux/magic.h.
+const HUGETLBFS_MAGIC: libc::c_long = 0x9584_58f6;
+
+pub fn create_guest_memory(vm: &VmFd, mem_config: &MemoryConfig) -> Result<GuestMemoryMmap> {
     let guest_addr = GuestAddress(RAM_BASE);
+    let ram_size = mem_config.ram_size;
 
-    let guest_mem = GuestMemoryMmap::<()>::from_ranges(&[(guest_addr, ram_size as usize)])
-        .context("failed to create guest memory")?;
+    let ram_region = create_ram_region(mem_config, guest_addr)?;
+    let guest_mem =
+        GuestMemoryMmap::from_regions(vec![ram_region]).context("failed to create guest memory")?;
 
     let host_addr = guest_mem
         .get_host_address(guest_addr)
@@ -20,6 +41,7 @@ pub fn create_guest_memory(vm: &VmFd, ram_size: u64) -> Result<GuestMemoryMmap>
         guest_phys_addr: RAM_BASE,
         memory_size: ram_size,
         userspace_addr: host_addr as u64,
+        // Needed by the dirty page tracking of snapshots, see `snapshot::DirtyTracker`.
         flags: KVM_MEM_LOG_DIRTY_PAGES,
     };
 
@@ -33,3 +55,214 @@ pub fn create_guest_memory(vm: &VmFd, ram_size: u64) -> Result<GuestMemoryMmap>
 
     Ok(guest_mem)
 }
+
+fn create_ram_region(
+    mem_config: &MemoryConfig,
+    guest_addr: GuestAddress,
+) -> Result<GuestRegionMmap> {
+    let size = usize::try_from(mem_config.ram_size)?;
+
+    if let Some(hugepages) = mem_config.hugepages {
+        if size % hugepages.bytes() != 0 {
+            anyhow::bail!(
+                "guest memory size {:#x} is not a multiple of the huge page size {:#x}",
+                size,
+                hugepages.bytes()
+            )
+        }
+    }
+
+    let prot = libc::PROT_READ | libc::PROT_WRITE;
+
+    // Huge pages are reserved at mmap time, so that a shortage fails here instead of raising
+    // SIGBUS once the guest touches the memory.
+    let reserve = match mem_config.hugepages {
+        Some(_) => 0,
+        None => libc::MAP_NORESERVE,
+    };
+
+    let populate = match mem_config.prefault {
+        true => libc::MAP_POPULATE,
+        false => 0,
+    };
+
+    let builder = match &mem_config.backend {
+        MemoryBackend::Anon => {
+            let mut flags = libc::MAP_PRIVATE | libc::MAP_ANONYMOUS | reserve | populate;
+            if let Some(hugepages) = mem_config.hugepages {
+                flags |= libc::MAP_HUGETLB | (hugepages.shift() << libc::MAP_HUGE_SHIFT);
+            }
+
+            MmapRegionBuilder::new_with_bitmap(size, new_bitmap(size)).with_mmap_flags(flags)
+        }
+        MemoryBackend::Memfd => {
+            let file = create_memfd(size, mem_config.hugepages)?;
+
+            MmapRegionBuilder::new_with_bitmap(size, new_bitmap(size))
+                .with_mmap_flags(libc::MAP_SHARED | reserve | populate)
+                .with_file_offset(FileOffset::new(file, 0))
+        }
+        MemoryBackend::File(path) => {
+            let file = open_memory_file(path, size, mem_config.hugepages)?;
+
+            MmapRegionBuilder::new_with_bitmap(size, new_bitmap(size))
+                .with_mmap_flags(libc::MAP_SHARED | reserve | populate)
+                .with_file_offset(FileOffset::new(file, 0))
+        }
+        MemoryBackend::Template(path) => {
+            let file = open_template_file(path, size, mem_config.hugepages)?;
+
+            MmapRegionBuilder::new_with_bitmap(size, new_bitmap(size))
+                .with_mmap_flags(libc::MAP_PRIVATE | libc::MAP_NORESERVE | populate)
+                .with_file_offset(FileOffset::new(file, 0))
+        }
+    };
+
+    let region = builder
+        .with_mmap_prot(prot)
+        .with_hugetlbfs(mem_config.hugepages.is_some())
+        .build()
+        .context("failed to mmap guest memory")?;
+
+    info!(
+        "guest memory: {} MiB, backend {:?}, hugepages {:?}",
+        size >> 20,
+        mem_config.backend,
+        mem_config.hugepages
+    );
+
+    let region =
+        GuestRegionMmap::new(region, guest_addr).context("failed to create guest memory region")?;
+
+    apply_memory_policies(&region, mem_config)?;
+
+    Ok(region)
+}
+
+/// Applies the mlock and madvise knobs of `mem_config` to a freshly mapped region.
+fn apply_memory_policies(region: &GuestRegionMmap, mem_config: &MemoryConfig) -> Result<()> {
+    let addr = region.as_ptr() as *mut libc::c_void;
+    let len = region.len() as usize;
+
+    if mem_config.mlock {
+        // SAFETY: the range is a mapping owned by `region`.
+        if unsafe { libc::mlock(addr, len) } < 0 {
+            return Err(std::io::Error::last_os_error())
+                .context("failed to mlock guest memory, check RLIMIT_MEMLOCK");
+        }
+    }
+
+    if mem_config.merge {
+        // KSM only scans private anonymous memory, the advice is a no-op for shared mappings.
+        if mem_config.backend != MemoryBackend::Anon || mem_config.hugepages.is_some() {
+            warn!("ksm merging only applies to anonymous memory without huge pages");
+        }
+
+        madvise(addr, len, libc::MADV_MERGEABLE).context("failed to enable ksm merging")?;
+    }
+
+    if mem_config.dontdump {
+        madvise(addr, len, libc::MADV_DONTDUMP)
+            .context("failed to exclude guest memory from core dumps")?;
+    }
+
+    info!(
+        "guest memory policies: prefault {}, mlock {}, ksm merge {}, dontdump {}",
+        mem_config.prefault, mem_config.mlock, mem_config.merge, mem_config.dontdump
+    );
+
+    Ok(())
+}
+
+fn madvise(addr: *mut libc::c_void, len: usize, advice: libc::c_int) -> std::io::Result<()> {
+    // SAFETY: callers pass a range mapped by a guest memory region.
+    match unsafe { libc::madvise(addr, len, advice) } {
+        0 => Ok(()),
+        _ => Err(std::io::Error::last_os_error()),
+    }
+}
+
+fn new_bitmap(size: usize) -> AtomicBitmap {
+    AtomicBitmap::new(size, NonZeroUsize::new(crate::arch::PAGE_SIZE).unwrap())
+}
+
+fn create_memfd(size: usize, hugepages: Option<HugePageSize>) -> Result<File> {
+    let name = CString::new("kvm_box_guest_mem").unwrap();
+
+    let mut flags = libc::MFD_CLOEXEC;
+    if let Some(hugepages) = hugepages {
+        let page_size = (hugepages.shift() as libc::c_uint) << libc::MFD_HUGE_SHIFT;
+        flags |= libc::MFD_HUGETLB | page_size;
+    }
+
+    // SAFETY: `name` is a valid nul terminated string and the returned fd is checked.
+    let fd = unsafe { libc::memfd_create(name.as_ptr(), flags) };
+    if fd < 0 {
+        return Err(std::io::Error::last_os_error()).context("failed to create memfd");
+    }
+
+    // SAFETY: `fd` is a freshly created descriptor that nothing else owns.
+    let file = unsafe { File::from_raw_fd(fd) };
+    file.set_len(size as u64)
+        .context("failed to set memfd size")?;
+
+    Ok(file)
+}
+
+fn open_memory_file(path: &Path, size: usize, hugepages: Option<HugePageSize>) -> Result<File> {
+    let file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(path)
+        .with_context(|| format!("failed to open memory file {}", path.display()))?;
+
+    // A file mapping gets the page size of its filesystem, MAP_HUGETLB does not apply.
+    if hugepages.is_some() && !is_on_hugetlbfs(&file)? {
+        anyhow::bail!(
+            "huge pages requested but {} is not on a hugetlbfs mount",
+            path.display()
+        )
+    }
+
+    file.set_len(size as u64)
+        .context("failed to set memory file size")?;
+
+    Ok(file)
+}
+
+fn open_template_file(path: &Path, size: usize, hugepages: Option<HugePageSize>) -> Result<File> {
+    if hugepages.is_some() {
+        anyhow::bail!("huge pages are not supported with a template memory file")
+    }
+
+    let file = File::open(path)
+        .with_context(|| format!("failed to open template memory file {}", path.display()))?;
+
+    let file_size = file
+        .metadata()
+        .context("failed to stat template memory file")?
+        .len();
+    if file_size != size as u64 {
+        anyhow::bail!(
+            "template memory file has {:#x} bytes, guest memory has {:#x}",
+            file_size,
+            size
+        )
+    }
+
+    Ok(file)
+}
+
+fn is_on_hugetlbfs(file: &File) -> Result<bool> {
+    // SAFETY: zeroed memory is a valid statfs and the kernel fills it on success.
+    let mut buf: libc::statfs = unsafe { std::mem::zeroed() };
+
+    // SAFETY: the fd is valid for the lifetime of `file` and `buf` is writable.
+    if unsafe { libc::fstatfs(file.as_raw_fd(), &mut buf) } < 0 {
+        return Err(std::io::Error::last_os_error()).context("failed to statfs memory file");
+    }
+
+    Ok(buf.f_type == HUGETLBFS_MAGIC)
+}
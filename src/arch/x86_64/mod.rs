@@ -1,7 +1,14 @@
+pub mod clock;
+pub mod coredump;
+pub mod crash;
+pub mod gdb;
 pub mod gdt;
 pub mod irq;
 pub mod layout;
 pub mod memory;
+pub mod mptable;
+pub mod paging;
 pub mod regs;
+pub mod state;
 pub mod system;
 pub mod vcpu;
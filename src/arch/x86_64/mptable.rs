@@ -0,0 +1,269 @@
+use anyhow::{Context, Result};
+use vm_memory::{Address, ByteValued, Bytes, GuestAddress};
+
+use crate::arch::memory::GuestMemoryMmap;
+use crate::arch::CpuTopology;
+
+// Most of these variables are sourced from the Intel MP Spec 1.4 and the kernel's mpspec_def.h.
+const SMP_MAGIC_IDENT: [u8; 4] = *b"_MP_";
+const MPC_SIGNATURE: [u8; 4] = *b"PCMP";
+const MPC_SPEC: i8 = 4;
+const MPC_OEM: [u8; 8] = *b"KVM-BOX ";
+const MPC_PRODUCT_ID: [u8; 12] = *b"000000000000";
+const BUS_TYPE_ISA: [u8; 6] = *b"ISA   ";
+
+const MP_PROCESSOR: u8 = 0;
+const MP_BUS: u8 = 1;
+const MP_IOAPIC: u8 = 2;
+const MP_INTSRC: u8 = 3;
+const MP_LINTSRC: u8 = 4;
+
+const MP_IRQDIR_DEFAULT: u16 = 0;
+const MP_INT: u8 = 0;
+const MP_NMI: u8 = 1;
+const MP_EXTINT: u8 = 3;
+
+const CPU_ENABLED: u8 = 1;
+const CPU_BOOTPROCESSOR: u8 = 2;
+const CPU_STEPPING: u32 = 0x600;
+const CPU_FEATURE_APIC: u32 = 0x200;
+const CPU_FEATURE_FPU: u32 = 0x001;
+
+const APIC_VERSION: u8 = 0x14;
+const MPC_APIC_USABLE: u8 = 1;
+const IRQ_MAX: u8 = 23;
+
+/// The MP table lives in the EBDA, see `system::EBDA_START`.
+const MPTABLE_START: u64 = 0x9fc00;
+const MPTABLE_MAX_SIZE: usize = 1 << 10;
+
+#[repr(C, packed)]
+#[derive(Debug, Default, Copy, Clone)]
+struct MpfIntel {
+    signature: [u8; 4],
+    physptr: u32,
+    length: u8,
+    specification: u8,
+    checksum: u8,
+    feature1: u8,
+    feature2: u8,
+    feature3: u8,
+    feature4: u8,
+    feature5: u8,
+}
+
+#[repr(C, packed)]
+#[derive(Debug, Default, Copy, Clone)]
+struct MpcTable {
+    signature: [u8; 4],
+    length: u16,
+    spec: i8,
+    checksum: u8,
+    oem: [u8; 8],
+    productid: [u8; 12],
+    oemptr: u32,
+    oemsize: u16,
+    oemcount: u16,
+    lapic: u32,
+    reserved: u32,
+}
+
+#[repr(C, packed)]
+#[derive(Debug, Default, Copy, Clone)]
+struct MpcCpu {
+    type_: u8,
+    apicid: u8,
+    apicver: u8,
+    cpuflag: u8,
+    cpufeature: u32,
+    featureflag: u32,
+    reserved: [u32; 2],
+}
+
+#[repr(C, packed)]
+#[derive(Debug, Default, Copy, Clone)]
+struct MpcBus {
+    type_: u8,
+    busid: u8,
+    bustype: [u8; 6],
+}
+
+#[repr(C, packed)]
+#[derive(Debug, Default, Copy, Clone)]
+struct MpcIoapic {
+    type_: u8,
+    apicid: u8,
+    apicver: u8,
+    flags: u8,
+    apicaddr: u32,
+}
+
+#[repr(C, packed)]
+#[derive(Debug, Default, Copy, Clone)]
+struct MpcIntsrc {
+    type_: u8,
+    irqtype: u8,
+    irqflag: u16,
+    srcbus: u8,
+    srcbusirq: u8,
+    dstapic: u8,
+    dstirq: u8,
+}
+
+#[repr(C, packed)]
+#[derive(Debug, Default, Copy, Clone)]
+struct MpcLintsrc {
+    type_: u8,
+    irqtype: u8,
+    irqflag: u16,
+    srcbusid: u8,
+    srcbusirq: u8,
+    destapic: u8,
+    destapiclint: u8,
+}
+
+// SAFETY: all of the above are packed POD structs without implicit padding.
+unsafe impl ByteValued for MpfIntel {}
+unsafe impl ByteValued for MpcTable {}
+unsafe impl ByteValued for MpcCpu {}
+unsafe impl ByteValued for MpcBus {}
+unsafe impl ByteValued for MpcIoapic {}
+unsafe impl ByteValued for MpcIntsrc {}
+unsafe impl ByteValued for MpcLintsrc {}
+
+/// Accumulates MP table entries and their checksum before they are written to guest memory.
+struct MpTableWriter {
+    bytes: Vec<u8>,
+}
+
+impl MpTableWriter {
+    fn push<T: ByteValued>(&mut self, entry: T) {
+        self.bytes.extend_from_slice(entry.as_slice());
+    }
+}
+
+fn compute_checksum(bytes: &[u8]) -> u8 {
+    bytes.iter().fold(0u8, |acc, b| acc.wrapping_add(*b))
+}
+
+/// Writes the MP table describing the vCPUs of `topology` into the EBDA. The Linux guest
+/// needs it to discover the application processors since the default cmdline disables ACPI.
+pub fn setup_mptable(guest_mem: &GuestMemoryMmap, topology: &CpuTopology) -> Result<()> {
+    let num_cpus = topology.vcpu_count();
+    let mpf_size = std::mem::size_of::<MpfIntel>();
+    let table_addr = GuestAddress(MPTABLE_START).unchecked_add(mpf_size as u64);
+
+    let max_apic_id = (0..num_cpus)
+        .map(|i| topology.apic_id(i))
+        .max()
+        .unwrap_or(0);
+    let ioapic_id = u8::try_from(max_apic_id + 1).context("too many vcpus for mptable")?;
+
+    let mut entries = MpTableWriter { bytes: Vec::new() };
+
+    for cpu_index in 0..num_cpus {
+        let mut cpuflag = CPU_ENABLED;
+        if cpu_index == 0 {
+            cpuflag |= CPU_BOOTPROCESSOR;
+        }
+
+        entries.push(MpcCpu {
+            type_: MP_PROCESSOR,
+            apicid: u8::try_from(topology.apic_id(cpu_index))?,
+            apicver: APIC_VERSION,
+            cpuflag,
+            cpufeature: CPU_STEPPING,
+            featureflag: CPU_FEATURE_APIC | CPU_FEATURE_FPU,
+            ..Default::default()
+        });
+    }
+
+    entries.push(MpcBus {
+        type_: MP_BUS,
+        busid: 0,
+        bustype: BUS_TYPE_ISA,
+    });
+
+    entries.push(MpcIoapic {
+        type_: MP_IOAPIC,
+        apicid: ioapic_id,
+        apicver: APIC_VERSION,
+        flags: MPC_APIC_USABLE,
+        apicaddr: crate::arch::layout::IO_APIC_DEFAULT_PHYS_BASE,
+    });
+
+    // Identity map the legacy ISA interrupts onto the IOAPIC pins.
+    for irq in 0..=IRQ_MAX {
+        entries.push(MpcIntsrc {
+            type_: MP_INTSRC,
+            irqtype: MP_INT,
+            irqflag: MP_IRQDIR_DEFAULT,
+            srcbus: 0,
+            srcbusirq: irq,
+            dstapic: ioapic_id,
+            dstirq: irq,
+        });
+    }
+
+    entries.push(MpcLintsrc {
+        type_: MP_LINTSRC,
+        irqtype: MP_EXTINT,
+        irqflag: MP_IRQDIR_DEFAULT,
+        srcbusid: 0,
+        srcbusirq: 0,
+        destapic: 0,
+        destapiclint: 0,
+    });
+
+    entries.push(MpcLintsrc {
+        type_: MP_LINTSRC,
+        irqtype: MP_NMI,
+        irqflag: MP_IRQDIR_DEFAULT,
+        srcbusid: 0,
+        srcbusirq: 0,
+        destapic: 0xff, // All local APICs.
+        destapiclint: 1,
+    });
+
+    let table_len = std::mem::size_of::<MpcTable>() + entries.bytes.len();
+    if mpf_size + table_len > MPTABLE_MAX_SIZE {
+        anyhow::bail!("mptable for {} vcpus does not fit in the EBDA", num_cpus)
+    }
+
+    let mut table = MpcTable {
+        signature: MPC_SIGNATURE,
+        length: u16::try_from(table_len)?,
+        spec: MPC_SPEC,
+        oem: MPC_OEM,
+        productid: MPC_PRODUCT_ID,
+        lapic: crate::arch::layout::APIC_DEFAULT_PHYS_BASE,
+        ..Default::default()
+    };
+    let checksum =
+        compute_checksum(table.as_slice()).wrapping_add(compute_checksum(&entries.bytes));
+    table.checksum = 0u8.wrapping_sub(checksum);
+
+    let mut mpf = MpfIntel {
+        signature: SMP_MAGIC_IDENT,
+        physptr: u32::try_from(table_addr.raw_value())?,
+        length: 1, // In 16 byte units.
+        specification: 4,
+        ..Default::default()
+    };
+    mpf.checksum = 0u8.wrapping_sub(compute_checksum(mpf.as_slice()));
+
+    guest_mem
+        .write_obj(mpf, GuestAddress(MPTABLE_START))
+        .context("failed to write mpf intel")?;
+    guest_mem
+        .write_obj(table, table_addr)
+        .context("failed to write mpc table")?;
+    guest_mem
+        .write_slice(
+            &entries.bytes,
+            table_addr.unchecked_add(std::mem::size_of::<MpcTable>() as u64),
+        )
+        .context("failed to write mpc entries")?;
+
+    Ok(())
+}
@@ -0,0 +1,259 @@
+use std::ops::Range;
+
+use anyhow::{Context, Result};
+use kvm_bindings::kvm_sregs;
+use vm_memory::{Bytes, GuestAddress};
+
+use crate::arch::memory::GuestMemoryMmap;
+use crate::arch::regs::{EFER_LMA, X86_CR0_PG, X86_CR4_PAE};
+
+const X86_CR4_PSE: u64 = 0x10;
+const X86_CR4_LA57: u64 = 0x1000;
+
+const PTE_PRESENT: u64 = 0x1;
+const PTE_PAGE_SIZE: u64 = 0x80;
+/// Physical address bits 12..51 of a 64-bit paging entry.
+const PTE_ADDR_MASK: u64 = 0x000f_ffff_ffff_f000;
+
+/// Translates a guest virtual address by walking the page tables the vCPU currently uses.
+/// Supports 32-bit, PAE, 4-level and 5-level paging, including large pages.
+pub fn translate_gva(guest_mem: &GuestMemoryMmap, sregs: &kvm_sregs, gva: u64) -> Result<u64> {
+    if sregs.cr0 & X86_CR0_PG == 0 {
+        return Ok(gva);
+    }
+
+    if sregs.efer & EFER_LMA == 0 && sregs.cr4 & X86_CR4_PAE == 0 {
+        return translate_gva_32(guest_mem, sregs, gva);
+    }
+
+    // PAE paging translates 32-bit linear addresses.
+    let pae = sregs.efer & EFER_LMA == 0;
+    let gva = if pae { gva & 0xffff_ffff } else { gva };
+
+    let (levels, mut table) = if pae {
+        (3, sregs.cr3 & 0xffff_ffe0)
+    } else if sregs.cr4 & X86_CR4_LA57 != 0 {
+        (5, sregs.cr3 & PTE_ADDR_MASK)
+    } else {
+        (4, sregs.cr3 & PTE_ADDR_MASK)
+    };
+
+    for level in (1..=levels).rev() {
+        let shift = 12 + 9 * (level - 1);
+        let index = (gva >> shift) & 0x1ff;
+
+        let entry: u64 = read_entry(guest_mem, table + index * 8)?;
+        if entry & PTE_PRESENT == 0 {
+            anyhow::bail!("address {:#x} is not mapped", gva)
+        }
+
+        let large = level <= 3 && entry & PTE_PAGE_SIZE != 0;
+        if large && pae && level == 3 {
+            // PAE PDPTEs map no pages, the bit is reserved there.
+            anyhow::bail!(
+                "address {:#x} is mapped by a pdpte with a reserved bit",
+                gva
+            )
+        }
+
+        let page_mask = (1u64 << shift) - 1;
+        if level == 1 || large {
+            return Ok((entry & PTE_ADDR_MASK & !page_mask) | (gva & page_mask));
+        }
+
+        table = entry & PTE_ADDR_MASK;
+    }
+
+    unreachable!()
+}
+
+fn translate_gva_32(guest_mem: &GuestMemoryMmap, sregs: &kvm_sregs, gva: u64) -> Result<u64> {
+    let gva = gva & 0xffff_ffff;
+
+    let pde: u32 = read_entry(guest_mem, (sregs.cr3 & 0xffff_f000) + ((gva >> 22) << 2))?;
+    if u64::from(pde) & PTE_PRESENT == 0 {
+        anyhow::bail!("address {:#x} is not mapped", gva)
+    }
+
+    if sregs.cr4 & X86_CR4_PSE != 0 && u64::from(pde) & PTE_PAGE_SIZE != 0 {
+        return Ok((u64::from(pde) & 0xffc0_0000) | (gva & 0x3f_ffff));
+    }
+
+    let pte: u32 = read_entry(
+        guest_mem,
+        (u64::from(pde) & 0xffff_f000) + (((gva >> 12) & 0x3ff) << 2),
+    )?;
+    if u64::from(pte) & PTE_PRESENT == 0 {
+        anyhow::bail!("address {:#x} is not mapped", gva)
+    }
+
+    Ok((u64::from(pte) & 0xffff_f000) | (gva & 0xfff))
+}
+
+fn read_entry<T: vm_memory::ByteValued>(guest_mem: &GuestMemoryMmap, addr: u64) -> Result<T> {
+    guest_mem
+        .read_obj(GuestAddress(addr))
+        .with_context(|| format!("failed to read page table entry at {:#x}", addr))
+}
+
+/// Calls `f` with the guest physical address of each piece of the virtual range at `gva` that
+/// lies in one page, and the offsets of the piece in the range. Each page is translated on its
+/// own.
+fn for_each_page<F>(
+    guest_mem: &GuestMemoryMmap,
+    sregs: &kvm_sregs,
+    gva: u64,
+    len: usize,
+    mut f: F,
+) -> Result<()>
+where
+    F: FnMut(GuestAddress, Range<usize>) -> Result<()>,
+{
+    let page_size = crate::arch::PAGE_SIZE as u64;
+    let mut done = 0;
+
+    while done < len {
+        let addr = gva.wrapping_add(done as u64);
+        let gpa = translate_gva(guest_mem, sregs, addr)?;
+        let chunk = (len - done).min((page_size - (addr % page_size)) as usize);
+
+        f(GuestAddress(gpa), done..done + chunk)?;
+        done += chunk;
+    }
+
+    Ok(())
+}
+
+/// Reads guest virtual memory at `gva` into `buf`.
+pub fn read_virtual(
+    guest_mem: &GuestMemoryMmap,
+    sregs: &kvm_sregs,
+    gva: u64,
+    buf: &mut [u8],
+) -> Result<()> {
+    for_each_page(guest_mem, sregs, gva, buf.len(), |gpa, range| {
+        guest_mem
+            .read_slice(&mut buf[range], gpa)
+            .with_context(|| format!("failed to read guest memory at {:#x}", gpa.0))
+    })
+}
+
+/// Writes `data` to guest virtual memory at `gva`.
+pub fn write_virtual(
+    guest_mem: &GuestMemoryMmap,
+    sregs: &kvm_sregs,
+    gva: u64,
+    data: &[u8],
+) -> Result<()> {
+    for_each_page(guest_mem, sregs, gva, data.len(), |gpa, range| {
+        guest_mem
+            .write_slice(&data[range], gpa)
+            .with_context(|| format!("failed to write guest memory at {:#x}", gpa.0))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn guest_mem() -> GuestMemoryMmap {
+        GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10_0000)]).unwrap()
+    }
+
+    fn set_entry(guest_mem: &GuestMemoryMmap, table: u64, index: u64, entry: u64) {
+        guest_mem
+            .write_obj(entry, GuestAddress(table + index * 8))
+            .unwrap();
+    }
+
+    fn sregs(long_mode: bool) -> kvm_sregs {
+        kvm_sregs {
+            cr0: X86_CR0_PG,
+            cr3: 0x1000,
+            cr4: X86_CR4_PAE,
+            efer: if long_mode { EFER_LMA } else { 0 },
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_translate_pae() {
+        let guest_mem = guest_mem();
+        let sregs = sregs(false);
+        set_entry(&guest_mem, 0x1000, 0, 0x2000 | PTE_PRESENT);
+        set_entry(&guest_mem, 0x2000, 0, 0x3000 | PTE_PRESENT);
+        set_entry(
+            &guest_mem,
+            0x2000,
+            1,
+            0x40_0000 | PTE_PRESENT | PTE_PAGE_SIZE,
+        );
+        set_entry(&guest_mem, 0x3000, 1, 0x5000 | PTE_PRESENT);
+
+        assert_eq!(translate_gva(&guest_mem, &sregs, 0x1234).unwrap(), 0x5234);
+        assert_eq!(
+            translate_gva(&guest_mem, &sregs, 0x20_0123).unwrap(),
+            0x40_0123
+        );
+        assert!(translate_gva(&guest_mem, &sregs, 0x2000).is_err());
+
+        // Linear addresses are 32 bits wide.
+        assert_eq!(
+            translate_gva(&guest_mem, &sregs, 0xffff_ffff_0000_1234).unwrap(),
+            0x5234
+        );
+
+        // PDPTEs have no page size bit.
+        set_entry(&guest_mem, 0x1000, 1, 0x2000 | PTE_PRESENT | PTE_PAGE_SIZE);
+        assert!(translate_gva(&guest_mem, &sregs, 0x4000_1234).is_err());
+    }
+
+    #[test]
+    fn test_translate_4_level() {
+        let guest_mem = guest_mem();
+        let sregs = sregs(true);
+        set_entry(&guest_mem, 0x1000, 0, 0x2000 | PTE_PRESENT);
+        set_entry(&guest_mem, 0x2000, 0, 0x3000 | PTE_PRESENT);
+        set_entry(
+            &guest_mem,
+            0x2000,
+            1,
+            0x4000_0000 | PTE_PRESENT | PTE_PAGE_SIZE,
+        );
+        set_entry(&guest_mem, 0x3000, 0, 0x4000 | PTE_PRESENT);
+        set_entry(&guest_mem, 0x4000, 1, 0x5000 | PTE_PRESENT);
+
+        assert_eq!(translate_gva(&guest_mem, &sregs, 0x1234).unwrap(), 0x5234);
+        // A 1GiB page.
+        assert_eq!(
+            translate_gva(&guest_mem, &sregs, 0x7654_3210).unwrap(),
+            0x7654_3210
+        );
+        // The upper half has no mapping.
+        assert!(translate_gva(&guest_mem, &sregs, 0xffff_ffff_0000_1234).is_err());
+    }
+
+    #[test]
+    fn test_read_write_virtual() {
+        let guest_mem = guest_mem();
+        let sregs = sregs(true);
+        set_entry(&guest_mem, 0x1000, 0, 0x2000 | PTE_PRESENT);
+        set_entry(&guest_mem, 0x2000, 0, 0x3000 | PTE_PRESENT);
+        set_entry(&guest_mem, 0x3000, 0, 0x4000 | PTE_PRESENT);
+        // Two virtually contiguous pages, physically in the reverse order.
+        set_entry(&guest_mem, 0x4000, 1, 0x7000 | PTE_PRESENT);
+        set_entry(&guest_mem, 0x4000, 2, 0x6000 | PTE_PRESENT);
+
+        write_virtual(&guest_mem, &sregs, 0x1ffe, &[1, 2, 3, 4]).unwrap();
+        let low: [u8; 2] = guest_mem.read_obj(GuestAddress(0x7ffe)).unwrap();
+        let high: [u8; 2] = guest_mem.read_obj(GuestAddress(0x6000)).unwrap();
+        assert_eq!((low, high), ([1, 2], [3, 4]));
+
+        let mut buf = [0u8; 4];
+        read_virtual(&guest_mem, &sregs, 0x1ffe, &mut buf).unwrap();
+        assert_eq!(buf, [1, 2, 3, 4]);
+
+        // Nothing maps the page after.
+        assert!(read_virtual(&guest_mem, &sregs, 0x2ffe, &mut buf).is_err());
+    }
+}
@@ -1,9 +1,10 @@
 use anyhow::{Context, Result};
 use kvm_bindings::{kvm_fpu, kvm_regs, kvm_sregs};
 use kvm_ioctls::VcpuFd;
-use vm_memory::{Address, Bytes, GuestAddress, GuestMemory, GuestMemoryMmap};
+use vm_memory::{Address, Bytes, GuestAddress, GuestMemory};
 
 use crate::arch::gdt::{gdt_entry, kvm_segment_from_gdt};
+use crate::arch::memory::GuestMemoryMmap;
 
 // Initial pagetables.
 const PML4_START: u64 = 0x9000;
@@ -15,12 +16,12 @@ const BOOT_GDT_MAX: usize = 4;
 const BOOT_GDT_OFFSET: u64 = 0x500;
 const BOOT_IDT_OFFSET: u64 = 0x520;
 
-const EFER_LMA: u64 = 0x400;
+pub const EFER_LMA: u64 = 0x400;
 const EFER_LME: u64 = 0x100;
 
 const X86_CR0_PE: u64 = 0x1;
-const X86_CR0_PG: u64 = 0x8000_0000;
-const X86_CR4_PAE: u64 = 0x20;
+pub const X86_CR0_PG: u64 = 0x8000_0000;
+pub const X86_CR4_PAE: u64 = 0x20;
 
 pub fn init_regs(vcpu: &VcpuFd, boot_ip: u64) -> Result<()> {
     let regs = kvm_regs {
@@ -56,37 +57,48 @@ pub fn init_fpu(vcpu: &VcpuFd) -> Result<()> {
     Ok(())
 }
 
-pub fn init_sregs(guest_mem: &GuestMemoryMmap, vcpu: &VcpuFd) -> Result<()> {
+/// Points the vCPU at the boot GDT, IDT and page tables, in 64-bit mode. Those are written by
+/// `setup_boot_tables` for a booting guest, a restored guest brings its own.
+pub fn init_sregs(vcpu: &VcpuFd) -> Result<()> {
     let mut sregs = vcpu.get_sregs().context("failed to get sregs")?;
 
-    configure_segments_and_sregs(guest_mem, &mut sregs)
-        .context("failed to configure segments and sregs")?;
+    configure_segments_and_sregs(&mut sregs).context("failed to configure segments and sregs")?;
 
-    setup_page_tables(guest_mem, &mut sregs).context("failed to setup page tables")?;
+    sregs.cr3 = PML4_START;
+    sregs.cr4 |= X86_CR4_PAE;
+    sregs.cr0 |= X86_CR0_PG;
 
     vcpu.set_sregs(&sregs).context("failed to set sregs")?;
 
     Ok(())
 }
 
-fn configure_segments_and_sregs(guest_mem: &GuestMemoryMmap, sregs: &mut kvm_sregs) -> Result<()> {
-    let gdt_table: [u64; BOOT_GDT_MAX] = [
+/// Writes the GDT, IDT and page tables `init_sregs` points the vCPUs at into guest memory.
+pub fn setup_boot_tables(guest_mem: &GuestMemoryMmap) -> Result<()> {
+    write_gdt_table(&boot_gdt(), guest_mem)?;
+    write_idt_value(0, guest_mem)?;
+
+    setup_page_tables(guest_mem).context("failed to setup page tables")
+}
+
+fn boot_gdt() -> [u64; BOOT_GDT_MAX] {
+    [
         gdt_entry(0, 0, 0),            // NULL
         gdt_entry(0xa09b, 0, 0xfffff), // CODE
         gdt_entry(0xc093, 0, 0xfffff), // DATA
         gdt_entry(0x808b, 0, 0xfffff), // TSS
-    ];
+    ]
+}
 
+fn configure_segments_and_sregs(sregs: &mut kvm_sregs) -> Result<()> {
+    let gdt_table = boot_gdt();
     let code_seg = kvm_segment_from_gdt(gdt_table[1], 1);
     let data_seg = kvm_segment_from_gdt(gdt_table[2], 2);
     let tss_seg = kvm_segment_from_gdt(gdt_table[3], 3);
 
-    // Write segments
-    write_gdt_table(&gdt_table[..], guest_mem)?;
     sregs.gdt.base = BOOT_GDT_OFFSET;
     sregs.gdt.limit = u16::try_from(std::mem::size_of_val(&gdt_table))? - 1;
 
-    write_idt_value(0, guest_mem)?;
     sregs.idt.base = BOOT_IDT_OFFSET;
     sregs.idt.limit = u16::try_from(std::mem::size_of::<u64>())? - 1;
 
@@ -131,7 +143,7 @@ fn write_idt_value(val: u64, guest_mem: &GuestMemoryMmap) -> Result<()> {
     Ok(())
 }
 
-fn setup_page_tables(guest_mem: &GuestMemoryMmap, sregs: &mut kvm_sregs) -> Result<()> {
+fn setup_page_tables(guest_mem: &GuestMemoryMmap) -> Result<()> {
     // Puts PML4 right after zero page but aligned to 4k.
     let boot_pml4_addr = GuestAddress(PML4_START);
     let boot_pdpte_addr = GuestAddress(PDPTE_START);
@@ -155,9 +167,5 @@ fn setup_page_tables(guest_mem: &GuestMemoryMmap, sregs: &mut kvm_sregs) -> Resu
             .context("failed to write PDE address")?;
     }
 
-    sregs.cr3 = boot_pml4_addr.raw_value();
-    sregs.cr4 |= X86_CR4_PAE;
-    sregs.cr0 |= X86_CR0_PG;
-
     Ok(())
 }
@@ -0,0 +1,224 @@
+use std::io::{Read, Write};
+
+use anyhow::{Context, Result};
+use kvm_bindings::{
+    kvm_clock_data, kvm_debugregs, kvm_fpu, kvm_irqchip, kvm_lapic_state, kvm_mp_state,
+    kvm_msr_entry, kvm_pit_state2, kvm_regs, kvm_sregs, kvm_vcpu_events, kvm_xcrs, kvm_xsave, Msrs,
+    KVM_IRQCHIP_IOAPIC, KVM_IRQCHIP_PIC_MASTER, KVM_IRQCHIP_PIC_SLAVE,
+};
+use kvm_ioctls::{Kvm, VcpuFd, VmFd};
+use log::debug;
+
+use crate::snapshot::persist::{StateReader, StateWriter};
+
+/// Architectural state of one vCPU, everything KVM needs to resume it elsewhere.
+#[derive(Debug, Default, Clone)]
+pub struct VcpuState {
+    pub regs: kvm_regs,
+    pub sregs: kvm_sregs,
+    pub fpu: kvm_fpu,
+    /// The legacy 4KiB `kvm_xsave` region, the struct itself is neither `Copy` nor `Default`.
+    pub xsave: Vec<u32>,
+    pub xcrs: kvm_xcrs,
+    pub debug_regs: kvm_debugregs,
+    pub lapic: kvm_lapic_state,
+    pub mp_state: kvm_mp_state,
+    pub vcpu_events: kvm_vcpu_events,
+    pub msrs: Vec<kvm_msr_entry>,
+}
+
+impl VcpuState {
+    /// Reads the state of a vCPU that is not inside KVM_RUN.
+    pub fn save(vcpu: &VcpuFd, msr_indices: &[u32]) -> Result<VcpuState> {
+        // KVM_GET_MP_STATE may process pending APIC events, so it has to come before
+        // KVM_GET_VCPU_EVENTS.
+        let mp_state = vcpu.get_mp_state().context("failed to get mp state")?;
+        let vcpu_events = vcpu
+            .get_vcpu_events()
+            .context("failed to get vcpu events")?;
+
+        Ok(VcpuState {
+            regs: vcpu.get_regs().context("failed to get regs")?,
+            sregs: vcpu.get_sregs().context("failed to get sregs")?,
+            fpu: vcpu.get_fpu().context("failed to get fpu")?,
+            xsave: vcpu
+                .get_xsave()
+                .context("failed to get xsave")?
+                .region
+                .to_vec(),
+            xcrs: vcpu.get_xcrs().context("failed to get xcrs")?,
+            debug_regs: vcpu.get_debug_regs().context("failed to get debug regs")?,
+            lapic: vcpu.get_lapic().context("failed to get lapic")?,
+            mp_state,
+            vcpu_events,
+            msrs: get_msrs(vcpu, msr_indices)?,
+        })
+    }
+
+    /// Loads the state into a vCPU configured with the same CPUID as the saved one.
+    pub fn restore(&self, vcpu: &VcpuFd) -> Result<()> {
+        vcpu.set_mp_state(self.mp_state)
+            .context("failed to set mp state")?;
+        vcpu.set_regs(&self.regs).context("failed to set regs")?;
+        vcpu.set_sregs(&self.sregs).context("failed to set sregs")?;
+        vcpu.set_fpu(&self.fpu).context("failed to set fpu")?;
+        vcpu.set_xsave(&self.kvm_xsave()?)
+            .context("failed to set xsave")?;
+        vcpu.set_xcrs(&self.xcrs).context("failed to set xcrs")?;
+        vcpu.set_debug_regs(&self.debug_regs)
+            .context("failed to set debug regs")?;
+        vcpu.set_lapic(&self.lapic).context("failed to set lapic")?;
+
+        let msrs = Msrs::from_entries(&self.msrs).context("failed to build msrs")?;
+        let written = vcpu.set_msrs(&msrs).context("failed to set msrs")?;
+        if written != self.msrs.len() {
+            anyhow::bail!("failed to set msr {:#x}", self.msrs[written].index)
+        }
+
+        vcpu.set_vcpu_events(&self.vcpu_events)
+            .context("failed to set vcpu events")?;
+
+        Ok(())
+    }
+
+    fn kvm_xsave(&self) -> Result<kvm_xsave> {
+        // SAFETY: kvm_xsave only holds integers, all zeroes is a valid value.
+        let mut xsave: kvm_xsave = unsafe { std::mem::zeroed() };
+        if self.xsave.len() != xsave.region.len() {
+            anyhow::bail!("invalid xsave region of {} words", self.xsave.len())
+        }
+
+        xsave.region.copy_from_slice(&self.xsave);
+        Ok(xsave)
+    }
+
+    pub fn persist<W: Write>(&self, w: &mut StateWriter<W>) -> Result<()> {
+        w.write_pod(&self.regs)?;
+        w.write_pod(&self.sregs)?;
+        w.write_pod(&self.fpu)?;
+        let xsave: Vec<u8> = self.xsave.iter().flat_map(|w| w.to_le_bytes()).collect();
+        w.write_vec(&xsave)?;
+        w.write_pod(&self.xcrs)?;
+        w.write_pod(&self.debug_regs)?;
+        w.write_pod(&self.lapic)?;
+        w.write_pod(&self.mp_state)?;
+        w.write_pod(&self.vcpu_events)?;
+        w.write_pod_slice(&self.msrs)
+    }
+
+    pub fn load<R: Read>(r: &mut StateReader<R>) -> Result<VcpuState> {
+        Ok(VcpuState {
+            regs: r.read_pod()?,
+            sregs: r.read_pod()?,
+            fpu: r.read_pod()?,
+            xsave: r
+                .read_vec()?
+                .chunks_exact(4)
+                .map(|b| u32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+                .collect(),
+            xcrs: r.read_pod()?,
+            debug_regs: r.read_pod()?,
+            lapic: r.read_pod()?,
+            mp_state: r.read_pod()?,
+            vcpu_events: r.read_pod()?,
+            msrs: r.read_pod_vec()?,
+        })
+    }
+}
+
+/// Returns the MSRs that KVM can save for this host, see `VcpuState::save`.
+pub fn supported_msr_indices(kvm: &Kvm) -> Result<Vec<u32>> {
+    let msr_list = kvm
+        .get_msr_index_list()
+        .context("failed to get msr index list")?;
+
+    Ok(msr_list.as_slice().to_vec())
+}
+
+/// Reads `indices` from the vCPU. KVM stops at the first MSR it cannot read, such MSRs are
+/// skipped.
+fn get_msrs(vcpu: &VcpuFd, indices: &[u32]) -> Result<Vec<kvm_msr_entry>> {
+    let mut entries: Vec<kvm_msr_entry> = indices
+        .iter()
+        .map(|&index| kvm_msr_entry {
+            index,
+            ..Default::default()
+        })
+        .collect();
+
+    let mut saved = Vec::with_capacity(entries.len());
+    while !entries.is_empty() {
+        let mut msrs = Msrs::from_entries(&entries).context("failed to build msrs")?;
+        let read = vcpu.get_msrs(&mut msrs).context("failed to get msrs")?;
+
+        saved.extend_from_slice(&msrs.as_slice()[..read]);
+        if read < entries.len() {
+            debug!("skipping unreadable msr {:#x}", entries[read].index);
+            entries.drain(..=read);
+        } else {
+            entries.clear();
+        }
+    }
+
+    Ok(saved)
+}
+
+/// State of the in-kernel irqchip, PIT and kvmclock.
+#[derive(Debug, Default, Clone)]
+pub struct VmState {
+    pub pic_master: kvm_irqchip,
+    pub pic_slave: kvm_irqchip,
+    pub ioapic: kvm_irqchip,
+    pub pit: kvm_pit_state2,
+    pub clock: kvm_clock_data,
+}
+
+impl VmState {
+    /// Reads the VM state. `clock` is the kvmclock saved when the vCPUs were paused.
+    pub fn save(vm: &VmFd, clock: kvm_clock_data) -> Result<VmState> {
+        Ok(VmState {
+            pic_master: get_irqchip(vm, KVM_IRQCHIP_PIC_MASTER)?,
+            pic_slave: get_irqchip(vm, KVM_IRQCHIP_PIC_SLAVE)?,
+            ioapic: get_irqchip(vm, KVM_IRQCHIP_IOAPIC)?,
+            pit: vm.get_pit2().context("failed to get pit2")?,
+            clock,
+        })
+    }
+
+    pub fn restore(&self, vm: &VmFd) -> Result<()> {
+        for chip in [&self.pic_master, &self.pic_slave, &self.ioapic] {
+            vm.set_irqchip(chip).context("failed to set irqchip")?;
+        }
+        vm.set_pit2(&self.pit).context("failed to set pit2")?;
+        crate::arch::clock::restore_clock(vm, &self.clock)
+    }
+
+    pub fn persist<W: Write>(&self, w: &mut StateWriter<W>) -> Result<()> {
+        w.write_pod(&self.pic_master)?;
+        w.write_pod(&self.pic_slave)?;
+        w.write_pod(&self.ioapic)?;
+        w.write_pod(&self.pit)?;
+        w.write_pod(&self.clock)
+    }
+
+    pub fn load<R: Read>(r: &mut StateReader<R>) -> Result<VmState> {
+        Ok(VmState {
+            pic_master: r.read_pod()?,
+            pic_slave: r.read_pod()?,
+            ioapic: r.read_pod()?,
+            pit: r.read_pod()?,
+            clock: r.read_pod()?,
+        })
+    }
+}
+
+fn get_irqchip(vm: &VmFd, chip_id: u32) -> Result<kvm_irqchip> {
+    let mut chip = kvm_irqchip {
+        chip_id,
+        ..Default::default()
+    };
+
+    vm.get_irqchip(&mut chip).context("failed to get irqchip")?;
+
+    Ok(chip)
+}
@@ -10,9 +10,9 @@ use linux_loader::{
     bootparam::boot_params,
     loader::{elf::Elf, KernelLoader},
 };
-use vm_memory::{
-    Address, GuestAddress, GuestMemory, GuestMemoryMmap, GuestMemoryRegion, ReadVolatile,
-};
+use vm_memory::{Address, GuestAddress, GuestMemory, GuestMemoryRegion, ReadVolatile};
+
+use crate::arch::memory::GuestMemoryMmap;
 
 // Value taken from https://elixir.bootlin.com/linux/v5.10.68/source/arch/x86/include/uapi/asm/e820.h#L31
 // Usable normal RAM
@@ -67,10 +67,10 @@ pub fn load_initrd<P: AsRef<Path>>(
     image.seek(SeekFrom::Start(0))?;
 
     // Get the target address
-    let address = initrd_load_addr(vm_memory, size as usize)?;
+    let address = initrd_load_addr(vm_memory, size)?;
 
     // Load the image into memory
-    let mut slice = vm_memory.get_slice(GuestAddress(address), size as usize)?;
+    let mut slice = vm_memory.get_slice(GuestAddress(address), size)?;
 
     image.read_exact_volatile(&mut slice)?;
 
@@ -101,6 +101,7 @@ pub fn configure_system(
     cmdline_addr: GuestAddress,
     cmdline_size: usize,
     initrd: &Option<crate::arch::InitrdConfig>,
+    topology: &crate::arch::CpuTopology,
 ) -> Result<()> {
     const KERNEL_BOOT_FLAG_MAGIC: u16 = 0xaa55;
     const KERNEL_HDR_MAGIC: u32 = 0x5372_6448;
@@ -112,9 +113,8 @@ pub fn configure_system(
 
     let himem_start = GuestAddress(crate::arch::layout::KERNEL_START_ADDRESS);
 
-    // TODO: support mptable
     // Note that this puts the mptable at the last 1k of Linux's 640k base RAM
-    //mptable::setup_mptable(guest_mem, num_cpus)?;
+    super::mptable::setup_mptable(guest_mem, topology).context("failed to setup mptable")?;
 
     let mut params = boot_params::default();
 
@@ -177,11 +177,12 @@ pub fn configure_system(
 pub fn load_boot_cmdline(
     guest_mem: &GuestMemoryMmap,
     boot_source_cfg: &crate::arch::BootSourceConfig,
+    irqchip_mode: crate::arch::IrqChipMode,
 ) -> Result<(GuestAddress, usize)> {
     let cmdline_addr = GuestAddress(crate::arch::layout::CMDLINE_START);
 
     let (boot_cmdline, cmdline_size) = boot_source_cfg
-        .to_kernel_cmdline()
+        .to_kernel_cmdline(irqchip_mode)
         .context("failed to build kernel cmdline")?;
 
     load_cmdline(guest_mem, cmdline_addr, &boot_cmdline).context("failed to load boot cmdline")?;
@@ -1,28 +1,424 @@
 use anyhow::{Context, Result};
-use kvm_bindings::KVM_MAX_CPUID_ENTRIES;
+use kvm_bindings::{kvm_cpuid_entry2, KVM_CPUID_FLAG_SIGNIFCANT_INDEX, KVM_MAX_CPUID_ENTRIES};
 use kvm_ioctls::{Kvm, VcpuFd};
+use log::{info, warn};
+
+use crate::arch::{CpuTopology, KvmPvFeatures, VcpuConfig};
 
 const KVM_CPUID_SIGNATURE: u32 = 0x40000000;
 const KVM_CPUID_FEATURES: u32 = 0x40000001;
+const KVM_CPUID_TIMING_INFO: u32 = 0x40000010;
+
+// KVM_CPUID_FEATURES eax bits, see the kernel's arch/x86/include/uapi/asm/kvm_para.h.
+const KVM_FEATURE_CLOCKSOURCE: u32 = 1 << 0;
+const KVM_FEATURE_CLOCKSOURCE2: u32 = 1 << 3;
+const KVM_FEATURE_ASYNC_PF: u32 = 1 << 4;
+const KVM_FEATURE_STEAL_TIME: u32 = 1 << 5;
+const KVM_FEATURE_PV_EOI: u32 = 1 << 6;
+const KVM_FEATURE_PV_UNHALT: u32 = 1 << 7;
+const KVM_FEATURE_PV_TLB_FLUSH: u32 = 1 << 9;
+const KVM_FEATURE_ASYNC_PF_VMEXIT: u32 = 1 << 10;
+const KVM_FEATURE_PV_SEND_IPI: u32 = 1 << 11;
+const KVM_FEATURE_ASYNC_PF_INT: u32 = 1 << 14;
+const KVM_FEATURE_CLOCKSOURCE_STABLE_BIT: u32 = 1 << 24;
+
+// Topology related leaves.
+const CPUID_VENDOR_ID: u32 = 0x0;
+const CPUID_FEATURE_INFO: u32 = 0x1;
+const CPUID_CACHE_PARAMS: u32 = 0x4;
+const CPUID_EXT_TOPOLOGY: u32 = 0xb;
+const CPUID_V2_EXT_TOPOLOGY: u32 = 0x1f;
+const CPUID_AMD_ADDRESS_SIZES: u32 = 0x8000_0008;
+const CPUID_AMD_CACHE_TOPOLOGY: u32 = 0x8000_001d;
+const CPUID_AMD_EXT_APIC_ID: u32 = 0x8000_001e;
+
+// Leaf 0x0 ebx of AMD and Hygon CPUs, "Auth" of AuthenticAMD and "Hygo" of HygonGenuine.
+const VENDOR_AMD_EBX: u32 = 0x6874_7541;
+const VENDOR_HYGON_EBX: u32 = 0x6f67_7948;
+
+// Leaf 0x1 fields.
+const EBX_APIC_ID_SHIFT: u32 = 24;
+const EBX_CPU_COUNT_SHIFT: u32 = 16;
+const EBX_CPU_COUNT_MASK: u32 = 0xff << EBX_CPU_COUNT_SHIFT;
+const EDX_HTT_BIT: u32 = 28;
+
+// Leaf 0x4 fields.
+const EAX_CACHE_LEVEL_SHIFT: u32 = 5;
+const EAX_CACHE_LEVEL_MASK: u32 = 0x7;
+const EAX_SHARING_SHIFT: u32 = 14;
+const EAX_SHARING_MASK: u32 = 0xfff << EAX_SHARING_SHIFT;
+const EAX_CORES_SHIFT: u32 = 26;
+const EAX_CORES_MASK: u32 = 0x3f << EAX_CORES_SHIFT;
+
+// Leaf 0x8000_0008 ecx fields.
+const ECX_THREAD_COUNT_MASK: u32 = 0xff;
+const ECX_APIC_ID_SIZE_SHIFT: u32 = 12;
+const ECX_APIC_ID_SIZE_MASK: u32 = 0xf << ECX_APIC_ID_SIZE_SHIFT;
+
+// Leaf 0x8000_001e fields.
+const EBX_THREADS_PER_CORE_SHIFT: u32 = 8;
+
+// Leaf 0xb / 0x1f level types.
+const LEVEL_TYPE_INVALID: u32 = 0;
+const LEVEL_TYPE_SMT: u32 = 1;
+const LEVEL_TYPE_CORE: u32 = 2;
+const ECX_LEVEL_TYPE_SHIFT: u32 = 8;
+/// The SMT and core levels, the subleaf after them ends the list.
+const TOPOLOGY_LEVELS: u32 = 2;
 
 // KVM CPU feature flags
-pub fn init_cpu_id(vm: &Kvm, vcpu: &VcpuFd) -> Result<()> {
+pub fn init_cpu_id(
+    vm: &Kvm,
+    vcpu: &VcpuFd,
+    cpu_index: u8,
+    config: &VcpuConfig,
+    tsc_khz: u32,
+) -> Result<()> {
     let mut cpuid = vm
         .get_supported_cpuid(KVM_MAX_CPUID_ENTRIES)
         .context("failed to get supported cpuid")?;
 
+    // Advertise the TSC and APIC bus frequencies, so that the guest does not need to calibrate.
+    if !cpuid
+        .as_slice()
+        .iter()
+        .any(|entry| entry.function == KVM_CPUID_TIMING_INFO)
+    {
+        cpuid
+            .push(kvm_cpuid_entry2 {
+                function: KVM_CPUID_TIMING_INFO,
+                ..Default::default()
+            })
+            .context("failed to add cpuid timing leaf")?;
+    }
+
+    // The host may list only the first subleaf, the guest needs the SMT and core levels.
+    for function in [CPUID_EXT_TOPOLOGY, CPUID_V2_EXT_TOPOLOGY] {
+        let subleaves: Vec<u32> = cpuid
+            .as_slice()
+            .iter()
+            .filter(|entry| entry.function == function)
+            .map(|entry| entry.index)
+            .collect();
+        if subleaves.is_empty() {
+            continue;
+        }
+
+        for index in (0..=TOPOLOGY_LEVELS).filter(|index| !subleaves.contains(index)) {
+            cpuid
+                .push(kvm_cpuid_entry2 {
+                    function,
+                    index,
+                    flags: KVM_CPUID_FLAG_SIGNIFCANT_INDEX,
+                    ..Default::default()
+                })
+                .context("failed to add cpuid topology subleaf")?;
+        }
+    }
+
     let entries = cpuid.as_mut_slice();
 
     for entry in entries.iter_mut() {
         if entry.function == KVM_CPUID_SIGNATURE {
-            entry.eax = KVM_CPUID_FEATURES;
+            entry.eax = KVM_CPUID_TIMING_INFO;
             entry.ebx = 0x4b4d564b; // KVMK
             entry.ecx = 0x564b4d56; // VMKV
             entry.edx = 0x4d; // M
         }
+
+        if entry.function == KVM_CPUID_TIMING_INFO {
+            entry.eax = tsc_khz;
+            entry.ebx = crate::arch::clock::KVM_APIC_BUS_KHZ;
+        }
+
+        if entry.function == KVM_CPUID_FEATURES {
+            entry.eax = pv_features_eax(entry.eax, &config.pv_features, cpu_index == 0);
+        }
     }
 
+    patch_topology(entries, cpu_index, &config.topology);
+
     vcpu.set_cpuid2(&cpuid).context("failed to set cpuid2")?;
 
     Ok(())
 }
+
+/// Builds the `KVM_CPUID_FEATURES` eax value from the configured features, masked by what the
+/// host KVM supports. Bits without a knob are passed through untouched.
+fn pv_features_eax(host_eax: u32, features: &KvmPvFeatures, log_result: bool) -> u32 {
+    let knobs = [
+        (
+            features.kvmclock,
+            KVM_FEATURE_CLOCKSOURCE | KVM_FEATURE_CLOCKSOURCE2 | KVM_FEATURE_CLOCKSOURCE_STABLE_BIT,
+        ),
+        (
+            features.async_pf,
+            KVM_FEATURE_ASYNC_PF | KVM_FEATURE_ASYNC_PF_VMEXIT | KVM_FEATURE_ASYNC_PF_INT,
+        ),
+        (features.steal_time, KVM_FEATURE_STEAL_TIME),
+        (features.pv_eoi, KVM_FEATURE_PV_EOI),
+        (features.pv_unhalt, KVM_FEATURE_PV_UNHALT),
+        (features.pv_tlb_flush, KVM_FEATURE_PV_TLB_FLUSH),
+        (features.pv_send_ipi, KVM_FEATURE_PV_SEND_IPI),
+    ];
+
+    let mut eax = host_eax;
+    for (enabled, bits) in knobs {
+        if !enabled {
+            eax &= !bits;
+        } else if host_eax & bits == 0 && log_result {
+            warn!("pv feature {:#x} is not supported by host kvm", bits);
+        }
+    }
+
+    if log_result {
+        info!(
+            "kvm pv features: {} (cpuid eax {:#x})",
+            features.enabled_names().join(","),
+            eax
+        );
+    }
+
+    eax
+}
+
+/// Rewrites the topology leaves so that the guest sees the configured sockets, cores and
+/// threads instead of the host's, with `cpu_index` identifying the vCPU being configured.
+///
+/// The counts of addressable IDs in leaves 0x1, 0x4 and 0x8000_001d follow the APIC ID fields
+/// leaf 0xb reports, which are powers of two, as Linux derives the package layout from both.
+fn patch_topology(entries: &mut [kvm_cpuid_entry2], cpu_index: u8, topology: &CpuTopology) {
+    let apic_id = topology.apic_id(cpu_index);
+    let threads_per_core = u32::from(topology.threads);
+    let threads_per_package = u32::from(topology.threads_per_package());
+    let thread_ids = 1 << topology.thread_shift();
+    let package_ids = 1 << topology.core_shift();
+    let core_ids = package_ids >> topology.thread_shift();
+    let amd = entries.iter().any(|entry| {
+        entry.function == CPUID_VENDOR_ID
+            && (entry.ebx == VENDOR_AMD_EBX || entry.ebx == VENDOR_HYGON_EBX)
+    });
+
+    for entry in entries.iter_mut() {
+        match entry.function {
+            CPUID_FEATURE_INFO => {
+                entry.ebx &= !(0xff << EBX_APIC_ID_SHIFT) & !EBX_CPU_COUNT_MASK;
+                entry.ebx |= (apic_id & 0xff) << EBX_APIC_ID_SHIFT;
+                entry.ebx |= (package_ids & 0xff) << EBX_CPU_COUNT_SHIFT;
+
+                if threads_per_package > 1 {
+                    entry.edx |= 1 << EDX_HTT_BIT;
+                } else {
+                    entry.edx &= !(1 << EDX_HTT_BIT);
+                }
+            }
+            CPUID_CACHE_PARAMS => {
+                let Some(sharing) = cache_sharing(entry.eax, thread_ids, package_ids) else {
+                    continue;
+                };
+
+                entry.eax &= !EAX_SHARING_MASK & !EAX_CORES_MASK;
+                entry.eax |= ((sharing - 1) << EAX_SHARING_SHIFT) & EAX_SHARING_MASK;
+                entry.eax |= ((core_ids - 1) << EAX_CORES_SHIFT) & EAX_CORES_MASK;
+            }
+            CPUID_EXT_TOPOLOGY | CPUID_V2_EXT_TOPOLOGY => {
+                let (shift, count, level_type) = match entry.index {
+                    0 => (topology.thread_shift(), threads_per_core, LEVEL_TYPE_SMT),
+                    1 => (topology.core_shift(), threads_per_package, LEVEL_TYPE_CORE),
+                    _ => (0, 0, LEVEL_TYPE_INVALID),
+                };
+
+                entry.eax = shift;
+                entry.ebx = count;
+                entry.ecx = (level_type << ECX_LEVEL_TYPE_SHIFT) | (entry.index & 0xff);
+                entry.edx = apic_id;
+            }
+            CPUID_AMD_ADDRESS_SIZES if amd => {
+                entry.ecx &= !ECX_THREAD_COUNT_MASK & !ECX_APIC_ID_SIZE_MASK;
+                entry.ecx |= (threads_per_package - 1) & ECX_THREAD_COUNT_MASK;
+                entry.ecx |= topology.core_shift() << ECX_APIC_ID_SIZE_SHIFT;
+            }
+            CPUID_AMD_CACHE_TOPOLOGY if amd => {
+                let Some(sharing) = cache_sharing(entry.eax, thread_ids, package_ids) else {
+                    continue;
+                };
+
+                entry.eax &= !EAX_SHARING_MASK;
+                entry.eax |= ((sharing - 1) << EAX_SHARING_SHIFT) & EAX_SHARING_MASK;
+            }
+            CPUID_AMD_EXT_APIC_ID if amd => {
+                // One node per package, with the socket as its ID.
+                let core_id = (apic_id >> topology.thread_shift()) & (core_ids - 1);
+                entry.eax = apic_id;
+                entry.ebx = ((threads_per_core - 1) << EBX_THREADS_PER_CORE_SHIFT) | core_id;
+                entry.ecx = apic_id >> topology.core_shift();
+                entry.edx = 0;
+            }
+            _ => {}
+        }
+    }
+}
+
+/// The addressable IDs sharing the cache a leaf 0x4 or 0x8000_001d subleaf describes, none for
+/// the subleaf ending the list. L1 and L2 are private to a core, L3 is shared by the package.
+fn cache_sharing(eax: u32, thread_ids: u32, package_ids: u32) -> Option<u32> {
+    match (eax >> EAX_CACHE_LEVEL_SHIFT) & EAX_CACHE_LEVEL_MASK {
+        0 => None,
+        1 | 2 => Some(thread_ids),
+        _ => Some(package_ids),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Leaf 0x0 ebx of Intel CPUs, "Genu" of GenuineIntel.
+    const VENDOR_INTEL_EBX: u32 = 0x756e_6547;
+
+    fn entry(function: u32, index: u32, eax: u32) -> kvm_cpuid_entry2 {
+        kvm_cpuid_entry2 {
+            function,
+            index,
+            eax,
+            ..Default::default()
+        }
+    }
+
+    /// The topology leaves of a host with a private L1d and L2 and a shared L3.
+    fn host_entries(vendor_ebx: u32) -> Vec<kvm_cpuid_entry2> {
+        let mut entries = vec![
+            kvm_cpuid_entry2 {
+                ebx: vendor_ebx,
+                ..entry(CPUID_VENDOR_ID, 0, 0xd)
+            },
+            kvm_cpuid_entry2 {
+                ebx: 0xff_ff_08_00,
+                edx: 1 << EDX_HTT_BIT,
+                ..entry(CPUID_FEATURE_INFO, 0, 0)
+            },
+            kvm_cpuid_entry2 {
+                ecx: 0x7f0ff,
+                ..entry(CPUID_AMD_ADDRESS_SIZES, 0, 0x3030)
+            },
+            entry(CPUID_AMD_EXT_APIC_ID, 0, 0),
+        ];
+        for function in [CPUID_CACHE_PARAMS, CPUID_AMD_CACHE_TOPOLOGY] {
+            entries.extend([
+                entry(function, 0, (1 << EAX_CACHE_LEVEL_SHIFT) | 1),
+                entry(function, 1, (2 << EAX_CACHE_LEVEL_SHIFT) | 3),
+                entry(
+                    function,
+                    2,
+                    (3 << EAX_CACHE_LEVEL_SHIFT) | 3 | EAX_SHARING_MASK,
+                ),
+                entry(function, 3, 0),
+            ]);
+        }
+        for function in [CPUID_EXT_TOPOLOGY, CPUID_V2_EXT_TOPOLOGY] {
+            entries.extend((0..=TOPOLOGY_LEVELS).map(|index| entry(function, index, 0)));
+        }
+
+        entries
+    }
+
+    fn leaf(entries: &[kvm_cpuid_entry2], function: u32, index: u32) -> (u32, u32, u32, u32) {
+        let entry = entries
+            .iter()
+            .find(|entry| entry.function == function && entry.index == index)
+            .unwrap();
+
+        (entry.eax, entry.ebx, entry.ecx, entry.edx)
+    }
+
+    #[test]
+    fn test_patch_topology_single_cpu() {
+        let mut entries = host_entries(VENDOR_AMD_EBX);
+        patch_topology(&mut entries, 0, &CpuTopology::default());
+
+        let (_, ebx, _, edx) = leaf(&entries, CPUID_FEATURE_INFO, 0);
+        assert_eq!(ebx, 0x00_01_08_00);
+        assert_eq!(edx & (1 << EDX_HTT_BIT), 0);
+
+        // Every cache is private, in a package of one core.
+        for index in 0..3 {
+            let (eax, ..) = leaf(&entries, CPUID_CACHE_PARAMS, index);
+            assert_eq!(eax & (EAX_SHARING_MASK | EAX_CORES_MASK), 0);
+        }
+        assert_eq!(leaf(&entries, CPUID_CACHE_PARAMS, 3).0, 0);
+
+        for function in [CPUID_EXT_TOPOLOGY, CPUID_V2_EXT_TOPOLOGY] {
+            assert_eq!(leaf(&entries, function, 0), (0, 1, 0x100, 0));
+            assert_eq!(leaf(&entries, function, 1), (0, 1, 0x201, 0));
+            assert_eq!(leaf(&entries, function, 2), (0, 0, 0x2, 0));
+        }
+
+        assert_eq!(leaf(&entries, CPUID_AMD_ADDRESS_SIZES, 0).2, 0x70000);
+        assert_eq!(leaf(&entries, CPUID_AMD_EXT_APIC_ID, 0), (0, 0, 0, 0));
+    }
+
+    #[test]
+    fn test_patch_topology_sockets_cores_threads() {
+        let topology: CpuTopology = "sockets=2,cores=3,threads=2".parse().unwrap();
+
+        // The second thread of the third core of the second socket.
+        let mut entries = host_entries(VENDOR_AMD_EBX);
+        patch_topology(&mut entries, 11, &topology);
+        let apic_id = (1 << 3) | (2 << 1) | 1;
+
+        let (_, ebx, _, edx) = leaf(&entries, CPUID_FEATURE_INFO, 0);
+        assert_eq!(
+            ebx,
+            (apic_id << EBX_APIC_ID_SHIFT) | (8 << EBX_CPU_COUNT_SHIFT) | 0x800
+        );
+        assert_ne!(edx & (1 << EDX_HTT_BIT), 0);
+
+        // L1 and L2 are shared by the 2 thread IDs of a core, L3 by the 8 IDs of the package,
+        // which has 4 core IDs.
+        let sharing = |eax: u32| ((eax & EAX_SHARING_MASK) >> EAX_SHARING_SHIFT) + 1;
+        let cores = |eax: u32| ((eax & EAX_CORES_MASK) >> EAX_CORES_SHIFT) + 1;
+        for (index, ids) in [(0, 2), (1, 2), (2, 8)] {
+            let (eax, ..) = leaf(&entries, CPUID_CACHE_PARAMS, index);
+            assert_eq!((sharing(eax), cores(eax)), (ids, 4));
+            let (eax, ..) = leaf(&entries, CPUID_AMD_CACHE_TOPOLOGY, index);
+            assert_eq!(sharing(eax), ids);
+        }
+
+        for function in [CPUID_EXT_TOPOLOGY, CPUID_V2_EXT_TOPOLOGY] {
+            assert_eq!(leaf(&entries, function, 0), (1, 2, 0x100, apic_id));
+            assert_eq!(leaf(&entries, function, 1), (3, 6, 0x201, apic_id));
+            assert_eq!(leaf(&entries, function, 2), (0, 0, 0x2, apic_id));
+        }
+
+        // 6 threads per package in an APIC ID field of 3 bits.
+        assert_eq!(leaf(&entries, CPUID_AMD_ADDRESS_SIZES, 0).2, 0x73005);
+        // Core 2 with 2 threads, in node 1.
+        assert_eq!(
+            leaf(&entries, CPUID_AMD_EXT_APIC_ID, 0),
+            (apic_id, 0x102, 1, 0)
+        );
+    }
+
+    #[test]
+    fn test_patch_topology_intel_keeps_amd_leaves() {
+        let topology: CpuTopology = "sockets=2,cores=3,threads=2".parse().unwrap();
+        let host = host_entries(VENDOR_INTEL_EBX);
+        let mut entries = host.clone();
+        patch_topology(&mut entries, 11, &topology);
+
+        for function in [
+            CPUID_AMD_ADDRESS_SIZES,
+            CPUID_AMD_CACHE_TOPOLOGY,
+            CPUID_AMD_EXT_APIC_ID,
+        ] {
+            for (patched, host) in entries.iter().zip(&host) {
+                if host.function == function {
+                    assert_eq!(
+                        (patched.eax, patched.ebx, patched.ecx, patched.edx),
+                        (host.eax, host.ebx, host.ecx, host.edx)
+                    );
+                }
+            }
+        }
+    }
+}
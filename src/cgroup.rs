@@ -0,0 +1,386 @@
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::os::fd::{AsRawFd, RawFd};
+use std::path::{Component, Path, PathBuf};
+
+use anyhow::{Context, Result};
+use log::{error, info};
+
+/// Default cpu.max period.
+const DEFAULT_CPU_PERIOD_US: u64 = 100_000;
+/// Valid range of cpu.weight and io.weight.
+const MIN_WEIGHT: u32 = 1;
+const MAX_WEIGHT: u32 = 10_000;
+
+/// Children of the VM cgroup the vCPU threads and the other threads are accounted to.
+const VCPU_CGROUP: &str = "vcpu";
+const VMM_CGROUP: &str = "vmm";
+
+/// CPU bandwidth limit, given as `QUOTA_US[/PERIOD_US]`: the VM runs at most `QUOTA_US` every
+/// `PERIOD_US`, 100ms by default.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CpuMax {
+    quota_us: u64,
+    period_us: u64,
+}
+
+impl std::str::FromStr for CpuMax {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (quota, period) = match s.split_once('/') {
+            Some((quota, period)) => (quota, Some(period)),
+            None => (s, None),
+        };
+        let quota_us = quota
+            .parse()
+            .with_context(|| format!("invalid cpu quota: {}", s))?;
+        let period_us = match period {
+            Some(period) => period
+                .parse()
+                .with_context(|| format!("invalid cpu period: {}", s))?,
+            None => DEFAULT_CPU_PERIOD_US,
+        };
+
+        if quota_us == 0 || period_us == 0 {
+            anyhow::bail!("invalid cpu max: {}, quota and period must not be zero", s)
+        }
+
+        Ok(CpuMax {
+            quota_us,
+            period_us,
+        })
+    }
+}
+
+/// A size in bytes, with an optional K, M or G suffix.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ByteSize(pub u64);
+
+impl std::str::FromStr for ByteSize {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (digits, shift) = match s.as_bytes().last() {
+            Some(b'K') => (&s[..s.len() - 1], 10),
+            Some(b'M') => (&s[..s.len() - 1], 20),
+            Some(b'G') => (&s[..s.len() - 1], 30),
+            _ => (s, 0),
+        };
+        let value: u64 = digits
+            .parse()
+            .with_context(|| format!("invalid size: {}", s))?;
+
+        value
+            .checked_mul(1 << shift)
+            .map(ByteSize)
+            .ok_or(anyhow::anyhow!("size too large: {}", s))
+    }
+}
+
+/// The cgroup v2 subtree of a VM and its limits. Limits left unset are inherited from the
+/// parent cgroups.
+#[derive(Debug, Default)]
+pub struct CgroupConfig {
+    /// Path of the VM cgroup, relative to the cgroup2 mount.
+    pub path: PathBuf,
+    pub cpu_max: Option<CpuMax>,
+    pub cpu_weight: Option<u32>,
+    pub memory_max: Option<ByteSize>,
+    pub io_weight: Option<u32>,
+}
+
+impl CgroupConfig {
+    /// Controllers the limits need enabled in the parent cgroups.
+    fn controllers(&self) -> Vec<&'static str> {
+        let mut controllers = Vec::new();
+        if self.cpu_max.is_some() || self.cpu_weight.is_some() {
+            controllers.push("cpu");
+        }
+        if self.memory_max.is_some() {
+            controllers.push("memory");
+        }
+        if self.io_weight.is_some() {
+            controllers.push("io");
+        }
+
+        controllers
+    }
+
+    /// The interface files written, with their values.
+    fn limits(&self) -> Result<Vec<(&'static str, String)>> {
+        let mut limits = Vec::new();
+        if let Some(cpu_max) = self.cpu_max {
+            limits.push((
+                "cpu.max",
+                format!("{} {}", cpu_max.quota_us, cpu_max.period_us),
+            ));
+        }
+        if let Some(weight) = self.cpu_weight {
+            limits.push(("cpu.weight", check_weight("cpu", weight)?.to_string()));
+        }
+        if let Some(memory_max) = self.memory_max {
+            limits.push(("memory.max", memory_max.0.to_string()));
+        }
+        if let Some(weight) = self.io_weight {
+            limits.push((
+                "io.weight",
+                format!("default {}", check_weight("io", weight)?),
+            ));
+        }
+
+        Ok(limits)
+    }
+}
+
+/// The cgroup of a VM. The process is moved into it when it is created, the calling thread
+/// into its `vmm` child and the vCPU threads into its `vcpu` child, both threaded so that the
+/// threads of one process can be split between them. Limits apply to the whole VM, the children
+/// account the CPU time of the guest and of the VMM separately.
+///
+/// Dropping it moves the process back to its original cgroup and removes the VM cgroup.
+#[derive(Debug)]
+pub struct Cgroup {
+    dir: PathBuf,
+    original: PathBuf,
+    vcpu_threads: File,
+    vmm_threads: File,
+    remove_on_drop: bool,
+}
+
+/// Moves threads into one child of the VM cgroup, usable after the VMM got jailed since the
+/// file is already open.
+#[derive(Debug)]
+pub struct ThreadCgroup(File);
+
+impl ThreadCgroup {
+    /// Moves the calling thread into the cgroup.
+    pub fn join(&self) -> Result<()> {
+        join(&self.0)
+    }
+}
+
+impl Cgroup {
+    pub fn create(config: &CgroupConfig) -> Result<Cgroup> {
+        let relative = config
+            .path
+            .components()
+            .all(|component| matches!(component, Component::Normal(_)));
+        if !relative || config.path.as_os_str().is_empty() {
+            anyhow::bail!(
+                "invalid cgroup path: {}, expected a path relative to the cgroup2 mount",
+                config.path.display()
+            )
+        }
+
+        let mount = cgroup2_mount()?;
+        let dir = mount.join(&config.path);
+        let original = mount.join(current_cgroup()?);
+        let limits = config.limits()?;
+
+        let controllers = config.controllers();
+        let mut parent = mount.clone();
+        for component in config.path.parent().into_iter().flat_map(Path::components) {
+            enable_controllers(&parent, &controllers)?;
+            parent.push(component);
+            if !parent.exists() {
+                std::fs::create_dir(&parent)
+                    .with_context(|| format!("failed to create cgroup {}", parent.display()))?;
+            }
+        }
+        enable_controllers(&parent, &controllers)?;
+
+        std::fs::create_dir(&dir)
+            .with_context(|| format!("failed to create cgroup {}", dir.display()))?;
+
+        let (vcpu_threads, vmm_threads) = match create_children(&dir) {
+            Ok(files) => files,
+            Err(e) => {
+                let _ = remove_dirs(&dir);
+                return Err(e);
+            }
+        };
+
+        // From here on errors drop the cgroup, which removes it.
+        let cgroup = Cgroup {
+            dir,
+            original,
+            vcpu_threads,
+            vmm_threads,
+            remove_on_drop: true,
+        };
+        for (file, value) in &limits {
+            write_file(&cgroup.dir.join(file), value)?;
+        }
+        write_file(
+            &cgroup.dir.join("cgroup.procs"),
+            &std::process::id().to_string(),
+        )?;
+        join(&cgroup.vmm_threads)?;
+
+        let limits: Vec<_> = limits
+            .iter()
+            .map(|(file, value)| format!("{} {}", file, value))
+            .collect();
+        info!(
+            "vm cgroup {}, limits: {}",
+            cgroup.dir.display(),
+            if limits.is_empty() {
+                String::from("none")
+            } else {
+                limits.join(", ")
+            }
+        );
+
+        Ok(cgroup)
+    }
+
+    /// Where the vCPU threads join.
+    pub fn vcpu_threads(&self) -> Result<ThreadCgroup> {
+        Ok(ThreadCgroup(
+            self.vcpu_threads
+                .try_clone()
+                .context("failed to clone cgroup file")?,
+        ))
+    }
+
+    /// Descriptors that must stay open for threads to join their cgroup.
+    pub fn fds(&self) -> Vec<RawFd> {
+        vec![self.vcpu_threads.as_raw_fd(), self.vmm_threads.as_raw_fd()]
+    }
+
+    /// Leaves the removal to another process, the jail launcher which still sees the cgroup
+    /// filesystem.
+    pub fn disown(&mut self) {
+        self.remove_on_drop = false;
+    }
+}
+
+impl Drop for Cgroup {
+    fn drop(&mut self) {
+        if !self.remove_on_drop {
+            return;
+        }
+
+        // Moving the process takes all of its threads out, running or not.
+        let res = write_file(
+            &self.original.join("cgroup.procs"),
+            &std::process::id().to_string(),
+        )
+        .and_then(|_| remove_dirs(&self.dir));
+        if let Err(e) = res {
+            error!("failed to remove cgroup {}: {:?}", self.dir.display(), e);
+        }
+    }
+}
+
+fn check_weight(controller: &str, weight: u32) -> Result<u32> {
+    if !(MIN_WEIGHT..=MAX_WEIGHT).contains(&weight) {
+        anyhow::bail!(
+            "invalid {} weight: {}, expected {} to {}",
+            controller,
+            weight,
+            MIN_WEIGHT,
+            MAX_WEIGHT
+        )
+    }
+
+    Ok(weight)
+}
+
+/// Finds where the cgroup2 hierarchy is mounted, `/sys/fs/cgroup` or `/sys/fs/cgroup/unified`
+/// on hybrid hosts.
+fn cgroup2_mount() -> Result<PathBuf> {
+    let mountinfo =
+        std::fs::read_to_string("/proc/self/mountinfo").context("failed to read mountinfo")?;
+
+    // The mount point is the fifth field, the filesystem type follows the " - " separator.
+    mountinfo
+        .lines()
+        .find_map(|line| {
+            let (fields, fs) = line.split_once(" - ")?;
+            if fs.split(' ').next()? != "cgroup2" {
+                return None;
+            }
+            fields.split(' ').nth(4).map(PathBuf::from)
+        })
+        .ok_or(anyhow::anyhow!("cgroup2 is not mounted"))
+}
+
+/// The cgroup2 path of the process, relative to the mount.
+fn current_cgroup() -> Result<PathBuf> {
+    let cgroups =
+        std::fs::read_to_string("/proc/self/cgroup").context("failed to read process cgroup")?;
+
+    cgroups
+        .lines()
+        .find_map(|line| line.strip_prefix("0::"))
+        .map(|path| PathBuf::from(path.trim_start_matches('/')))
+        .ok_or(anyhow::anyhow!("the process is not in a cgroup2 hierarchy"))
+}
+
+fn enable_controllers(dir: &Path, controllers: &[&str]) -> Result<()> {
+    if controllers.is_empty() {
+        return Ok(());
+    }
+
+    let path = dir.join("cgroup.controllers");
+    let available = std::fs::read_to_string(&path)
+        .with_context(|| format!("failed to read {}", path.display()))?;
+    for controller in controllers {
+        if !available.split_whitespace().any(|c| c == *controller) {
+            anyhow::bail!(
+                "the {} controller is not available in cgroup {}",
+                controller,
+                dir.display()
+            )
+        }
+    }
+
+    let value: Vec<_> = controllers.iter().map(|c| format!("+{}", c)).collect();
+    write_file(&dir.join("cgroup.subtree_control"), &value.join(" "))
+}
+
+/// Moves the calling thread into the cgroup of `threads`, its cgroup.threads file.
+fn join(threads: &File) -> Result<()> {
+    // Writing 0 moves the writer.
+    (&*threads)
+        .write_all(b"0")
+        .context("failed to move thread to its cgroup")
+}
+
+/// Creates the threaded children, and opens the files their threads are moved with.
+fn create_children(dir: &Path) -> Result<(File, File)> {
+    let create = |child: &str| {
+        let child = dir.join(child);
+        std::fs::create_dir(&child)
+            .with_context(|| format!("failed to create cgroup {}", child.display()))?;
+        write_file(&child.join("cgroup.type"), "threaded")?;
+
+        let path = child.join("cgroup.threads");
+        OpenOptions::new()
+            .write(true)
+            .open(&path)
+            .with_context(|| format!("failed to open {}", path.display()))
+    };
+
+    Ok((create(VCPU_CGROUP)?, create(VMM_CGROUP)?))
+}
+
+fn write_file(path: &Path, value: &str) -> Result<()> {
+    std::fs::write(path, value)
+        .with_context(|| format!("failed to write {} to {}", value, path.display()))
+}
+
+/// Removes the VM cgroup and its children, which must have no threads left.
+fn remove_dirs(dir: &Path) -> Result<()> {
+    for child in [VCPU_CGROUP, VMM_CGROUP] {
+        let child = dir.join(child);
+        if child.exists() {
+            std::fs::remove_dir(&child)
+                .with_context(|| format!("failed to remove cgroup {}", child.display()))?;
+        }
+    }
+
+    std::fs::remove_dir(dir).with_context(|| format!("failed to remove cgroup {}", dir.display()))
+}
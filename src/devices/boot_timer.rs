@@ -0,0 +1,85 @@
+use std::sync::{Arc, OnceLock};
+use std::time::{Duration, Instant};
+
+/// The value guests write once booted, Firecracker's `MAGIC_VALUE_SIGNAL_GUEST_BOOT_COMPLETE`.
+pub const BOOT_COMPLETE_MAGIC: u8 = 123;
+
+/// How long the guest took to boot.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BootDuration {
+    /// Since kvm-box started.
+    pub since_start: Duration,
+    /// Since the first KVM_RUN of any vCPU.
+    pub since_first_run: Duration,
+}
+
+/// Milestones of the boot, shared by the boot timer device, the vCPU threads and the metrics.
+#[derive(Debug)]
+pub struct BootTimes {
+    start: Instant,
+    first_run: OnceLock<Instant>,
+    boot_complete: OnceLock<BootDuration>,
+}
+
+impl BootTimes {
+    /// `start` is when the process started.
+    pub fn new(start: Instant) -> Self {
+        BootTimes {
+            start,
+            first_run: OnceLock::new(),
+            boot_complete: OnceLock::new(),
+        }
+    }
+
+    /// Time elapsed since the process started.
+    pub fn since_start(&self) -> Duration {
+        self.start.elapsed()
+    }
+
+    /// Called before every KVM_RUN, only the first call counts.
+    pub fn record_first_run(&self) {
+        self.first_run.get_or_init(Instant::now);
+    }
+
+    /// The time the first boot completion signal took, if the guest sent one.
+    pub fn boot_duration(&self) -> Option<BootDuration> {
+        self.boot_complete.get().copied()
+    }
+}
+
+/// Timestamps the boot when the guest init writes `BOOT_COMPLETE_MAGIC` to the port. Later
+/// writes are logged as further milestones, the metrics keep the first one.
+#[derive(Debug)]
+pub struct BootTimerDevice {
+    times: Arc<BootTimes>,
+}
+
+impl BootTimerDevice {
+    pub fn new(times: Arc<BootTimes>) -> Self {
+        BootTimerDevice { times }
+    }
+
+    pub fn bus_read(&self, _offset: u64, _data: &mut [u8]) {}
+
+    pub fn bus_write(&self, offset: u64, data: &[u8]) {
+        if let (0, [BOOT_COMPLETE_MAGIC]) = (offset, data) {
+            let now = Instant::now();
+            let first_run = *self.times.first_run.get_or_init(|| now);
+            let boot = BootDuration {
+                since_start: now - self.times.start,
+                since_first_run: now - first_run,
+            };
+
+            let milestone = match self.times.boot_complete.set(boot) {
+                Ok(()) => "guest boot completed",
+                Err(_) => "guest boot milestone",
+            };
+            log::info!(
+                "{}: {:?} since kvm-box start, {:?} since the first KVM_RUN",
+                milestone,
+                boot.since_start,
+                boot.since_first_run
+            );
+        }
+    }
+}
@@ -1,10 +1,15 @@
 use std::cmp::{Ord, Ordering, PartialEq, PartialOrd};
 use std::collections::btree_map::BTreeMap;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::{self, AtomicBool};
 use std::sync::{Arc, Mutex};
 
 use anyhow::Result;
+use log::error;
 
-use crate::devices::SerialDevice;
+use crate::devices::{
+    BootTimerDevice, IdentityDevice, IoApic, PostCodeDevice, PvPanicDevice, SerialDevice,
+};
 
 #[derive(Debug, Copy, Clone)]
 struct BusRange(u64, u64);
@@ -29,45 +34,90 @@ impl PartialOrd for BusRange {
     }
 }
 
+/// A device on a bus. Accesses take `&self` and run concurrently on all vCPU threads, each
+/// device synchronizes its own state.
 #[derive(Debug)]
 pub enum BusDevice {
-    Serial(SerialDevice<std::io::Stdin>),
+    Serial(Mutex<SerialDevice>),
+    PvPanic(PvPanicDevice),
+    BootTimer(BootTimerDevice),
+    PostCode(PostCodeDevice),
+    Identity(IdentityDevice),
+    IoApic(IoApic),
 }
 
 impl BusDevice {
-    #[allow(dead_code)]
-    pub fn serial_ref(&self) -> Option<&SerialDevice<std::io::Stdin>> {
+    pub fn serial(&self) -> Option<&Mutex<SerialDevice>> {
         match self {
             Self::Serial(x) => Some(x),
+            _ => None,
         }
     }
 
-    pub fn serial_mut(&mut self) -> Option<&mut SerialDevice<std::io::Stdin>> {
+    pub fn pvpanic(&self) -> Option<&PvPanicDevice> {
         match self {
-            Self::Serial(x) => Some(x),
+            Self::PvPanic(x) => Some(x),
+            _ => None,
+        }
+    }
+
+    pub fn identity(&self) -> Option<&IdentityDevice> {
+        match self {
+            Self::Identity(x) => Some(x),
+            _ => None,
+        }
+    }
+
+    pub fn ioapic(&self) -> Option<&IoApic> {
+        match self {
+            Self::IoApic(x) => Some(x),
+            _ => None,
         }
     }
 
-    pub fn read(&mut self, offset: u64, data: &mut [u8]) {
+    /// Panics if the lock of the device is poisoned, the bus isolates it then.
+    pub fn read(&self, offset: u64, data: &mut [u8]) {
         match self {
-            Self::Serial(x) => x.bus_read(offset, data),
+            Self::Serial(x) => x.lock().expect("Poisoned lock").bus_read(offset, data),
+            Self::PvPanic(x) => x.bus_read(offset, data),
+            Self::BootTimer(x) => x.bus_read(offset, data),
+            Self::PostCode(x) => x.bus_read(offset, data),
+            Self::Identity(x) => x.bus_read(offset, data),
+            Self::IoApic(x) => x.bus_read(offset, data),
         }
     }
 
-    pub fn write(&mut self, offset: u64, data: &[u8]) {
+    /// Panics if the lock of the device is poisoned, the bus isolates it then.
+    pub fn write(&self, offset: u64, data: &[u8]) {
         match self {
-            Self::Serial(x) => x.bus_write(offset, data),
+            Self::Serial(x) => x.lock().expect("Poisoned lock").bus_write(offset, data),
+            Self::PvPanic(x) => x.bus_write(offset, data),
+            Self::BootTimer(x) => x.bus_write(offset, data),
+            Self::PostCode(x) => x.bus_write(offset, data),
+            Self::Identity(x) => x.bus_write(offset, data),
+            Self::IoApic(x) => x.bus_write(offset, data),
         }
     }
 }
 
+#[derive(Debug)]
+struct BusEntry {
+    device: Arc<BusDevice>,
+    /// Set once an access panicked, the range is no longer handled.
+    isolated: AtomicBool,
+}
+
 /// A device container for routing reads and writes over some address space.
 ///
 /// This doesn't have any restrictions on what kind of device or address space this applies to. The
 /// only restriction is that no two devices can overlap in this address space.
-#[derive(Debug, Clone, Default)]
+///
+/// Lookups take a logarithmic time in the number of devices. Once set up, the bus is only read and
+/// the vCPU threads share it behind an `Arc`, dispatching without any lock of the bus. A device
+/// that panics, a poisoned one included, is isolated: its range is left unhandled from then on.
+#[derive(Debug, Default)]
 pub struct Bus {
-    devices: BTreeMap<BusRange, Arc<Mutex<BusDevice>>>,
+    devices: BTreeMap<BusRange, BusEntry>,
 }
 
 impl Bus {
@@ -78,29 +128,30 @@ impl Bus {
         }
     }
 
-    fn first_before(&self, addr: u64) -> Option<(BusRange, &Mutex<BusDevice>)> {
-        // for when we switch to rustc 1.17: self.devices.range(..addr).iter().rev().next()
-        for (range, dev) in self.devices.iter().rev() {
-            if range.0 <= addr {
-                return Some((*range, dev));
-            }
-        }
-        None
+    fn first_before(&self, addr: u64) -> Option<(BusRange, &BusEntry)> {
+        // Ranges compare by their base only, so the length of the bound does not matter.
+        let (range, entry) = self.devices.range(..=BusRange(addr, 0)).next_back()?;
+        Some((*range, entry))
     }
 
-    /// Returns the device found at some address.
-    pub fn get_device(&self, addr: u64) -> Option<(u64, &Mutex<BusDevice>)> {
-        if let Some((BusRange(start, len), dev)) = self.first_before(addr) {
+    fn get_entry(&self, addr: u64) -> Option<(u64, &BusEntry)> {
+        if let Some((BusRange(start, len), entry)) = self.first_before(addr) {
             let offset = addr - start;
             if offset < len {
-                return Some((offset, dev));
+                return Some((offset, entry));
             }
         }
         None
     }
 
+    /// Returns the device found at some address.
+    pub fn get_device(&self, addr: u64) -> Option<(u64, &Arc<BusDevice>)> {
+        self.get_entry(addr)
+            .map(|(offset, entry)| (offset, &entry.device))
+    }
+
     /// Puts the given device at the given address space.
-    pub fn insert(&mut self, device: Arc<Mutex<BusDevice>>, base: u64, len: u64) -> Result<()> {
+    pub fn insert(&mut self, device: Arc<BusDevice>, base: u64, len: u64) -> Result<()> {
         if len == 0 {
             anyhow::bail!("Cannot insert a device with zero length")
         }
@@ -122,7 +173,11 @@ impl Bus {
             }
         }
 
-        if self.devices.insert(BusRange(base, len), device).is_some() {
+        let entry = BusEntry {
+            device,
+            isolated: AtomicBool::new(false),
+        };
+        if self.devices.insert(BusRange(base, len), entry).is_some() {
             anyhow::bail!("Device already exists at this address")
         }
 
@@ -133,29 +188,56 @@ impl Bus {
     ///
     /// Returns true on success, otherwise `data` is untouched.
     pub fn read(&self, addr: u64, data: &mut [u8]) -> bool {
-        if let Some((offset, dev)) = self.get_device(addr) {
-            // OK to unwrap as lock() failing is a serious error condition and should panic.
-            dev.lock()
-                .expect("Failed to acquire device lock")
-                .read(offset, data);
-            true
-        } else {
-            false
-        }
+        self.dispatch(addr, |device, offset| device.read(offset, data))
     }
 
     /// Writes `data` to the device that owns the range containing `addr`.
     ///
-    /// Returns true on success, otherwise `data` is untouched.
+    /// Returns false when no device handles `addr` or when that device is isolated.
     pub fn write(&self, addr: u64, data: &[u8]) -> bool {
-        if let Some((offset, dev)) = self.get_device(addr) {
-            // OK to unwrap as lock() failing is a serious error condition and should panic.
-            dev.lock()
-                .expect("Failed to acquire device lock")
-                .write(offset, data);
-            true
-        } else {
-            false
+        self.dispatch(addr, |device, offset| device.write(offset, data))
+    }
+
+    /// Handles a port read exit of a vCPU. Ports no device drives read as all ones.
+    ///
+    /// Returns false for those ports.
+    pub fn io_in(&self, port: u16, data: &mut [u8]) -> bool {
+        if self.read(port.into(), data) {
+            return true;
         }
+        data.fill(0xff);
+        false
+    }
+
+    /// Handles a port write exit of a vCPU.
+    ///
+    /// Returns false when the write went nowhere.
+    pub fn io_out(&self, port: u16, data: &[u8]) -> bool {
+        self.write(port.into(), data)
+    }
+
+    /// Runs `access` on the device at `addr`, unless it was isolated. A panic isolates the device
+    /// instead of unwinding through the vCPU thread.
+    fn dispatch(&self, addr: u64, access: impl FnOnce(&BusDevice, u64)) -> bool {
+        let Some((offset, entry)) = self.get_entry(addr) else {
+            return false;
+        };
+        if entry.isolated.load(atomic::Ordering::Acquire) {
+            return false;
+        }
+
+        // Devices left inconsistent by the panic are never accessed again.
+        let res = panic::catch_unwind(AssertUnwindSafe(|| access(&entry.device, offset)));
+        if res.is_err() {
+            if !entry.isolated.swap(true, atomic::Ordering::AcqRel) {
+                error!(
+                    "device at {:#x} failed, its accesses are no longer handled",
+                    addr - offset
+                );
+            }
+            return false;
+        }
+
+        true
     }
 }
@@ -0,0 +1,131 @@
+use std::sync::Arc;
+
+use anyhow::{Context, Result};
+use kvm_ioctls::{IoEventAddress, VmFd};
+use vmm_sys_util::eventfd::{EventFd, EFD_NONBLOCK};
+
+use super::Bus;
+
+/// The value written to ring a doorbell, its size is the size of the access.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DataMatch {
+    U8(u8),
+    U16(u16),
+    U32(u32),
+    U64(u64),
+}
+
+impl From<u8> for DataMatch {
+    fn from(v: u8) -> Self {
+        DataMatch::U8(v)
+    }
+}
+
+impl From<u16> for DataMatch {
+    fn from(v: u16) -> Self {
+        DataMatch::U16(v)
+    }
+}
+
+impl From<u32> for DataMatch {
+    fn from(v: u32) -> Self {
+        DataMatch::U32(v)
+    }
+}
+
+impl From<u64> for DataMatch {
+    fn from(v: u64) -> Self {
+        DataMatch::U64(v)
+    }
+}
+
+impl DataMatch {
+    fn to_le_bytes(self) -> Vec<u8> {
+        match self {
+            DataMatch::U8(v) => v.to_le_bytes().to_vec(),
+            DataMatch::U16(v) => v.to_le_bytes().to_vec(),
+            DataMatch::U32(v) => v.to_le_bytes().to_vec(),
+            DataMatch::U64(v) => v.to_le_bytes().to_vec(),
+        }
+    }
+}
+
+/// A device register KVM signals an eventfd for instead of exiting to the vCPU thread. Only
+/// writes of the datamatch value ring it, other accesses exit and go through the bus as before.
+#[derive(Debug)]
+struct Doorbell {
+    addr: IoEventAddress,
+    datamatch: DataMatch,
+    bus: Arc<Bus>,
+    evt: EventFd,
+}
+
+impl Doorbell {
+    fn bus_addr(&self) -> u64 {
+        match self.addr {
+            IoEventAddress::Pio(addr) | IoEventAddress::Mmio(addr) => addr,
+        }
+    }
+}
+
+/// The doorbells of all devices, serviced from the VMM event loop: the write the guest did is
+/// replayed on the bus, so devices handle it like any other write and the vCPU does not wait
+/// for it.
+#[derive(Debug, Default)]
+pub struct Doorbells(Vec<Doorbell>);
+
+impl Doorbells {
+    /// Makes writes of `datamatch` to `addr` ring a doorbell, whose writes are replayed on `bus`,
+    /// the bus `addr` belongs to. The type of `datamatch` gives the size of the write.
+    pub fn register(
+        &mut self,
+        vm_fd: &VmFd,
+        bus: &Arc<Bus>,
+        addr: IoEventAddress,
+        datamatch: impl Into<DataMatch>,
+    ) -> Result<()> {
+        let datamatch = datamatch.into();
+        let evt = EventFd::new(EFD_NONBLOCK).context("failed to create doorbell eventfd")?;
+        match datamatch {
+            DataMatch::U8(v) => vm_fd.register_ioevent(&evt, &addr, v),
+            DataMatch::U16(v) => vm_fd.register_ioevent(&evt, &addr, v),
+            DataMatch::U32(v) => vm_fd.register_ioevent(&evt, &addr, v),
+            DataMatch::U64(v) => vm_fd.register_ioevent(&evt, &addr, v),
+        }
+        .with_context(|| format!("failed to register doorbell at {:?}", addr))?;
+
+        self.0.push(Doorbell {
+            addr,
+            datamatch,
+            bus: bus.clone(),
+            evt,
+        });
+
+        Ok(())
+    }
+
+    /// The eventfds to poll, in the order `service` takes them.
+    pub fn evts(&self) -> impl Iterator<Item = &EventFd> {
+        self.0.iter().map(|doorbell| &doorbell.evt)
+    }
+
+    /// Services the doorbell `index` rang. Rings since the last service are coalesced into
+    /// one write.
+    pub fn service(&self, index: usize) -> Result<()> {
+        let doorbell = self
+            .0
+            .get(index)
+            .with_context(|| format!("no doorbell {}", index))?;
+        doorbell
+            .evt
+            .read()
+            .context("failed to read doorbell eventfd")?;
+
+        let addr = doorbell.bus_addr();
+        if !doorbell.bus.write(addr, &doorbell.datamatch.to_le_bytes()) {
+            log::warn!("doorbell at {:#x} rang without a device to handle it", addr);
+        }
+
+        Ok(())
+    }
+}
@@ -26,6 +26,12 @@ impl Deref for EventFdTrigger {
     }
 }
 
+impl Default for EventFdTrigger {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl EventFdTrigger {
     /// Create an `EventFdTrigger`.
     pub fn new() -> Self {
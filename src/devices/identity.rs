@@ -0,0 +1,49 @@
+use std::sync::Mutex;
+
+/// Offset of the 6 bytes of the MAC address.
+const MAC_OFFSET: usize = 0x0;
+/// Offset of the little endian u32 vsock CID.
+const VSOCK_CID_OFFSET: usize = 0x8;
+/// Number of ports of the device.
+pub const IDENTITY_SIZE: u64 = 0x10;
+
+/// Exposes the identity of the VM to the guest: the MAC address is read from ports 0x0-0x5 and the
+/// vsock CID from ports 0x8-0xb, reads of any size are allowed. Clones get a new identity while
+/// their memory still holds the one of the snapshot, so a guest agent reads it again after a
+/// restore instead of trusting what it cached, see guest/kvm-box-identity. Writes are ignored.
+#[derive(Debug)]
+pub struct IdentityDevice {
+    registers: Mutex<[u8; IDENTITY_SIZE as usize]>,
+}
+
+impl IdentityDevice {
+    pub fn new(mac: [u8; 6], vsock_cid: u32) -> Self {
+        let device = IdentityDevice {
+            registers: Mutex::new([0u8; IDENTITY_SIZE as usize]),
+        };
+        device.set_identity(mac, vsock_cid);
+
+        device
+    }
+
+    /// Replaces the identity the guest reads from now on.
+    pub fn set_identity(&self, mac: [u8; 6], vsock_cid: u32) {
+        let mut registers = self.registers.lock().expect("Poisoned lock");
+        registers[MAC_OFFSET..MAC_OFFSET + 6].copy_from_slice(&mac);
+        registers[VSOCK_CID_OFFSET..VSOCK_CID_OFFSET + 4].copy_from_slice(&vsock_cid.to_le_bytes());
+    }
+
+    pub fn bus_read(&self, offset: u64, data: &mut [u8]) {
+        let registers = *self.registers.lock().expect("Poisoned lock");
+        let Some(bytes) = usize::try_from(offset)
+            .ok()
+            .and_then(|start| registers.get(start..start.checked_add(data.len())?))
+        else {
+            return;
+        };
+
+        data.copy_from_slice(bytes);
+    }
+
+    pub fn bus_write(&self, _offset: u64, _data: &[u8]) {}
+}
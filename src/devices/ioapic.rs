@@ -0,0 +1,448 @@
+use std::sync::{Arc, Mutex};
+
+use anyhow::Result;
+
+/// Pins of the IOAPIC, raised through GSIs 0 to 23.
+pub const IOAPIC_NUM_PINS: u32 = 24;
+/// Size of the MMIO window.
+pub const IOAPIC_MMIO_SIZE: u64 = 0x1000;
+/// Offset of the EOI register, which the VMM also writes on the IOAPIC EOI exits of KVM.
+pub const IOAPIC_EOI: u64 = 0x40;
+
+const IOREGSEL: u64 = 0x00;
+const IOWIN: u64 = 0x10;
+
+const IOAPICID: u32 = 0x00;
+const IOAPICVER: u32 = 0x01;
+const IOAPICARB: u32 = 0x02;
+const IOREDTBL: u32 = 0x10;
+
+/// The version of the in-kernel IOAPIC, with the index of the last redirection entry.
+const IOAPIC_VERSION: u32 = 0x11 | ((IOAPIC_NUM_PINS - 1) << 16);
+const IOAPIC_ID_MASK: u32 = 0x0f00_0000;
+
+// Redirection entry fields.
+const VECTOR_MASK: u64 = 0xff;
+const DELIVERY_MODE_SHIFT: u32 = 8;
+const DEST_MODE_SHIFT: u32 = 11;
+const DELIVERY_STATUS: u64 = 1 << 12;
+const REMOTE_IRR: u64 = 1 << 14;
+const TRIGGER_MODE_SHIFT: u32 = 15;
+const LEVEL_TRIGGERED: u64 = 1 << TRIGGER_MODE_SHIFT;
+const MASKED: u64 = 1 << 16;
+const DEST_SHIFT: u32 = 56;
+const READ_ONLY: u64 = DELIVERY_STATUS | REMOTE_IRR;
+
+const MSI_ADDRESS_BASE: u64 = 0xfee0_0000;
+
+/// An MSI, the address and data a device writes to raise it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MsiMessage {
+    pub address: u64,
+    pub data: u32,
+}
+
+/// Routes of GSIs to MSIs, the GSI routing of the VM.
+pub trait MsiRouting: Send + Sync + std::fmt::Debug {
+    /// Makes `gsi` raise `msi`.
+    fn set_msi_route(&self, gsi: u32, msi: MsiMessage) -> Result<()>;
+    /// Leaves `gsi` without a route, raising it does nothing.
+    fn remove_route(&self, gsi: u32) -> Result<()>;
+    /// Sends what `gsi` is routed to once.
+    fn raise(&self, gsi: u32) -> Result<()>;
+}
+
+#[derive(Debug)]
+struct IoApicRegs {
+    ioregsel: u32,
+    id: u32,
+    redirtbl: [u64; IOAPIC_NUM_PINS as usize],
+    /// A bit per pin whose line is asserted.
+    lines: u32,
+}
+
+/// The IOAPIC of the split irqchip. Devices drive the lines of its pins through `set_irq`, an
+/// interrupt is delivered by raising the GSI of the pin, which KVM sends as the MSI the
+/// redirection entry of the pin translates to. Masked pins have no route.
+///
+/// Edge triggered pins deliver on the rising edge of their line. Level triggered ones deliver
+/// while their line is asserted and Remote IRR is clear, Remote IRR stays set until the guest
+/// EOIs the vector.
+#[derive(Debug)]
+pub struct IoApic {
+    regs: Mutex<IoApicRegs>,
+    routing: Arc<dyn MsiRouting>,
+}
+
+impl IoApic {
+    /// All pins start masked, as after a reset.
+    pub fn new(routing: Arc<dyn MsiRouting>) -> Self {
+        IoApic {
+            regs: Mutex::new(IoApicRegs {
+                ioregsel: 0,
+                id: 0,
+                redirtbl: [MASKED; IOAPIC_NUM_PINS as usize],
+                lines: 0,
+            }),
+            routing,
+        }
+    }
+
+    pub fn bus_read(&self, offset: u64, data: &mut [u8]) {
+        let Ok(data) = <&mut [u8; 4]>::try_from(data) else {
+            return;
+        };
+
+        let regs = self.regs.lock().expect("Poisoned lock");
+        let value = match offset {
+            IOREGSEL => regs.ioregsel,
+            IOWIN => regs.read(regs.ioregsel),
+            _ => 0,
+        };
+        *data = value.to_le_bytes();
+    }
+
+    pub fn bus_write(&self, offset: u64, data: &[u8]) {
+        let Ok(data) = <[u8; 4]>::try_from(data) else {
+            return;
+        };
+        let value = u32::from_le_bytes(data);
+
+        let mut regs = self.regs.lock().expect("Poisoned lock");
+        match offset {
+            IOREGSEL => regs.ioregsel = value & 0xff,
+            IOWIN => self.write_register(&mut regs, value),
+            IOAPIC_EOI => self.end_of_interrupt(&mut regs, value as u8),
+            _ => {}
+        }
+    }
+
+    /// Asserts or deasserts the line of `pin`.
+    pub fn set_irq(&self, pin: u32, level: bool) {
+        if pin >= IOAPIC_NUM_PINS {
+            return;
+        }
+
+        let mut regs = self.regs.lock().expect("Poisoned lock");
+        let mask = 1 << pin;
+        let rising = level && regs.lines & mask == 0;
+        if level {
+            regs.lines |= mask;
+        } else {
+            regs.lines &= !mask;
+        }
+
+        let entry = regs.redirtbl[pin as usize];
+        if entry & LEVEL_TRIGGERED == 0 {
+            if rising {
+                self.deliver(&mut regs, pin);
+            }
+        } else if level {
+            self.deliver(&mut regs, pin);
+        }
+    }
+
+    /// Sends the interrupt of `pin` unless it is masked or, level triggered, not EOIed yet.
+    fn deliver(&self, regs: &mut IoApicRegs, pin: u32) {
+        let entry = &mut regs.redirtbl[pin as usize];
+        if *entry & MASKED != 0 {
+            return;
+        }
+        if *entry & LEVEL_TRIGGERED != 0 {
+            if *entry & REMOTE_IRR != 0 {
+                return;
+            }
+            *entry |= REMOTE_IRR;
+        }
+
+        if let Err(e) = self.routing.raise(pin) {
+            log::error!("failed to raise ioapic pin {}: {:?}", pin, e);
+        }
+    }
+
+    /// Clears Remote IRR of the level triggered pins of `vector`, those whose line is still
+    /// asserted deliver again.
+    fn end_of_interrupt(&self, regs: &mut IoApicRegs, vector: u8) {
+        for pin in 0..IOAPIC_NUM_PINS {
+            let entry = &mut regs.redirtbl[pin as usize];
+            if *entry & VECTOR_MASK != u64::from(vector) || *entry & REMOTE_IRR == 0 {
+                continue;
+            }
+
+            *entry &= !REMOTE_IRR;
+            if regs.lines & (1 << pin) != 0 {
+                self.deliver(regs, pin);
+            }
+        }
+    }
+
+    fn write_register(&self, regs: &mut IoApicRegs, value: u32) {
+        let reg = regs.ioregsel;
+        if reg == IOAPICID {
+            regs.id = value & IOAPIC_ID_MASK;
+            return;
+        }
+        let Some((pin, high)) = redirection_entry(reg) else {
+            return;
+        };
+
+        let entry = &mut regs.redirtbl[pin as usize];
+        let (shift, mask) = if high {
+            (32, 0xffff_ffff_0000_0000)
+        } else {
+            (0, 0x0000_0000_ffff_ffff)
+        };
+        let written = (*entry & !mask) | (u64::from(value) << shift);
+        *entry = (written & !READ_ONLY) | (*entry & READ_ONLY);
+        // Remote IRR only exists for level triggered interrupts.
+        if *entry & LEVEL_TRIGGERED == 0 {
+            *entry &= !REMOTE_IRR;
+        }
+        let entry = *entry;
+
+        let res = if entry & MASKED != 0 {
+            self.routing.remove_route(pin)
+        } else {
+            self.routing.set_msi_route(pin, msi_message(entry))
+        };
+        if let Err(e) = res {
+            log::error!("failed to route ioapic pin {}: {:?}", pin, e);
+        }
+
+        // An unmasked level triggered pin delivers its asserted line.
+        if entry & LEVEL_TRIGGERED != 0 && regs.lines & (1 << pin) != 0 {
+            self.deliver(regs, pin);
+        }
+    }
+}
+
+impl IoApicRegs {
+    fn read(&self, reg: u32) -> u32 {
+        match reg {
+            IOAPICID | IOAPICARB => self.id,
+            IOAPICVER => IOAPIC_VERSION,
+            _ => match redirection_entry(reg) {
+                Some((pin, high)) => {
+                    let entry = self.redirtbl[pin as usize];
+                    if high {
+                        (entry >> 32) as u32
+                    } else {
+                        entry as u32
+                    }
+                }
+                None => 0,
+            },
+        }
+    }
+}
+
+/// The pin of a redirection table register, and whether it is the high half of the entry.
+fn redirection_entry(reg: u32) -> Option<(u32, bool)> {
+    let index = reg.checked_sub(IOREDTBL)?;
+    let pin = index / 2;
+    (pin < IOAPIC_NUM_PINS).then_some((pin, index % 2 == 1))
+}
+
+/// The MSI a redirection entry sends, see the MSI format in the Intel SDM.
+fn msi_message(entry: u64) -> MsiMessage {
+    let dest = (entry >> DEST_SHIFT) & 0xff;
+    let dest_mode = (entry >> DEST_MODE_SHIFT) & 1;
+    let delivery_mode = (entry >> DELIVERY_MODE_SHIFT) & 0x7;
+    let trigger_mode = (entry >> TRIGGER_MODE_SHIFT) & 1;
+
+    MsiMessage {
+        address: MSI_ADDRESS_BASE | (dest << 12) | (dest_mode << 2),
+        data: ((entry & VECTOR_MASK)
+            | (delivery_mode << DELIVERY_MODE_SHIFT)
+            | (trigger_mode << TRIGGER_MODE_SHIFT)) as u32,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use super::*;
+
+    /// Records the routes of the pins and the GSIs raised.
+    #[derive(Debug, Default)]
+    struct FakeRouting {
+        routes: Mutex<BTreeMap<u32, MsiMessage>>,
+        raised: Mutex<Vec<u32>>,
+    }
+
+    impl MsiRouting for FakeRouting {
+        fn set_msi_route(&self, gsi: u32, msi: MsiMessage) -> Result<()> {
+            self.routes.lock().unwrap().insert(gsi, msi);
+            Ok(())
+        }
+
+        fn remove_route(&self, gsi: u32) -> Result<()> {
+            self.routes.lock().unwrap().remove(&gsi);
+            Ok(())
+        }
+
+        fn raise(&self, gsi: u32) -> Result<()> {
+            assert!(self.routes.lock().unwrap().contains_key(&gsi));
+            self.raised.lock().unwrap().push(gsi);
+            Ok(())
+        }
+    }
+
+    impl FakeRouting {
+        fn take_raised(&self) -> Vec<u32> {
+            std::mem::take(&mut self.raised.lock().unwrap())
+        }
+    }
+
+    fn ioapic() -> (IoApic, Arc<FakeRouting>) {
+        let routing = Arc::new(FakeRouting::default());
+        (IoApic::new(routing.clone()), routing)
+    }
+
+    fn write_reg(ioapic: &IoApic, reg: u32, value: u32) {
+        ioapic.bus_write(IOREGSEL, &reg.to_le_bytes());
+        ioapic.bus_write(IOWIN, &value.to_le_bytes());
+    }
+
+    fn read_reg(ioapic: &IoApic, reg: u32) -> u32 {
+        let mut data = [0u8; 4];
+        ioapic.bus_write(IOREGSEL, &reg.to_le_bytes());
+        ioapic.bus_read(IOWIN, &mut data);
+        u32::from_le_bytes(data)
+    }
+
+    fn write_entry(ioapic: &IoApic, pin: u32, entry: u64) {
+        write_reg(ioapic, IOREDTBL + 2 * pin + 1, (entry >> 32) as u32);
+        write_reg(ioapic, IOREDTBL + 2 * pin, entry as u32);
+    }
+
+    fn read_entry(ioapic: &IoApic, pin: u32) -> u64 {
+        let low = read_reg(ioapic, IOREDTBL + 2 * pin);
+        let high = read_reg(ioapic, IOREDTBL + 2 * pin + 1);
+        (u64::from(high) << 32) | u64::from(low)
+    }
+
+    fn eoi(ioapic: &IoApic, vector: u8) {
+        ioapic.bus_write(IOAPIC_EOI, &u32::from(vector).to_le_bytes());
+    }
+
+    #[test]
+    fn test_msi_message() {
+        // Fixed delivery to the physical APIC ID 3, edge triggered.
+        let entry = 0x31 | (3 << DEST_SHIFT);
+        assert_eq!(
+            msi_message(entry),
+            MsiMessage {
+                address: 0xfee0_3000,
+                data: 0x31,
+            }
+        );
+
+        // Lowest priority delivery to the logical destination 0x0c, level triggered.
+        let entry = 0x52 | (1 << DELIVERY_MODE_SHIFT) | (1 << DEST_MODE_SHIFT) | LEVEL_TRIGGERED;
+        let entry = entry | (0x0c << DEST_SHIFT);
+        assert_eq!(
+            msi_message(entry),
+            MsiMessage {
+                address: 0xfee0_c004,
+                data: 0x8152,
+            }
+        );
+    }
+
+    #[test]
+    fn test_registers() {
+        let (ioapic, _) = ioapic();
+        assert_eq!(read_reg(&ioapic, IOAPICVER), 0x17_0011);
+
+        write_reg(&ioapic, IOAPICID, 0xffff_ffff);
+        assert_eq!(read_reg(&ioapic, IOAPICID), 0x0f00_0000);
+
+        // Delivery status and Remote IRR are read only.
+        write_entry(&ioapic, 2, 0x20 | READ_ONLY | LEVEL_TRIGGERED | MASKED);
+        assert_eq!(read_entry(&ioapic, 2), 0x20 | LEVEL_TRIGGERED | MASKED);
+    }
+
+    #[test]
+    fn test_edge_delivery() {
+        let (ioapic, routing) = ioapic();
+        write_entry(&ioapic, 4, 0x24);
+        assert_eq!(
+            routing.routes.lock().unwrap().get(&4),
+            Some(&msi_message(0x24))
+        );
+
+        ioapic.set_irq(4, true);
+        assert_eq!(routing.take_raised(), [4]);
+
+        // Only the rising edge delivers.
+        ioapic.set_irq(4, true);
+        assert!(routing.take_raised().is_empty());
+        ioapic.set_irq(4, false);
+        assert!(routing.take_raised().is_empty());
+        ioapic.set_irq(4, true);
+        assert_eq!(routing.take_raised(), [4]);
+
+        // Edge triggered pins have no Remote IRR to clear.
+        assert_eq!(read_entry(&ioapic, 4) & REMOTE_IRR, 0);
+    }
+
+    #[test]
+    fn test_level_delivery() {
+        let (ioapic, routing) = ioapic();
+        write_entry(&ioapic, 9, 0x39 | LEVEL_TRIGGERED);
+
+        ioapic.set_irq(9, true);
+        assert_eq!(routing.take_raised(), [9]);
+        assert_ne!(read_entry(&ioapic, 9) & REMOTE_IRR, 0);
+
+        // Remote IRR holds the interrupt until the guest EOIs its vector.
+        ioapic.set_irq(9, true);
+        eoi(&ioapic, 0x40);
+        assert!(routing.take_raised().is_empty());
+
+        // The line is still asserted at the EOI, the pin delivers again.
+        eoi(&ioapic, 0x39);
+        assert_eq!(routing.take_raised(), [9]);
+        assert_ne!(read_entry(&ioapic, 9) & REMOTE_IRR, 0);
+
+        // Deasserted, the EOI only clears Remote IRR.
+        ioapic.set_irq(9, false);
+        eoi(&ioapic, 0x39);
+        assert!(routing.take_raised().is_empty());
+        assert_eq!(read_entry(&ioapic, 9) & REMOTE_IRR, 0);
+
+        ioapic.set_irq(9, true);
+        assert_eq!(routing.take_raised(), [9]);
+    }
+
+    #[test]
+    fn test_masking() {
+        let (ioapic, routing) = ioapic();
+
+        // Pins start masked and without a route.
+        ioapic.set_irq(1, true);
+        ioapic.set_irq(10, true);
+        assert!(routing.take_raised().is_empty());
+        assert!(routing.routes.lock().unwrap().is_empty());
+
+        // Unmasking an edge triggered pin does not deliver the edge it missed.
+        write_entry(&ioapic, 1, 0x21);
+        assert!(routing.take_raised().is_empty());
+
+        // An asserted level triggered line delivers once its pin is unmasked.
+        write_entry(&ioapic, 10, 0x3a | LEVEL_TRIGGERED);
+        assert_eq!(routing.take_raised(), [10]);
+
+        // Masking removes the route, the line waits until the pin is unmasked again.
+        eoi(&ioapic, 0x3a);
+        assert_eq!(routing.take_raised(), [10]);
+        write_entry(&ioapic, 10, 0x3a | LEVEL_TRIGGERED | MASKED);
+        assert!(!routing.routes.lock().unwrap().contains_key(&10));
+        eoi(&ioapic, 0x3a);
+        assert!(routing.take_raised().is_empty());
+        write_entry(&ioapic, 10, 0x3a | LEVEL_TRIGGERED);
+        assert_eq!(routing.take_raised(), [10]);
+    }
+}
@@ -7,5 +7,23 @@ pub use serial::{SerialDevice, SerialEventsWrapper, setup_serial_device,SerialOu
 pub mod bus;
 pub use bus::{Bus, BusDevice};
 
+pub mod doorbell;
+pub use doorbell::{DataMatch, Doorbells};
+
+pub mod pvpanic;
+pub use pvpanic::PvPanicDevice;
+
+pub mod boot_timer;
+pub use boot_timer::{BootTimerDevice, BootTimes};
+
+pub mod post_code;
+pub use post_code::PostCodeDevice;
+
+pub mod identity;
+pub use identity::IdentityDevice;
+
+pub mod ioapic;
+pub use ioapic::IoApic;
+
 pub mod port_io;
 pub use port_io::PortIODeviceManager;
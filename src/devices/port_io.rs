@@ -2,19 +2,26 @@ use std::fmt::Debug;
 use std::sync::{Arc, Mutex};
 
 use anyhow::Result;
-use kvm_ioctls::VmFd;
+use kvm_ioctls::{IoEventAddress, VmFd};
 use vm_superio::Serial;
 use vmm_sys_util::eventfd::{EventFd, EFD_NONBLOCK};
 
-use crate::devices::{BusDevice, EventFdTrigger, SerialDevice, SerialEventsWrapper, SerialOut};
+use crate::devices::identity::IDENTITY_SIZE;
+use crate::devices::pvpanic::{PVPANIC_CRASH_LOADED, PVPANIC_PANICKED};
+use crate::devices::{
+    BootTimerDevice, BootTimes, Bus, BusDevice, DataMatch, Doorbells, EventFdTrigger,
+    IdentityDevice, PostCodeDevice, PvPanicDevice, SerialDevice, SerialEventsWrapper, SerialOut,
+};
 
 /// The `PortIODeviceManager` is a wrapper that is used for registering legacy devices
-/// on an I/O Bus. It currently manages the uart and i8042 devices.
+/// on an I/O Bus. It currently manages the uart, i8042, pvpanic, boot timer, POST code and
+/// identity devices.
 #[derive(Debug)]
 pub struct PortIODeviceManager {
-    pub io_bus: crate::devices::Bus,
+    /// Shared by the vCPU threads once the devices are registered.
+    pub io_bus: Arc<Bus>,
     // BusDevice::Serial
-    pub stdio_serial: Arc<Mutex<BusDevice>>,
+    pub stdio_serial: Arc<BusDevice>,
 
     // Communication event on ports 1 & 3.
     pub com_evt_1_3: EventFdTrigger,
@@ -22,6 +29,22 @@ pub struct PortIODeviceManager {
     pub com_evt_2_4: EventFdTrigger,
     // Keyboard event.
     pub kbd_evt: EventFd,
+
+    // BusDevice::PvPanic
+    pub pvpanic: Arc<BusDevice>,
+    // Guest panic event, handled by the VMM.
+    pub pvpanic_evt: EventFdTrigger,
+    // Writes KVM signals without exiting, serviced by the VMM event loop.
+    pub doorbells: Arc<Doorbells>,
+
+    // BusDevice::BootTimer
+    pub boot_timer: Arc<BusDevice>,
+
+    // BusDevice::PostCode
+    pub post_code: Arc<BusDevice>,
+
+    // BusDevice::Identity
+    pub identity: Arc<BusDevice>,
 }
 
 impl PortIODeviceManager {
@@ -41,16 +64,26 @@ impl PortIODeviceManager {
     const SERIAL_PORT_ADDRESSES: [u64; 4] = [0x3f8, 0x2f8, 0x3e8, 0x2e8];
     /// Size of legacy serial ports.
     const SERIAL_PORT_SIZE: u64 = 0x8;
+    /// The pvpanic port QEMU uses by default, where guests look for it.
+    const PVPANIC_PORT_ADDRESS: u64 = 0x505;
+    /// The port Firecracker guests signal the end of their boot on.
+    const BOOT_TIMER_PORT_ADDRESS: u64 = 0x3f0;
+    /// The POST code port of PC firmware.
+    const POST_CODE_PORT_ADDRESS: u64 = 0x80;
+    /// Free in the PC port map and next to the QEMU fw_cfg ports at 0x510.
+    const IDENTITY_PORT_ADDRESS: u64 = 0x520;
 
     /// Create a new DeviceManager handling legacy devices (uart, i8042).
-    pub fn new(serial: Arc<Mutex<BusDevice>>) -> Result<Self> {
-        debug_assert!(matches!(*serial.lock().unwrap(), BusDevice::Serial(_)));
-        let io_bus = crate::devices::Bus::new();
+    pub fn new(
+        serial: Arc<BusDevice>,
+        boot_times: Arc<BootTimes>,
+        identity: IdentityDevice,
+    ) -> Result<Self> {
         let com_evt_1_3 = serial
+            .serial()
+            .ok_or(anyhow::anyhow!("expected a serial device"))?
             .lock()
             .expect("Poisoned lock")
-            .serial_mut()
-            .unwrap()
             .serial
             .interrupt_evt()
             .try_clone()?;
@@ -58,59 +91,110 @@ impl PortIODeviceManager {
         let com_evt_2_4 = EventFdTrigger::new();
         let kbd_evt = EventFd::new(EFD_NONBLOCK)?;
 
+        let pvpanic_evt = EventFdTrigger::new();
+        let pvpanic = Arc::new(BusDevice::PvPanic(PvPanicDevice::new(
+            pvpanic_evt.try_clone()?,
+        )));
+
+        let post_code = Arc::new(BusDevice::PostCode(PostCodeDevice::new(boot_times.clone())));
+        let boot_timer = Arc::new(BusDevice::BootTimer(BootTimerDevice::new(boot_times)));
+        let identity = Arc::new(BusDevice::Identity(identity));
+
         Ok(PortIODeviceManager {
-            io_bus,
+            io_bus: Arc::new(Bus::new()),
             stdio_serial: serial,
             com_evt_1_3,
             com_evt_2_4,
             kbd_evt,
+            pvpanic,
+            pvpanic_evt,
+            doorbells: Arc::new(Doorbells::default()),
+            boot_timer,
+            post_code,
+            identity,
         })
     }
 
     /// Register supported legacy devices.
     pub fn register_devices(&mut self, vm_fd: &VmFd) -> Result<()> {
-        let serial_2_4 = Arc::new(Mutex::new(BusDevice::Serial(SerialDevice {
+        let serial_2_4 = Arc::new(BusDevice::Serial(Mutex::new(SerialDevice {
             serial: Serial::with_events(
                 self.com_evt_2_4.try_clone()?.try_clone()?,
                 SerialEventsWrapper,
                 SerialOut::Sink(std::io::sink()),
             ),
-            input: None,
         })));
 
-        let serial_1_3 = Arc::new(Mutex::new(BusDevice::Serial(SerialDevice {
+        let serial_1_3 = Arc::new(BusDevice::Serial(Mutex::new(SerialDevice {
             serial: Serial::with_events(
                 self.com_evt_1_3.try_clone()?.try_clone()?,
                 SerialEventsWrapper,
                 SerialOut::Sink(std::io::sink()),
             ),
-            input: None,
         })));
 
-        self.io_bus.insert(
+        let mut io_bus = Bus::new();
+        io_bus.insert(
             self.stdio_serial.clone(),
             Self::SERIAL_PORT_ADDRESSES[0],
             Self::SERIAL_PORT_SIZE,
         )?;
-        self.io_bus.insert(
+        io_bus.insert(
             serial_2_4.clone(),
             Self::SERIAL_PORT_ADDRESSES[1],
             Self::SERIAL_PORT_SIZE,
         )?;
-        self.io_bus.insert(
+        io_bus.insert(
             serial_1_3,
             Self::SERIAL_PORT_ADDRESSES[2],
             Self::SERIAL_PORT_SIZE,
         )?;
-        self.io_bus.insert(
+        io_bus.insert(
             serial_2_4,
             Self::SERIAL_PORT_ADDRESSES[3],
             Self::SERIAL_PORT_SIZE,
         )?;
 
-        vm_fd.register_irqfd(&self.com_evt_1_3, Self::COM_EVT_1_3_GSI)?;
-        vm_fd.register_irqfd(&self.com_evt_2_4, Self::COM_EVT_2_4_GSI)?;
-        vm_fd.register_irqfd(&self.kbd_evt, Self::KBD_EVT_GSI)?;
+        io_bus.insert(self.pvpanic.clone(), Self::PVPANIC_PORT_ADDRESS, 1)?;
+        io_bus.insert(self.boot_timer.clone(), Self::BOOT_TIMER_PORT_ADDRESS, 1)?;
+        io_bus.insert(self.post_code.clone(), Self::POST_CODE_PORT_ADDRESS, 1)?;
+        io_bus.insert(
+            self.identity.clone(),
+            Self::IDENTITY_PORT_ADDRESS,
+            IDENTITY_SIZE,
+        )?;
+        self.io_bus = Arc::new(io_bus);
+
+        // The guest reports each pvpanic event in its own write, which only needs to reach the
+        // event loop.
+        let mut doorbells = Doorbells::default();
+        for event in [PVPANIC_PANICKED, PVPANIC_CRASH_LOADED] {
+            doorbells.register(
+                vm_fd,
+                &self.io_bus,
+                IoEventAddress::Pio(Self::PVPANIC_PORT_ADDRESS),
+                DataMatch::U8(event),
+            )?;
+        }
+        self.doorbells = Arc::new(doorbells);
+
+        Ok(())
+    }
+
+    /// The eventfds the devices write to raise their interrupt, with the GSI of each.
+    pub fn irq_lines(&self) -> [(&EventFd, u32); 3] {
+        [
+            (&self.com_evt_1_3, Self::COM_EVT_1_3_GSI),
+            (&self.com_evt_2_4, Self::COM_EVT_2_4_GSI),
+            (&self.kbd_evt, Self::KBD_EVT_GSI),
+        ]
+    }
+
+    /// Lets KVM raise the GSIs of the device interrupts, for its in-kernel irqchip.
+    pub fn register_irqfds(&self, vm_fd: &VmFd) -> Result<()> {
+        for (evt, gsi) in self.irq_lines() {
+            vm_fd.register_irqfd(evt, gsi)?;
+        }
 
         Ok(())
     }
@@ -0,0 +1,43 @@
+use std::sync::atomic::{AtomicU16, Ordering};
+use std::sync::Arc;
+
+use super::BootTimes;
+
+/// `last_code` before the first write, out of the range of codes.
+const NO_CODE: u16 = 0x100;
+
+/// The POST code port of PC firmware. Codes are logged when they change, with the time since
+/// kvm-box started, so that the writes guests also use as an I/O delay do not flood the log.
+/// The last code is read back like on most chipsets.
+#[derive(Debug)]
+pub struct PostCodeDevice {
+    last_code: AtomicU16,
+    times: Arc<BootTimes>,
+}
+
+impl PostCodeDevice {
+    pub fn new(times: Arc<BootTimes>) -> Self {
+        PostCodeDevice {
+            last_code: AtomicU16::new(NO_CODE),
+            times,
+        }
+    }
+
+    pub fn bus_read(&self, offset: u64, data: &mut [u8]) {
+        if let (0, [byte]) = (offset, data) {
+            *byte = u8::try_from(self.last_code.load(Ordering::Relaxed)).unwrap_or(0xff);
+        }
+    }
+
+    pub fn bus_write(&self, offset: u64, data: &[u8]) {
+        if let (0, &[code]) = (offset, data) {
+            if self.last_code.swap(code.into(), Ordering::Relaxed) != u16::from(code) {
+                log::info!(
+                    "guest POST code {:#04x} at {:?}",
+                    code,
+                    self.times.since_start()
+                );
+            }
+        }
+    }
+}
@@ -0,0 +1,58 @@
+use std::sync::atomic::{AtomicU8, Ordering};
+
+use vm_superio::Trigger;
+
+use super::EventFdTrigger;
+
+/// The guest kernel panicked.
+pub const PVPANIC_PANICKED: u8 = 1 << 0;
+/// The guest kernel panicked and is about to boot its crash kernel.
+pub const PVPANIC_CRASH_LOADED: u8 = 1 << 1;
+
+/// Events the device advertises to the guest.
+const PVPANIC_EVENTS: u8 = PVPANIC_PANICKED | PVPANIC_CRASH_LOADED;
+
+/// The QEMU pvpanic ISA device, see QEMU's docs/specs/pvpanic.rst. Reading the port returns
+/// the supported events, the guest writes the events that happened. Without ACPI the guest
+/// kernel does not discover it, see the README for what the guest needs.
+#[derive(Debug)]
+pub struct PvPanicDevice {
+    /// Events written by the guest and not handled by the VMM yet.
+    pending: AtomicU8,
+    evt: EventFdTrigger,
+}
+
+impl PvPanicDevice {
+    /// `evt` is triggered whenever the guest reports an event.
+    pub fn new(evt: EventFdTrigger) -> Self {
+        PvPanicDevice {
+            pending: AtomicU8::new(0),
+            evt,
+        }
+    }
+
+    /// Returns the events reported since the last call.
+    pub fn take_events(&self) -> u8 {
+        self.pending.swap(0, Ordering::AcqRel)
+    }
+
+    pub fn bus_read(&self, offset: u64, data: &mut [u8]) {
+        if let (0, 1) = (offset, data.len()) {
+            data[0] = PVPANIC_EVENTS;
+        }
+    }
+
+    pub fn bus_write(&self, offset: u64, data: &[u8]) {
+        if let (0, 1) = (offset, data.len()) {
+            let events = data[0] & PVPANIC_EVENTS;
+            if events == 0 {
+                return;
+            }
+
+            self.pending.fetch_or(events, Ordering::AcqRel);
+            if let Err(err) = self.evt.trigger() {
+                log::error!("Failed to signal pvpanic event: {:?}", err);
+            }
+        }
+    }
+}
@@ -1,5 +1,4 @@
-use std::io::{Read, Write};
-use std::os::fd::AsRawFd;
+use std::io::Write;
 use std::sync::{Arc, Mutex};
 
 use anyhow::Result;
@@ -9,15 +8,11 @@ use vm_superio::{Serial, Trigger};
 use super::{BusDevice, EventFdTrigger};
 
 /// Sets up the serial device.
-pub fn setup_serial_device(
-    input: std::io::Stdin,
-    out: std::io::Stdout,
-) -> Result<Arc<Mutex<BusDevice>>> {
+pub fn setup_serial_device(out: std::io::Stdout) -> Result<Arc<BusDevice>> {
     let interrupt_evt = EventFdTrigger::new();
 
-    let serial = Arc::new(Mutex::new(BusDevice::Serial(SerialWrapper {
+    let serial = Arc::new(BusDevice::Serial(Mutex::new(SerialWrapper {
         serial: Serial::with_events(interrupt_evt, SerialEventsWrapper, SerialOut::Stdout(out)),
-        input: Some(input),
     })));
 
     Ok(serial)
@@ -45,13 +40,11 @@ impl Write for SerialOut {
     }
 }
 
-/// Wrapper over the imported serial device.
+/// Wrapper over the imported serial device. The event loop reads stdin and enqueues its bytes.
 #[derive(Debug)]
-pub struct SerialWrapper<T: Trigger, EV: SerialEvents, I: Read + AsRawFd + Send> {
+pub struct SerialWrapper<T: Trigger, EV: SerialEvents> {
     /// Serial device object.
     pub serial: Serial<T, EV, SerialOut>,
-    /// Input to the serial device (needs to be readable).
-    pub input: Option<I>,
 }
 
 #[derive(Debug)]
@@ -68,11 +61,9 @@ impl SerialEvents for SerialEventsWrapper {
 }
 
 /// Type for representing a serial device.
-pub type SerialDevice<I> = SerialWrapper<EventFdTrigger, SerialEventsWrapper, I>;
+pub type SerialDevice = SerialWrapper<EventFdTrigger, SerialEventsWrapper>;
 
-impl<I: Read + AsRawFd + Send + std::fmt::Debug + 'static>
-    SerialWrapper<EventFdTrigger, SerialEventsWrapper, I>
-{
+impl SerialWrapper<EventFdTrigger, SerialEventsWrapper> {
     pub fn bus_read(&mut self, offset: u64, data: &mut [u8]) {
         if let (Ok(offset), 1) = (u8::try_from(offset), data.len()) {
             data[0] = self.serial.read(offset);
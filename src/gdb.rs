@@ -0,0 +1,681 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::io::{Read, Write};
+use std::sync::{Arc, Mutex};
+
+use anyhow::{Context, Result};
+use kvm_ioctls::VcpuFd;
+use log::{error, info, warn};
+use seccompiler::BpfProgram;
+use vm_memory::{Bytes, GuestAddress};
+use vmm_sys_util::eventfd::{EventFd, EFD_NONBLOCK};
+use vmm_sys_util::poll::PollContext;
+
+use crate::arch::gdb::{DebugExit, HwBreakpoint, HwBreakpointKind};
+use crate::arch::memory::GuestMemoryMmap;
+use crate::socket::{SocketStream, SocketUrl};
+use crate::vcpu::{PauseHolder, VcpuPause};
+
+/// Largest packet the server accepts and sends, advertised in `qSupported`.
+const PACKET_SIZE: usize = 0x4000;
+
+const SIGINT: u8 = 2;
+const SIGTRAP: u8 = 5;
+
+/// A vCPU exit that stopped the VM for the debugger.
+#[derive(Debug, Clone, Copy)]
+struct VcpuStop {
+    cpu_index: usize,
+    exit: DebugExit,
+}
+
+/// State shared between the GDB server and the vCPU threads.
+#[derive(Debug)]
+pub struct GdbStub {
+    stop: Mutex<Option<VcpuStop>>,
+    /// Addresses of the software breakpoints inserted by the debugger.
+    sw_breakpoints: Mutex<BTreeSet<u64>>,
+    stop_evt: EventFd,
+}
+
+impl GdbStub {
+    pub fn new() -> Result<GdbStub> {
+        Ok(GdbStub {
+            stop: Mutex::new(None),
+            sw_breakpoints: Mutex::new(BTreeSet::new()),
+            stop_evt: EventFd::new(EFD_NONBLOCK).context("failed to create gdb stop eventfd")?,
+        })
+    }
+
+    /// Called by a vCPU thread on KVM_EXIT_DEBUG. Stops the VM for the debugger, except for
+    /// int3 instructions the guest placed itself, which are delivered to the guest.
+    pub fn vcpu_debug_exit(
+        &self,
+        cpu_index: usize,
+        vcpu: &VcpuFd,
+        exit: DebugExit,
+        vcpu_pause: &VcpuPause,
+    ) {
+        if let DebugExit::SwBreakpoint { pc } = exit {
+            if !self
+                .sw_breakpoints
+                .lock()
+                .expect("Poisoned lock")
+                .contains(&pc)
+            {
+                if let Err(e) = crate::arch::gdb::inject_breakpoint(vcpu) {
+                    error!("failed to reinject guest breakpoint: {:?}", e);
+                }
+                return;
+            }
+        }
+
+        self.stop
+            .lock()
+            .expect("Poisoned lock")
+            .get_or_insert(VcpuStop { cpu_index, exit });
+
+        // The vCPU parks right after, the server waits for the others.
+        vcpu_pause.request(PauseHolder::Debugger);
+        if let Err(e) = self.stop_evt.write(1) {
+            error!("failed to signal gdb stop: {:?}", e);
+        }
+    }
+}
+
+/// Packets or interrupt requests coming from GDB.
+#[derive(Debug)]
+enum Incoming {
+    Packet(String),
+    Interrupt,
+}
+
+/// GDB remote serial protocol framing over one client connection.
+struct Connection {
+    stream: SocketStream,
+    buf: Vec<u8>,
+    no_ack: bool,
+}
+
+impl Connection {
+    fn new(stream: SocketStream) -> Self {
+        Connection {
+            stream,
+            buf: Vec::new(),
+            no_ack: false,
+        }
+    }
+
+    /// Takes the next complete packet or interrupt out of the buffered input.
+    fn parse(&mut self) -> Result<Option<Incoming>> {
+        loop {
+            match self.buf.first() {
+                None => return Ok(None),
+                Some(0x03) => {
+                    self.buf.remove(0);
+                    return Ok(Some(Incoming::Interrupt));
+                }
+                Some(b'$') => break,
+                // Acks and line noise.
+                Some(_) => {
+                    self.buf.remove(0);
+                }
+            }
+        }
+
+        let end = match self.buf.iter().position(|&b| b == b'#') {
+            Some(end) if self.buf.len() >= end + 3 => end,
+            _ => return Ok(None),
+        };
+
+        let packet: Vec<u8> = self.buf.drain(..end + 3).collect();
+        let data = &packet[1..end];
+        let checksum = std::str::from_utf8(&packet[end + 1..])
+            .ok()
+            .and_then(|cs| u8::from_str_radix(cs, 16).ok());
+
+        if checksum != Some(checksum_of(data)) {
+            warn!("gdb packet with bad checksum");
+            if !self.no_ack {
+                self.write_all(b"-")?;
+            }
+            return Ok(None);
+        }
+
+        if !self.no_ack {
+            self.write_all(b"+")?;
+        }
+
+        Ok(Some(Incoming::Packet(
+            String::from_utf8_lossy(data).into_owned(),
+        )))
+    }
+
+    /// Reads more input, returns false once the client disconnected.
+    fn fill(&mut self) -> Result<bool> {
+        let mut buf = [0u8; 4096];
+        let n = self
+            .stream
+            .read(&mut buf)
+            .context("failed to read gdb connection")?;
+        self.buf.extend_from_slice(&buf[..n]);
+
+        Ok(n > 0)
+    }
+
+    /// Blocks until the next packet or interrupt, `None` once the client disconnected.
+    fn recv(&mut self) -> Result<Option<Incoming>> {
+        loop {
+            if let Some(incoming) = self.parse()? {
+                return Ok(Some(incoming));
+            }
+            if !self.fill()? {
+                return Ok(None);
+            }
+        }
+    }
+
+    fn send(&mut self, data: &str) -> Result<()> {
+        let packet = format!("${}#{:02x}", data, checksum_of(data.as_bytes()));
+        self.write_all(packet.as_bytes())
+    }
+
+    fn write_all(&mut self, data: &[u8]) -> Result<()> {
+        self.stream
+            .write_all(data)
+            .context("failed to write gdb connection")
+    }
+}
+
+fn checksum_of(data: &[u8]) -> u8 {
+    data.iter().fold(0u8, |sum, &b| sum.wrapping_add(b))
+}
+
+/// What the server does after answering a packet.
+enum Action {
+    Reply(String),
+    Resume { step: bool },
+    Detach { reply: bool },
+}
+
+/// Serves one debugger at a time, stopping the whole VM while it is attached and not running.
+struct GdbServer {
+    stub: Arc<GdbStub>,
+    vcpus: Vec<Arc<VcpuFd>>,
+    vcpu_threads: Vec<libc::pthread_t>,
+    vcpu_pause: Arc<VcpuPause>,
+    guest_mem: GuestMemoryMmap,
+    /// Original byte under each inserted int3, by guest virtual address.
+    sw_breakpoints: BTreeMap<u64, (GuestAddress, u8)>,
+    hw_breakpoints: Vec<HwBreakpoint>,
+    /// vCPU selected by the `H` packet, GDB threads are numbered from 1.
+    current_cpu: usize,
+    last_stop: Option<VcpuStop>,
+}
+
+/// Starts the GDB server thread, which installs `seccomp_filter` first. The vCPUs should be
+/// asked to park before they start, so that the debugger sees the VM from its first
+/// instruction.
+pub fn start_server(
+    url: SocketUrl,
+    stub: Arc<GdbStub>,
+    vcpus: Vec<Arc<VcpuFd>>,
+    vcpu_threads: Vec<libc::pthread_t>,
+    vcpu_pause: Arc<VcpuPause>,
+    guest_mem: GuestMemoryMmap,
+    seccomp_filter: Option<Arc<BpfProgram>>,
+) -> Result<()> {
+    let mut server = GdbServer {
+        stub,
+        vcpus,
+        vcpu_threads,
+        vcpu_pause,
+        guest_mem,
+        sw_breakpoints: BTreeMap::new(),
+        hw_breakpoints: Vec::new(),
+        current_cpu: 0,
+        last_stop: None,
+    };
+
+    std::thread::Builder::new()
+        .name(String::from("gdb"))
+        .spawn(move || {
+            if let Some(filter) = &seccomp_filter {
+                if let Err(e) = crate::seccomp::apply(filter, "gdb") {
+                    error!("gdb server not started: {:?}", e);
+                    let _ = server.detach();
+                    return;
+                }
+            }
+
+            loop {
+                let stream = match url.accept() {
+                    Ok(stream) => stream,
+                    Err(e) => {
+                        error!("gdb server stopped: {:?}", e);
+                        let _ = server.detach();
+                        return;
+                    }
+                };
+
+                if let Err(e) = server.serve(stream) {
+                    error!("gdb server error: {:?}", e);
+                }
+                if let Err(e) = server.detach() {
+                    error!("failed to detach gdb: {:?}", e);
+                }
+            }
+        })
+        .context("failed to spawn gdb thread")?;
+
+    Ok(())
+}
+
+impl GdbServer {
+    fn serve(&mut self, stream: SocketStream) -> Result<()> {
+        self.stop_vm(None);
+
+        let mut conn = Connection::new(stream);
+        info!("gdb attached");
+
+        loop {
+            let packet = match conn.recv()? {
+                Some(Incoming::Packet(packet)) => packet,
+                Some(Incoming::Interrupt) => continue,
+                None => break,
+            };
+
+            match self.handle_packet(&packet) {
+                Ok(Action::Reply(reply)) => {
+                    conn.send(&reply)?;
+                    if packet == "QStartNoAckMode" {
+                        conn.no_ack = true;
+                    }
+                }
+                Ok(Action::Resume { step }) => {
+                    self.resume_vm(step)?;
+                    if !self.wait_for_stop(&mut conn)? {
+                        break;
+                    }
+                    conn.send(&self.stop_reply())?;
+                }
+                Ok(Action::Detach { reply }) => {
+                    if reply {
+                        conn.send("OK")?;
+                    }
+                    break;
+                }
+                Err(e) => {
+                    warn!("gdb request {} failed: {:#}", packet, e);
+                    conn.send("E01")?;
+                }
+            }
+        }
+
+        info!("gdb detached");
+        Ok(())
+    }
+
+    /// Parks all vCPUs. `stop` is the reason when the debugger interrupted the VM, otherwise
+    /// the one recorded by the vCPU that stopped it.
+    fn stop_vm(&mut self, stop: Option<VcpuStop>) {
+        let threads = &self.vcpu_threads;
+        self.vcpu_pause
+            .pause(PauseHolder::Debugger, || crate::vcpu::kick_threads(threads));
+
+        // No vCPU runs anymore, a pending event belongs to this stop.
+        let _ = self.stub.stop_evt.read();
+
+        let recorded = self.stub.stop.lock().expect("Poisoned lock").take();
+        self.last_stop = stop.or(recorded);
+        if let Some(stop) = self.last_stop {
+            self.current_cpu = stop.cpu_index;
+        }
+    }
+
+    fn resume_vm(&mut self, step: bool) -> Result<()> {
+        for (cpu_index, vcpu) in self.vcpus.iter().enumerate() {
+            crate::arch::gdb::set_guest_debug(
+                vcpu,
+                &self.hw_breakpoints,
+                step && cpu_index == self.current_cpu,
+            )?;
+        }
+
+        self.vcpu_pause.resume(PauseHolder::Debugger);
+        Ok(())
+    }
+
+    /// Waits until a vCPU stops the VM or the debugger interrupts it. Returns false if the
+    /// debugger went away meanwhile.
+    fn wait_for_stop(&mut self, conn: &mut Connection) -> Result<bool> {
+        let poll_ctx: PollContext<u8> =
+            PollContext::new().context("failed to create epoll context")?;
+        poll_ctx.add(&self.stub.stop_evt, 0)?;
+        poll_ctx.add(&conn.stream, 1)?;
+
+        loop {
+            while let Some(incoming) = conn.parse()? {
+                if let Incoming::Interrupt = incoming {
+                    self.stop_vm(Some(VcpuStop {
+                        cpu_index: self.current_cpu,
+                        exit: DebugExit::Unknown,
+                    }));
+                    return Ok(true);
+                }
+            }
+
+            let events = poll_ctx.wait().context("failed to wait for events")?;
+            for ev in events.iter_readable() {
+                match ev.token() {
+                    0 => {
+                        self.stop_vm(None);
+                        return Ok(true);
+                    }
+                    1 => {
+                        if !conn.fill()? {
+                            return Ok(false);
+                        }
+                    }
+                    _ => unreachable!(),
+                }
+            }
+        }
+    }
+
+    /// Removes the breakpoints and lets the VM run without debugging, unless the VMM holds it
+    /// paused too.
+    fn detach(&mut self) -> Result<()> {
+        let addrs: Vec<u64> = self.sw_breakpoints.keys().copied().collect();
+        for addr in addrs {
+            self.remove_sw_breakpoint(addr)?;
+        }
+        self.hw_breakpoints.clear();
+
+        for vcpu in self.vcpus.iter() {
+            crate::arch::gdb::clear_guest_debug(vcpu)?;
+        }
+
+        self.vcpu_pause.resume(PauseHolder::Debugger);
+        Ok(())
+    }
+
+    fn stop_reply(&self) -> String {
+        let stop = match self.last_stop {
+            Some(stop) => stop,
+            None => {
+                return format!("T{:02x}thread:{:x};", SIGTRAP, self.current_cpu + 1);
+            }
+        };
+
+        let (signal, reason) = match stop.exit {
+            DebugExit::SwBreakpoint { .. } => (SIGTRAP, String::from("swbreak:;")),
+            DebugExit::HwBreakpoint { index } => match self.hw_breakpoints.get(index) {
+                Some(bp) if bp.kind == HwBreakpointKind::Write => {
+                    (SIGTRAP, format!("watch:{:x};", bp.addr))
+                }
+                Some(bp) if bp.kind == HwBreakpointKind::Access => {
+                    (SIGTRAP, format!("awatch:{:x};", bp.addr))
+                }
+                _ => (SIGTRAP, String::from("hwbreak:;")),
+            },
+            DebugExit::Step => (SIGTRAP, String::new()),
+            DebugExit::Unknown => (SIGINT, String::new()),
+        };
+
+        format!("T{:02x}thread:{:x};{}", signal, stop.cpu_index + 1, reason)
+    }
+
+    fn handle_packet(&mut self, packet: &str) -> Result<Action> {
+        let reply = match packet.as_bytes().first() {
+            Some(b'?') => self.stop_reply(),
+            Some(b'g') => hex_encode(&crate::arch::gdb::read_registers(self.vcpu()?)?),
+            Some(b'G') => {
+                crate::arch::gdb::write_registers(self.vcpu()?, &hex_decode(&packet[1..])?)?;
+                String::from("OK")
+            }
+            Some(b'p') => {
+                let index = usize::from_str_radix(&packet[1..], 16)?;
+                match crate::arch::gdb::read_register(self.vcpu()?, index)? {
+                    Some(value) => hex_encode(&value),
+                    None => String::new(),
+                }
+            }
+            Some(b'P') => {
+                let (index, value) = packet[1..]
+                    .split_once('=')
+                    .ok_or(anyhow::anyhow!("malformed P packet"))?;
+                let index = usize::from_str_radix(index, 16)?;
+                match crate::arch::gdb::write_register(self.vcpu()?, index, &hex_decode(value)?)? {
+                    true => String::from("OK"),
+                    false => String::new(),
+                }
+            }
+            Some(b'm') => {
+                // Each byte takes two hex digits, a shorter reply is allowed.
+                let (addr, len) = parse_addr_len(&packet[1..])?;
+                match self.read_memory(addr, len.min(PACKET_SIZE / 2)) {
+                    Ok(data) => hex_encode(&data),
+                    Err(_) => String::from("E14"),
+                }
+            }
+            Some(b'M') => {
+                let (range, data) = packet[1..]
+                    .split_once(':')
+                    .ok_or(anyhow::anyhow!("malformed M packet"))?;
+                let (addr, _) = parse_addr_len(range)?;
+                match self.write_memory(addr, &hex_decode(data)?) {
+                    Ok(()) => String::from("OK"),
+                    Err(_) => String::from("E14"),
+                }
+            }
+            Some(b'c') => return Ok(Action::Resume { step: false }),
+            Some(b's') => return Ok(Action::Resume { step: true }),
+            Some(b'H') => match packet.get(2..).map(|tid| self.select_thread(tid)) {
+                Some(Ok(())) => String::from("OK"),
+                _ => String::from("E01"),
+            },
+            Some(b'T') => match self.thread_cpu(&packet[1..]) {
+                Ok(_) => String::from("OK"),
+                Err(_) => String::from("E01"),
+            },
+            Some(b'Z') => self.insert_breakpoint(&packet[1..])?,
+            Some(b'z') => self.remove_breakpoint(&packet[1..])?,
+            Some(b'D') => return Ok(Action::Detach { reply: true }),
+            Some(b'k') => return Ok(Action::Detach { reply: false }),
+            Some(b'q') | Some(b'Q') => self.handle_query(packet),
+            _ => String::new(),
+        };
+
+        Ok(Action::Reply(reply))
+    }
+
+    fn handle_query(&self, packet: &str) -> String {
+        let name = packet.split([':', ',']).next().unwrap_or_default();
+
+        match name {
+            "qSupported" => format!(
+                "PacketSize={:x};QStartNoAckMode+;swbreak+;hwbreak+",
+                PACKET_SIZE
+            ),
+            "QStartNoAckMode" => String::from("OK"),
+            "qAttached" => String::from("1"),
+            "qC" => format!("QC{:x}", self.current_cpu + 1),
+            "qfThreadInfo" => {
+                let threads: Vec<String> = (1..=self.vcpus.len())
+                    .map(|tid| format!("{:x}", tid))
+                    .collect();
+                format!("m{}", threads.join(","))
+            }
+            "qsThreadInfo" => String::from("l"),
+            _ => String::new(),
+        }
+    }
+
+    fn vcpu(&self) -> Result<&VcpuFd> {
+        self.vcpus
+            .get(self.current_cpu)
+            .map(|vcpu| vcpu.as_ref())
+            .ok_or(anyhow::anyhow!("invalid vcpu {}", self.current_cpu))
+    }
+
+    /// Maps a GDB thread id to a vCPU index.
+    fn thread_cpu(&self, tid: &str) -> Result<usize> {
+        let tid = usize::from_str_radix(tid, 16)?;
+        if tid == 0 || tid > self.vcpus.len() {
+            anyhow::bail!("invalid thread id {}", tid)
+        }
+
+        Ok(tid - 1)
+    }
+
+    fn select_thread(&mut self, tid: &str) -> Result<()> {
+        // 0 means any thread and -1 all threads, the current one does for both.
+        if tid != "0" && tid != "-1" {
+            self.current_cpu = self.thread_cpu(tid)?;
+        }
+
+        Ok(())
+    }
+
+    fn read_memory(&self, addr: u64, len: usize) -> Result<Vec<u8>> {
+        let sregs = self.vcpu()?.get_sregs().context("failed to get sregs")?;
+
+        let mut data = vec![0u8; len];
+        crate::arch::paging::read_virtual(&self.guest_mem, &sregs, addr, &mut data)?;
+
+        Ok(data)
+    }
+
+    fn write_memory(&self, addr: u64, data: &[u8]) -> Result<()> {
+        let sregs = self.vcpu()?.get_sregs().context("failed to get sregs")?;
+
+        crate::arch::paging::write_virtual(&self.guest_mem, &sregs, addr, data)
+    }
+
+    fn insert_breakpoint(&mut self, args: &str) -> Result<String> {
+        let (kind, addr, len) = parse_breakpoint(args)?;
+
+        match (kind, hw_breakpoint_kind(kind)) {
+            (0, _) => {
+                if !self.sw_breakpoints.contains_key(&addr) {
+                    let gpa = self.translate(addr)?;
+                    let orig: u8 = self.guest_mem.read_obj(gpa)?;
+                    self.guest_mem.write_obj(crate::arch::gdb::INT3, gpa)?;
+
+                    self.sw_breakpoints.insert(addr, (gpa, orig));
+                    self.stub
+                        .sw_breakpoints
+                        .lock()
+                        .expect("Poisoned lock")
+                        .insert(addr);
+                }
+            }
+            (_, Some(kind)) => {
+                let bp = HwBreakpoint { addr, kind, len };
+
+                if !self.hw_breakpoints.contains(&bp) {
+                    if self.hw_breakpoints.len() == crate::arch::gdb::MAX_HW_BREAKPOINTS {
+                        return Ok(String::from("E28"));
+                    }
+                    self.hw_breakpoints.push(bp);
+                }
+            }
+            _ => return Ok(String::new()),
+        }
+
+        Ok(String::from("OK"))
+    }
+
+    fn remove_breakpoint(&mut self, args: &str) -> Result<String> {
+        let (kind, addr, _) = parse_breakpoint(args)?;
+
+        match (kind, hw_breakpoint_kind(kind)) {
+            (0, _) => self.remove_sw_breakpoint(addr)?,
+            (_, Some(kind)) => self
+                .hw_breakpoints
+                .retain(|bp| bp.addr != addr || bp.kind != kind),
+            _ => return Ok(String::new()),
+        }
+
+        Ok(String::from("OK"))
+    }
+
+    fn remove_sw_breakpoint(&mut self, addr: u64) -> Result<()> {
+        if let Some((gpa, orig)) = self.sw_breakpoints.remove(&addr) {
+            self.stub
+                .sw_breakpoints
+                .lock()
+                .expect("Poisoned lock")
+                .remove(&addr);
+            self.guest_mem.write_obj(orig, gpa)?;
+        }
+
+        Ok(())
+    }
+
+    fn translate(&self, addr: u64) -> Result<GuestAddress> {
+        let sregs = self.vcpu()?.get_sregs().context("failed to get sregs")?;
+        crate::arch::paging::translate_gva(&self.guest_mem, &sregs, addr).map(GuestAddress)
+    }
+}
+
+/// Maps the breakpoint type of `Z` and `z` packets to a debug register type. Read watchpoints
+/// have no x86 equivalent.
+fn hw_breakpoint_kind(kind: u8) -> Option<HwBreakpointKind> {
+    match kind {
+        1 => Some(HwBreakpointKind::Execute),
+        2 => Some(HwBreakpointKind::Write),
+        4 => Some(HwBreakpointKind::Access),
+        _ => None,
+    }
+}
+
+/// Parses `type,addr,kind` of the `Z` and `z` packets.
+fn parse_breakpoint(args: &str) -> Result<(u8, u64, u64)> {
+    let mut parts = args.split(',');
+    let mut next = || {
+        parts
+            .next()
+            .ok_or(anyhow::anyhow!("malformed breakpoint packet"))
+    };
+
+    let kind = next()?.parse()?;
+    let addr = u64::from_str_radix(next()?, 16)?;
+    let len = u64::from_str_radix(next()?, 16)?;
+
+    Ok((kind, addr, len))
+}
+
+/// Parses `addr,length`.
+fn parse_addr_len(args: &str) -> Result<(u64, usize)> {
+    let (addr, len) = args
+        .split_once(',')
+        .ok_or(anyhow::anyhow!("malformed memory packet"))?;
+
+    Ok((
+        u64::from_str_radix(addr, 16)?,
+        usize::from_str_radix(len, 16)?,
+    ))
+}
+
+fn hex_encode(data: &[u8]) -> String {
+    data.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn hex_decode(hex: &str) -> Result<Vec<u8>> {
+    let hex = hex.as_bytes();
+    if !hex.len().is_multiple_of(2) {
+        anyhow::bail!("odd length hex string")
+    }
+
+    hex.chunks_exact(2)
+        .map(|pair| Ok((hex_digit(pair[0])? << 4) | hex_digit(pair[1])?))
+        .collect()
+}
+
+fn hex_digit(c: u8) -> Result<u8> {
+    char::from(c)
+        .to_digit(16)
+        .map(|digit| digit as u8)
+        .ok_or(anyhow::anyhow!("invalid hex string"))
+}
@@ -0,0 +1,115 @@
+use anyhow::{Context, Result};
+
+/// Ethernet MAC address.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct MacAddr(pub [u8; 6]);
+
+impl MacAddr {
+    /// Returns a random locally administered unicast address.
+    pub fn random() -> Result<MacAddr> {
+        let mut bytes = [0u8; 6];
+        fill_random(&mut bytes)?;
+        bytes[0] = (bytes[0] & !0x01) | 0x02;
+
+        Ok(MacAddr(bytes))
+    }
+}
+
+impl std::str::FromStr for MacAddr {
+    type Err = anyhow::Error;
+
+    /// Parses `xx:xx:xx:xx:xx:xx`.
+    fn from_str(s: &str) -> Result<Self> {
+        let mut bytes = [0u8; 6];
+        let mut parts = s.split(':');
+
+        for byte in bytes.iter_mut() {
+            let part = parts
+                .next()
+                .ok_or(anyhow::anyhow!("invalid mac address: {}", s))?;
+            *byte = u8::from_str_radix(part, 16)
+                .with_context(|| format!("invalid mac address: {}", s))?;
+        }
+
+        if parts.next().is_some() {
+            anyhow::bail!("invalid mac address: {}", s)
+        }
+
+        Ok(MacAddr(bytes))
+    }
+}
+
+impl std::fmt::Display for MacAddr {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let b = &self.0;
+        write!(
+            f,
+            "{:02x}:{:02x}:{:02x}:{:02x}:{:02x}:{:02x}",
+            b[0], b[1], b[2], b[3], b[4], b[5]
+        )
+    }
+}
+
+/// Data that has to be unique per VM, so that clones of one snapshot can coexist.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct VmIdentity {
+    pub mac: MacAddr,
+    pub vsock_cid: u32,
+}
+
+impl VmIdentity {
+    /// First vsock CID available to guests, lower ones are reserved for the host.
+    const MIN_VSOCK_CID: u32 = 3;
+
+    pub fn random() -> Result<VmIdentity> {
+        let mut cid = [0u8; 4];
+        fill_random(&mut cid)?;
+
+        // u32::MAX is VMADDR_CID_ANY.
+        let range = u32::MAX - Self::MIN_VSOCK_CID;
+        let vsock_cid = Self::MIN_VSOCK_CID + u32::from_ne_bytes(cid) % range;
+
+        Ok(VmIdentity {
+            mac: MacAddr::random()?,
+            vsock_cid,
+        })
+    }
+
+    /// Replaces the fields given by the user, the others keep their value.
+    pub fn with_overrides(self, mac: Option<MacAddr>, vsock_cid: Option<u32>) -> Result<Self> {
+        if let Some(cid) = vsock_cid {
+            if !(Self::MIN_VSOCK_CID..u32::MAX).contains(&cid) {
+                anyhow::bail!("invalid vsock cid {}", cid)
+            }
+        }
+
+        Ok(VmIdentity {
+            mac: mac.unwrap_or(self.mac),
+            vsock_cid: vsock_cid.unwrap_or(self.vsock_cid),
+        })
+    }
+}
+
+impl std::fmt::Display for VmIdentity {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "mac={} vsock_cid={}", self.mac, self.vsock_cid)
+    }
+}
+
+/// A random number, e.g. to tell apart things written to the same path.
+pub fn random_u64() -> Result<u64> {
+    let mut bytes = [0u8; 8];
+    fill_random(&mut bytes)?;
+
+    Ok(u64::from_ne_bytes(bytes))
+}
+
+fn fill_random(buf: &mut [u8]) -> Result<()> {
+    // SAFETY: `buf` is writable for its whole length and the result is checked.
+    let ret = unsafe { libc::getrandom(buf.as_mut_ptr().cast(), buf.len(), 0) };
+    if ret < 0 || ret as usize != buf.len() {
+        return Err(std::io::Error::last_os_error()).context("failed to get random bytes");
+    }
+
+    Ok(())
+}
@@ -0,0 +1,97 @@
+use std::collections::HashSet;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use log::info;
+
+/// At most this many accesses are logged per `LOG_WINDOW`, the others are counted.
+const MAX_LOGS_PER_WINDOW: u32 = 10;
+const LOG_WINDOW: Duration = Duration::from_secs(1);
+/// Bounds the memory of guests scanning the address space, accesses beyond are not traced.
+const MAX_TRACKED_ACCESSES: usize = 4096;
+
+/// An access no device handled.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum IoAccess {
+    PioRead { port: u16, size: usize },
+    PioWrite { port: u16, size: usize },
+    MmioRead { addr: u64, size: usize },
+    MmioWrite { addr: u64, size: usize },
+}
+
+impl std::fmt::Display for IoAccess {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            IoAccess::PioRead { port, size } => write!(f, "port read {:#x}, {} bytes", port, size),
+            IoAccess::PioWrite { port, size } => {
+                write!(f, "port write {:#x}, {} bytes", port, size)
+            }
+            IoAccess::MmioRead { addr, size } => write!(f, "mmio read {:#x}, {} bytes", addr, size),
+            IoAccess::MmioWrite { addr, size } => {
+                write!(f, "mmio write {:#x}, {} bytes", addr, size)
+            }
+        }
+    }
+}
+
+#[derive(Debug)]
+struct TraceState {
+    /// Accesses logged already.
+    seen: HashSet<IoAccess>,
+    window_start: Instant,
+    logged: u32,
+    suppressed: u64,
+}
+
+/// Logs the unhandled accesses of all vCPUs, once per port or address, size and direction,
+/// and at most `MAX_LOGS_PER_WINDOW` of them per second.
+#[derive(Debug)]
+pub struct UnhandledIo {
+    state: Mutex<TraceState>,
+}
+
+impl Default for UnhandledIo {
+    fn default() -> Self {
+        UnhandledIo {
+            state: Mutex::new(TraceState {
+                seen: HashSet::new(),
+                window_start: Instant::now(),
+                logged: 0,
+                suppressed: 0,
+            }),
+        }
+    }
+}
+
+impl UnhandledIo {
+    pub fn record(&self, access: IoAccess) {
+        let mut state = self.state.lock().expect("Poisoned lock");
+
+        // Any access, traced before or not, reports what the previous window suppressed.
+        let now = Instant::now();
+        if now.duration_since(state.window_start) >= LOG_WINDOW {
+            if state.suppressed != 0 {
+                info!(
+                    "{} more unhandled accesses were not logged",
+                    state.suppressed
+                );
+            }
+            state.window_start = now;
+            state.logged = 0;
+            state.suppressed = 0;
+        }
+
+        if state.seen.len() >= MAX_TRACKED_ACCESSES || state.seen.contains(&access) {
+            return;
+        }
+
+        // A suppressed access is not seen yet, it gets logged when it comes again later.
+        if state.logged < MAX_LOGS_PER_WINDOW {
+            state.logged += 1;
+            state.seen.insert(access);
+            info!("unhandled {}", access);
+        } else {
+            state.suppressed += 1;
+        }
+    }
+}
@@ -0,0 +1,478 @@
+use std::ffi::{CStr, CString};
+use std::fs::File;
+use std::os::fd::{AsRawFd, FromRawFd, RawFd};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::{fchown, FileTypeExt, MetadataExt, OpenOptionsExt};
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+use log::info;
+
+/// Default parent directory of the jails, which are created in `<base>/kvm-box/<id>/root`.
+pub const DEFAULT_CHROOT_BASE: &str = "/srv/jail";
+
+/// Open file limit when none is given.
+const DEFAULT_NOFILE: u64 = 2048;
+
+/// Device nodes recreated in the jail when they exist on the host, and whether they are
+/// required.
+const DEVICE_NODES: &[(&str, bool)] = &[
+    ("/dev/kvm", true),
+    ("/dev/null", false),
+    ("/dev/urandom", false),
+    ("/dev/userfaultfd", false),
+];
+
+/// Which side of the fork `JailConfig::enter` returned in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum JailProcess {
+    /// The launching process, once the jailed VMM exited with this status.
+    Launcher(i32),
+    /// The jailed process, which goes on running the VMM.
+    Jailed,
+}
+
+/// A resource limit applied in the jail, given as `RESOURCE=VALUE`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Rlimit {
+    resource: libc::__rlimit_resource_t,
+    value: u64,
+}
+
+impl std::str::FromStr for Rlimit {
+    type Err = anyhow::Error;
+
+    /// Parses `fsize`, `nofile`, `nproc`, `memlock`, `core`, `as` or `rtprio`, followed by
+    /// `=VALUE`.
+    fn from_str(s: &str) -> Result<Self> {
+        let (name, value) = s.split_once('=').ok_or(anyhow::anyhow!(
+            "invalid rlimit: {}, expected RESOURCE=VALUE",
+            s
+        ))?;
+
+        let resource = match name {
+            "fsize" => libc::RLIMIT_FSIZE,
+            "nofile" => libc::RLIMIT_NOFILE,
+            "nproc" => libc::RLIMIT_NPROC,
+            "memlock" => libc::RLIMIT_MEMLOCK,
+            "core" => libc::RLIMIT_CORE,
+            "as" => libc::RLIMIT_AS,
+            "rtprio" => libc::RLIMIT_RTPRIO,
+            _ => anyhow::bail!("unknown rlimit resource: {}", name),
+        };
+        let value = value
+            .parse()
+            .with_context(|| format!("invalid rlimit value: {}", s))?;
+
+        Ok(Rlimit { resource, value })
+    }
+}
+
+/// How the VMM is isolated: the directory it is confined to, the user it runs as, the network
+/// namespace it joins and its resource limits.
+#[derive(Debug)]
+pub struct JailConfig {
+    pub id: String,
+    pub uid: u32,
+    pub gid: u32,
+    pub chroot_base: PathBuf,
+    /// Existing network namespace to join, such as `/var/run/netns/NAME`, a new empty one by
+    /// default.
+    pub netns: Option<PathBuf>,
+    pub rlimits: Vec<Rlimit>,
+}
+
+impl JailConfig {
+    /// The directory the VMM is confined to.
+    pub fn root(&self) -> PathBuf {
+        self.chroot_base.join("kvm-box").join(&self.id).join("root")
+    }
+
+    /// Moves the process into the jail: `files` are copied to the jail root, the device nodes
+    /// are created there, and a child process is forked in new mount, PID, network and IPC
+    /// namespaces, which pivots into the jail root, applies the rlimits and drops to the
+    /// unprivileged user. Inherited descriptors are closed in the child, except `keep_fds`.
+    /// The launching process returns once the child exited. The jail directory is left in
+    /// place, with the files the VMM wrote there, and must be removed before its id is used
+    /// again.
+    pub fn enter(&self, files: &[&Path], keep_fds: &[RawFd]) -> Result<JailProcess> {
+        validate_id(&self.id)?;
+        // SAFETY: getuid cannot fail.
+        if unsafe { libc::getuid() } != 0 {
+            anyhow::bail!("the jail must be set up as root")
+        }
+        if self.uid == 0 || self.gid == 0 {
+            anyhow::bail!("the jailed vmm must not run as root")
+        }
+
+        let root = self.root();
+        self.prepare_root(&root, files)?;
+
+        // The network namespace is opened before forking so that errors are reported here.
+        let netns = self
+            .netns
+            .as_ref()
+            .map(|path| {
+                File::open(path)
+                    .with_context(|| format!("failed to open network namespace {}", path.display()))
+            })
+            .transpose()?;
+
+        // A new PID namespace only applies to the children of the caller.
+        check_errno(
+            // SAFETY: no pointers are passed.
+            unsafe { libc::unshare(libc::CLONE_NEWPID) },
+            "failed to create pid namespace",
+        )?;
+
+        // SAFETY: no other thread is running yet, the child continues with a copy of this
+        // single thread.
+        let pid = unsafe { libc::fork() };
+        if pid < 0 {
+            return Err(std::io::Error::last_os_error()).context("failed to fork the jailed vmm");
+        }
+        if pid > 0 {
+            info!("jailed vmm started in {} as pid {}", root.display(), pid);
+            return Ok(JailProcess::Launcher(wait_for(pid)?));
+        }
+
+        self.confine(&root, netns, keep_fds)?;
+
+        Ok(JailProcess::Jailed)
+    }
+
+    /// Creates the jail root owned by the unprivileged user, with copies of `files` and the
+    /// device nodes.
+    ///
+    /// A root left by a previous run is refused: the VMM it jailed owns it and could have put
+    /// links there for this setup to follow as root. Everything is created relative to the root
+    /// directory, failing on existing entries and never following links.
+    fn prepare_root(&self, root: &Path, files: &[&Path]) -> Result<()> {
+        let parent = root.parent().context("jail root has no parent")?;
+        std::fs::create_dir_all(parent)
+            .with_context(|| format!("failed to create {}", parent.display()))?;
+        std::fs::create_dir(root).with_context(|| {
+            format!(
+                "failed to create jail root {}, a jail left by a previous run must be removed",
+                root.display()
+            )
+        })?;
+        let root_dir = open_dir(root)?;
+
+        let mut names = std::collections::HashSet::new();
+        for file in files {
+            let name = jailed_path(file)?;
+            if !names.insert(name.clone()) {
+                anyhow::bail!("more than one file named {} in the jail", name.display())
+            }
+
+            let mut source =
+                File::open(file).with_context(|| format!("failed to open {}", file.display()))?;
+            let mut target = create_at(&root_dir, name.strip_prefix("/")?)?;
+            std::io::copy(&mut source, &mut target)
+                .with_context(|| format!("failed to copy {} into the jail", file.display()))?;
+            self.fchown(&target, &name)?;
+        }
+
+        let dev_name = c"dev";
+        check_errno(
+            // SAFETY: `dev_name` is a valid C string.
+            unsafe { libc::mkdirat(root_dir.as_raw_fd(), dev_name.as_ptr(), 0o755) },
+            "failed to create jail /dev",
+        )?;
+        let dev_dir = open_dir_at(&root_dir, dev_name)?;
+        for &(node, required) in DEVICE_NODES {
+            let metadata = match std::fs::metadata(node) {
+                Ok(metadata) if metadata.file_type().is_char_device() => metadata,
+                _ if required => anyhow::bail!("{} is not available", node),
+                _ => continue,
+            };
+
+            let name = cstring(Path::new(node.trim_start_matches("/dev/")))?;
+            check_errno(
+                // SAFETY: `name` is a valid C string.
+                unsafe {
+                    libc::mknodat(
+                        dev_dir.as_raw_fd(),
+                        name.as_ptr(),
+                        libc::S_IFCHR | 0o600,
+                        metadata.rdev(),
+                    )
+                },
+                &format!("failed to create {} in the jail", node),
+            )?;
+            check_errno(
+                // SAFETY: `name` is a valid C string.
+                unsafe {
+                    libc::fchownat(
+                        dev_dir.as_raw_fd(),
+                        name.as_ptr(),
+                        self.uid,
+                        self.gid,
+                        libc::AT_SYMLINK_NOFOLLOW,
+                    )
+                },
+                &format!("failed to change owner of {} in the jail", node),
+            )?;
+        }
+
+        // The unprivileged user only gets the root once it is populated.
+        self.fchown(&root_dir, root)
+    }
+
+    /// Runs in the forked child: enters the namespaces, pivots into `root`, applies the
+    /// rlimits and drops privileges.
+    fn confine(&self, root: &Path, netns: Option<File>, keep_fds: &[RawFd]) -> Result<()> {
+        // SAFETY: no pointers are passed.
+        unsafe { libc::prctl(libc::PR_SET_PDEATHSIG, libc::SIGKILL) };
+
+        let mut flags = libc::CLONE_NEWNS | libc::CLONE_NEWIPC;
+        if netns.is_none() {
+            flags |= libc::CLONE_NEWNET;
+        }
+        check_errno(
+            // SAFETY: no pointers are passed.
+            unsafe { libc::unshare(flags) },
+            "failed to create namespaces",
+        )?;
+        if let Some(file) = netns {
+            check_errno(
+                // SAFETY: `file` is an open namespace file.
+                unsafe { libc::setns(file.as_raw_fd(), libc::CLONE_NEWNET) },
+                "failed to join network namespace",
+            )?;
+        }
+
+        // Nothing else the launcher inherited leaks into the jail.
+        close_fds_except(keep_fds);
+
+        pivot_root(root)?;
+
+        for rlimit in self.rlimits_or_default() {
+            let limit = libc::rlimit {
+                rlim_cur: rlimit.value,
+                rlim_max: rlimit.value,
+            };
+            check_errno(
+                // SAFETY: `limit` is a valid rlimit.
+                unsafe { libc::setrlimit(rlimit.resource, &limit) },
+                "failed to set rlimit",
+            )?;
+        }
+
+        // SAFETY: no pointers are passed to setgroups with a zero size.
+        unsafe {
+            check_errno(
+                libc::setgroups(0, std::ptr::null()),
+                "failed to drop groups",
+            )?;
+            check_errno(
+                libc::setresgid(self.gid, self.gid, self.gid),
+                "failed to set gid",
+            )?;
+            check_errno(
+                libc::setresuid(self.uid, self.uid, self.uid),
+                "failed to set uid",
+            )?;
+        }
+
+        info!("vmm jailed as uid {} gid {}", self.uid, self.gid);
+
+        Ok(())
+    }
+
+    fn rlimits_or_default(&self) -> Vec<Rlimit> {
+        let mut rlimits = self.rlimits.clone();
+        if !rlimits.iter().any(|r| r.resource == libc::RLIMIT_NOFILE) {
+            rlimits.push(Rlimit {
+                resource: libc::RLIMIT_NOFILE,
+                value: DEFAULT_NOFILE,
+            });
+        }
+
+        rlimits
+    }
+
+    fn fchown(&self, file: &File, path: &Path) -> Result<()> {
+        fchown(file, Some(self.uid), Some(self.gid))
+            .with_context(|| format!("failed to change owner of {}", path.display()))
+    }
+}
+
+/// Where a file copied into the jail is seen from inside: at the root, under its own name.
+pub fn jailed_path(path: &Path) -> Result<PathBuf> {
+    let name = path
+        .file_name()
+        .ok_or(anyhow::anyhow!("{} is not a file", path.display()))?;
+
+    Ok(Path::new("/").join(name))
+}
+
+/// Jail ids name a directory, they are limited to letters, digits and dashes.
+fn validate_id(id: &str) -> Result<()> {
+    let valid = !id.is_empty()
+        && id.len() <= 64
+        && id.chars().all(|c| c.is_ascii_alphanumeric() || c == '-');
+    if !valid {
+        anyhow::bail!(
+            "invalid jail id: {}, expected up to 64 letters, digits or dashes",
+            id
+        )
+    }
+
+    Ok(())
+}
+
+/// Makes `root` the root of the mount namespace and detaches everything else.
+fn pivot_root(root: &Path) -> Result<()> {
+    let root_c = cstring(root)?;
+    let old_root = c"old_root";
+
+    // SAFETY: all pointers are valid C strings or null where the call accepts it.
+    unsafe {
+        // Mounts made in the jail must not propagate back to the host.
+        check_errno(
+            libc::mount(
+                std::ptr::null(),
+                c"/".as_ptr(),
+                std::ptr::null(),
+                libc::MS_PRIVATE | libc::MS_REC,
+                std::ptr::null(),
+            ),
+            "failed to make mounts private",
+        )?;
+        // pivot_root needs the new root to be a mount point.
+        check_errno(
+            libc::mount(
+                root_c.as_ptr(),
+                root_c.as_ptr(),
+                std::ptr::null(),
+                libc::MS_BIND | libc::MS_REC,
+                std::ptr::null(),
+            ),
+            "failed to bind mount the jail root",
+        )?;
+        check_errno(
+            libc::chdir(root_c.as_ptr()),
+            "failed to enter the jail root",
+        )?;
+
+        let _ = std::fs::remove_dir("old_root");
+        check_errno(
+            libc::mkdir(old_root.as_ptr(), 0o700),
+            "failed to create old_root",
+        )?;
+        check_errno(
+            libc::syscall(libc::SYS_pivot_root, c".".as_ptr(), old_root.as_ptr()) as i32,
+            "failed to pivot into the jail root",
+        )?;
+        check_errno(libc::chdir(c"/".as_ptr()), "failed to enter the new root")?;
+        check_errno(
+            libc::umount2(c"/old_root".as_ptr(), libc::MNT_DETACH),
+            "failed to detach the old root",
+        )?;
+        check_errno(
+            libc::rmdir(c"/old_root".as_ptr()),
+            "failed to remove old_root",
+        )?;
+    }
+
+    Ok(())
+}
+
+/// Closes every descriptor above stderr but `keep_fds`.
+fn close_fds_except(keep_fds: &[RawFd]) {
+    let mut keep_fds = keep_fds.to_vec();
+    keep_fds.sort_unstable();
+
+    let mut first = 3;
+    for fd in keep_fds {
+        if fd > first {
+            // SAFETY: no pointers are passed.
+            unsafe { libc::syscall(libc::SYS_close_range, first, fd - 1, 0) };
+        }
+        first = first.max(fd + 1);
+    }
+    // SAFETY: no pointers are passed.
+    unsafe { libc::syscall(libc::SYS_close_range, first, u32::MAX, 0) };
+}
+
+/// Waits for the jailed vmm and returns the exit status to pass on.
+fn wait_for(pid: libc::pid_t) -> Result<i32> {
+    let mut status = 0;
+    loop {
+        // SAFETY: `status` is a valid pointer.
+        if unsafe { libc::waitpid(pid, &mut status, 0) } >= 0 {
+            break;
+        }
+        let err = std::io::Error::last_os_error();
+        if err.kind() != std::io::ErrorKind::Interrupted {
+            return Err(err).context("failed to wait for the jailed vmm");
+        }
+    }
+
+    if libc::WIFSIGNALED(status) {
+        Ok(128 + libc::WTERMSIG(status))
+    } else {
+        Ok(libc::WEXITSTATUS(status))
+    }
+}
+
+/// Opens the directory at `path`, which must not be a link.
+fn open_dir(path: &Path) -> Result<File> {
+    std::fs::OpenOptions::new()
+        .read(true)
+        .custom_flags(libc::O_DIRECTORY | libc::O_NOFOLLOW)
+        .open(path)
+        .with_context(|| format!("failed to open {}", path.display()))
+}
+
+/// Opens the directory `name` of `dir`, which must not be a link.
+fn open_dir_at(dir: &File, name: &CStr) -> Result<File> {
+    // SAFETY: `name` is a valid C string.
+    let fd = unsafe {
+        libc::openat(
+            dir.as_raw_fd(),
+            name.as_ptr(),
+            libc::O_RDONLY | libc::O_DIRECTORY | libc::O_NOFOLLOW | libc::O_CLOEXEC,
+        )
+    };
+    check_errno(fd, &format!("failed to open {:?} in the jail", name))?;
+
+    // SAFETY: `fd` was just opened and is owned by nothing else.
+    Ok(unsafe { File::from_raw_fd(fd) })
+}
+
+/// Creates the file `name` in `dir` for writing, failing if any entry exists there.
+fn create_at(dir: &File, name: &Path) -> Result<File> {
+    let name_c = cstring(name)?;
+    // SAFETY: `name_c` is a valid C string.
+    let fd = unsafe {
+        libc::openat(
+            dir.as_raw_fd(),
+            name_c.as_ptr(),
+            libc::O_WRONLY | libc::O_CREAT | libc::O_EXCL | libc::O_NOFOLLOW | libc::O_CLOEXEC,
+            0o600,
+        )
+    };
+    check_errno(
+        fd,
+        &format!("failed to create {} in the jail", name.display()),
+    )?;
+
+    // SAFETY: `fd` was just opened and is owned by nothing else.
+    Ok(unsafe { File::from_raw_fd(fd) })
+}
+
+fn cstring(path: &Path) -> Result<CString> {
+    CString::new(path.as_os_str().as_bytes())
+        .with_context(|| format!("invalid path {}", path.display()))
+}
+
+fn check_errno(ret: i32, msg: &str) -> Result<()> {
+    if ret < 0 {
+        return Err(std::io::Error::last_os_error()).context(msg.to_string());
+    }
+
+    Ok(())
+}
@@ -0,0 +1,127 @@
+use std::fmt::Write as _;
+use std::fs::File;
+use std::os::fd::{AsRawFd, FromRawFd};
+use std::os::unix::fs::FileExt;
+
+use anyhow::{Context, Result};
+use kvm_bindings::{
+    KVMIO, KVM_STATS_TYPE_LINEAR_HIST, KVM_STATS_TYPE_LOG_HIST, KVM_STATS_TYPE_MASK,
+};
+use vmm_sys_util::ioctl::ioctl;
+use vmm_sys_util::{ioctl_io_nr, ioctl_ioc_nr};
+
+ioctl_io_nr!(KVM_GET_STATS_FD, KVMIO, 0xce);
+
+/// Size of `struct kvm_stats_header`.
+const HEADER_SIZE: usize = 24;
+/// Size of `struct kvm_stats_desc` without the name that follows it.
+const DESC_SIZE: usize = 16;
+
+/// One statistic of a stats fd, see the kernel's Documentation/virt/kvm/api.rst, 4.133.
+#[derive(Debug)]
+struct StatDesc {
+    name: String,
+    flags: u32,
+    /// Number of u64 values, more than one for histograms.
+    size: usize,
+    /// Offset of the values in the data block.
+    offset: u64,
+}
+
+impl StatDesc {
+    fn is_histogram(&self) -> bool {
+        matches!(
+            self.flags & KVM_STATS_TYPE_MASK,
+            KVM_STATS_TYPE_LINEAR_HIST | KVM_STATS_TYPE_LOG_HIST
+        )
+    }
+}
+
+/// The binary statistics KVM exposes for a VM or a vCPU through KVM_GET_STATS_FD. The
+/// descriptors are read once, the values on every `write_json`.
+#[derive(Debug)]
+pub struct KvmStats {
+    file: File,
+    descs: Vec<StatDesc>,
+    data_offset: u64,
+}
+
+impl KvmStats {
+    /// Opens the stats fd of a `VmFd` or `VcpuFd`.
+    pub fn open<F: AsRawFd>(fd: &F) -> Result<KvmStats> {
+        // SAFETY: KVM_GET_STATS_FD takes no argument, the returned fd is checked.
+        let stats_fd = unsafe { ioctl(fd, KVM_GET_STATS_FD()) };
+        if stats_fd < 0 {
+            return Err(std::io::Error::last_os_error()).context("failed to get kvm stats fd");
+        }
+        // SAFETY: `stats_fd` is a freshly created descriptor that nothing else owns.
+        let file = unsafe { File::from_raw_fd(stats_fd) };
+
+        let header = read_at(&file, 0, HEADER_SIZE)?;
+        let name_size = le32(&header, 4) as usize;
+        let num_desc = le32(&header, 8) as usize;
+        let desc_offset = u64::from(le32(&header, 16));
+        let data_offset = u64::from(le32(&header, 20));
+
+        let desc_len = DESC_SIZE + name_size;
+        let table = read_at(&file, desc_offset, num_desc * desc_len)?;
+
+        let descs = table
+            .chunks_exact(desc_len)
+            .map(|desc| {
+                let name = &desc[DESC_SIZE..];
+                let end = name.iter().position(|&c| c == 0).unwrap_or(name.len());
+
+                StatDesc {
+                    name: String::from_utf8_lossy(&name[..end]).into_owned(),
+                    flags: le32(desc, 0),
+                    size: usize::from(u16::from_le_bytes([desc[6], desc[7]])),
+                    offset: u64::from(le32(desc, 8)),
+                }
+            })
+            .collect();
+
+        Ok(KvmStats {
+            file,
+            descs,
+            data_offset,
+        })
+    }
+
+    /// Appends the current values as a JSON object, histograms as arrays of bucket counts.
+    pub fn write_json(&self, out: &mut String) -> Result<()> {
+        write!(out, "{{")?;
+
+        for (i, desc) in self.descs.iter().enumerate() {
+            let data = read_at(&self.file, self.data_offset + desc.offset, desc.size * 8)?;
+            let mut values = data
+                .chunks_exact(8)
+                .map(|value| u64::from_le_bytes(value.try_into().unwrap()));
+
+            let sep = if i == 0 { "" } else { "," };
+            write!(out, "{}\"{}\":", sep, desc.name)?;
+            if desc.is_histogram() {
+                let values: Vec<_> = values.map(|value| value.to_string()).collect();
+                write!(out, "[{}]", values.join(","))?;
+            } else {
+                write!(out, "{}", values.next().unwrap_or(0))?;
+            }
+        }
+
+        write!(out, "}}")?;
+
+        Ok(())
+    }
+}
+
+fn read_at(file: &File, offset: u64, len: usize) -> Result<Vec<u8>> {
+    let mut buf = vec![0u8; len];
+    file.read_exact_at(&mut buf, offset)
+        .context("failed to read kvm stats")?;
+
+    Ok(buf)
+}
+
+fn le32(data: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap())
+}
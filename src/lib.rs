@@ -0,0 +1,4 @@
+//! The devices of the kvm-box VMM, built as a library too so that the benchmarks run the code
+//! the vCPU threads do.
+
+pub mod devices;
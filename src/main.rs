@@ -1,13 +1,29 @@
 use std::env;
 use std::path::PathBuf;
+use std::sync::Arc;
 
 use anyhow::{Context, Result};
 use log::error;
 use vmm_sys_util::terminal::Terminal;
 
+mod api;
 mod arch;
-mod devices;
+mod cgroup;
+mod gdb;
+mod identity;
+mod io_trace;
+mod jail;
+mod kvm_stats;
+mod metrics;
+mod migration;
+mod sched;
+mod seccomp;
+mod snapshot;
+mod socket;
+mod symbols;
+mod vcpu;
 mod vmm;
+use kvm_box::devices;
 use vmm::Vmm;
 
 #[derive(argh::FromArgs, Debug)]
@@ -22,6 +38,201 @@ struct Args {
     #[argh(option, long = "initrd", description = "path to the initrd")]
     initrd: Option<PathBuf>,
 
+    #[argh(
+        option,
+        long = "topology",
+        description = "vcpu topology, as sockets=N,cores=N,threads=N"
+    )]
+    topology: Option<arch::CpuTopology>,
+
+    #[argh(
+        option,
+        long = "pv-features",
+        description = "kvm paravirtual features exposed to the guest, e.g. default,-async-pf"
+    )]
+    pv_features: Option<arch::KvmPvFeatures>,
+
+    #[argh(option, long = "tsc-khz", description = "guest tsc frequency in kHz")]
+    tsc_khz: Option<u32>,
+
+    #[argh(
+        option,
+        long = "irqchip",
+        description = "interrupt controllers: kernel (default), or split with the ioapic in the vmm and neither pic nor pit"
+    )]
+    irqchip: Option<arch::IrqChipMode>,
+
+    #[argh(
+        option,
+        long = "mem-backend",
+        description = "guest memory backend: anon (default), memfd or file:PATH"
+    )]
+    mem_backend: Option<arch::MemoryBackend>,
+
+    #[argh(
+        option,
+        long = "hugepages",
+        description = "back guest memory with huge pages: 2M or 1G"
+    )]
+    hugepages: Option<arch::HugePageSize>,
+
+    #[argh(
+        switch,
+        long = "mem-prefault",
+        description = "populate all guest memory at startup"
+    )]
+    mem_prefault: bool,
+
+    #[argh(switch, long = "mem-lock", description = "mlock guest memory")]
+    mem_lock: bool,
+
+    #[argh(
+        switch,
+        long = "mem-merge",
+        description = "mark guest memory mergeable for ksm"
+    )]
+    mem_merge: bool,
+
+    #[argh(
+        switch,
+        long = "mem-dontdump",
+        description = "exclude guest memory from vmm core dumps"
+    )]
+    mem_dontdump: bool,
+
+    #[argh(
+        option,
+        long = "api-sock",
+        description = "path of the unix socket accepting control requests"
+    )]
+    api_sock: Option<PathBuf>,
+
+    #[argh(
+        option,
+        long = "migrate-from",
+        description = "receive a live migrated vm on unix:PATH or tcp:HOST:PORT instead of booting"
+    )]
+    migrate_from: Option<socket::SocketUrl>,
+
+    #[argh(
+        option,
+        long = "restore-from",
+        description = "restore the vm from a snapshot directory instead of booting, a diff snapshot on top of its bases"
+    )]
+    restore_from: Option<PathBuf>,
+
+    #[argh(
+        option,
+        long = "restore-memory",
+        description = "how snapshot memory is restored: eager (default), lazy or lazy-prefetch"
+    )]
+    restore_memory: Option<snapshot::MemoryRestoreMode>,
+
+    #[argh(
+        option,
+        long = "gdb",
+        description = "serve the gdb remote protocol on unix:PATH or tcp:HOST:PORT, the vm waits for the debugger to attach"
+    )]
+    gdb: Option<socket::SocketUrl>,
+
+    #[argh(
+        option,
+        long = "coredump",
+        description = "write an elf core file of the guest to this path when it crashes"
+    )]
+    coredump: Option<PathBuf>,
+
+    #[argh(
+        switch,
+        long = "pause-on-panic",
+        description = "keep the vm paused when the guest kernel panics, instead of exiting"
+    )]
+    pause_on_panic: bool,
+
+    #[argh(
+        option,
+        long = "metrics",
+        description = "report vcpu exit metrics as json lines to a file, or to unix:PATH or tcp:HOST:PORT"
+    )]
+    metrics: Option<metrics::MetricsSink>,
+
+    #[argh(
+        option,
+        long = "metrics-interval-ms",
+        description = "metrics reporting interval in milliseconds, 1000 by default"
+    )]
+    metrics_interval_ms: Option<u64>,
+
+    #[argh(
+        option,
+        long = "seccomp",
+        description = "syscall filtering of the vcpu, event loop and helper threads: strict, log (default) or off"
+    )]
+    seccomp: Option<seccomp::SeccompLevel>,
+
+    #[argh(
+        switch,
+        long = "trace-unhandled-io",
+        description = "log each port and mmio access no device handles, once per address, size and direction"
+    )]
+    trace_unhandled_io: bool,
+
+    #[argh(
+        option,
+        long = "cpu-affinity",
+        description = "host cpus of each vcpu thread as VCPU=CPUS,..., e.g. 0=2,1=3, where CPUS is a cpu or a range"
+    )]
+    cpu_affinity: Option<sched::VcpuAffinity>,
+
+    #[argh(
+        option,
+        long = "vcpu-sched",
+        description = "real-time scheduling of the vcpu threads: fifo:PRIORITY or rr:PRIORITY, 1 to 99"
+    )]
+    vcpu_sched: Option<sched::SchedPolicy>,
+
+    #[argh(
+        option,
+        long = "vmm-cpu-affinity",
+        description = "host cpus of the event loop thread, as a list of cpus and ranges, e.g. 0,4-5"
+    )]
+    vmm_cpu_affinity: Option<sched::CpuSet>,
+
+    #[argh(
+        option,
+        long = "cgroup",
+        description = "create a cgroup v2 for the vm at this path relative to the cgroup2 mount, removed on exit"
+    )]
+    cgroup: Option<PathBuf>,
+
+    #[argh(
+        option,
+        long = "cpu-max",
+        description = "cpu bandwidth of the vm cgroup as QUOTA_US[/PERIOD_US], the period is 100000 by default"
+    )]
+    cpu_max: Option<cgroup::CpuMax>,
+
+    #[argh(
+        option,
+        long = "cpu-weight",
+        description = "cpu weight of the vm cgroup, 1 to 10000"
+    )]
+    cpu_weight: Option<u32>,
+
+    #[argh(
+        option,
+        long = "memory-max",
+        description = "memory limit of the vm cgroup in bytes, with an optional K, M or G suffix"
+    )]
+    memory_max: Option<cgroup::ByteSize>,
+
+    #[argh(
+        option,
+        long = "io-weight",
+        description = "io weight of the vm cgroup, 1 to 10000"
+    )]
+    io_weight: Option<u32>,
+
     #[argh(
         switch,
         short = 'v',
@@ -29,9 +240,96 @@ struct Args {
         description = "print version info"
     )]
     version: bool,
+
+    #[argh(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(argh::FromArgs, Debug)]
+#[argh(subcommand)]
+enum Command {
+    Clone(CloneArgs),
+    Jail(JailArgs),
+}
+
+#[derive(argh::FromArgs, Debug)]
+#[argh(
+    subcommand,
+    name = "clone",
+    description = "start a copy-on-write clone of a full snapshot"
+)]
+struct CloneArgs {
+    #[argh(option, long = "from", description = "snapshot directory")]
+    from: PathBuf,
+
+    #[argh(
+        option,
+        long = "mac",
+        description = "mac address of the clone, random by default"
+    )]
+    mac: Option<identity::MacAddr>,
+
+    #[argh(
+        option,
+        long = "vsock-cid",
+        description = "vsock cid of the clone, random by default"
+    )]
+    vsock_cid: Option<u32>,
+}
+
+#[derive(argh::FromArgs, Debug)]
+#[argh(
+    subcommand,
+    name = "jail",
+    description = "boot the vm confined to a directory with only its files, in new namespaces and as an unprivileged user; other paths given to the vmm are inside the jail"
+)]
+struct JailArgs {
+    #[argh(
+        option,
+        long = "id",
+        description = "name of the jail, letters, digits and dashes; the jail directory of a previous run with this name must be removed first"
+    )]
+    id: String,
+
+    #[argh(option, long = "uid", description = "user the vmm runs as")]
+    uid: u32,
+
+    #[argh(option, long = "gid", description = "group the vmm runs as")]
+    gid: u32,
+
+    #[argh(
+        option,
+        long = "chroot-base",
+        description = "directory the jails are created in, /srv/jail by default"
+    )]
+    chroot_base: Option<PathBuf>,
+
+    #[argh(
+        option,
+        long = "netns",
+        description = "network namespace to join, a new empty one by default"
+    )]
+    netns: Option<PathBuf>,
+
+    #[argh(
+        option,
+        long = "rlimit",
+        description = "resource limit as RESOURCE=VALUE, for fsize, nofile, nproc, memlock, core, as or rtprio; nofile is 2048 by default"
+    )]
+    rlimits: Vec<jail::Rlimit>,
+
+    #[argh(
+        option,
+        long = "file",
+        description = "additional file copied to the jail root, such as a drive image"
+    )]
+    files: Vec<PathBuf>,
 }
 
 fn main() -> Result<()> {
+    // Guest boot times are measured from here.
+    let start_time = std::time::Instant::now();
+
     if option_env!("RUST_LOG").is_none() {
         env::set_var("RUST_LOG", "info");
     }
@@ -44,6 +342,11 @@ fn main() -> Result<()> {
     std::panic::set_hook(Box::new(move |info| {
         error!("kvm-box {}", info);
 
+        // The event loop holds the stdin lock and restores the terminal itself, and the panics
+        // of devices on vCPU threads are caught while the VM keeps running.
+        if std::thread::current().name() != Some("main") {
+            return;
+        }
         if let Err(err) = stdin.lock().set_canon_mode() {
             error!(
                 "Failure while trying to reset stdin to canonical mode: {}",
@@ -59,29 +362,186 @@ fn main() -> Result<()> {
         return Ok(());
     }
 
-    let kernel = args
-        .kernel
-        .ok_or(anyhow::anyhow!("kernel argument required"))?;
+    let (clone_args, jail_args) = match args.command {
+        Some(Command::Clone(clone_args)) => (Some(clone_args), None),
+        Some(Command::Jail(jail_args)) => (None, Some(jail_args)),
+        None => (None, None),
+    };
 
-    let ram_size = 0x8000_0000; // 2G
-    let mut vm = Vmm::new(ram_size).context("failed to create vmm")?;
-    vm.init().context("failed to vmm.init")?;
+    let mut cgroup = match args.cgroup {
+        Some(path) => {
+            let config = cgroup::CgroupConfig {
+                path,
+                cpu_max: args.cpu_max,
+                cpu_weight: args.cpu_weight,
+                memory_max: args.memory_max,
+                io_weight: args.io_weight,
+            };
+
+            Some(cgroup::Cgroup::create(&config).context("failed to create vm cgroup")?)
+        }
+        None => {
+            let limits = [
+                args.cpu_max.is_some(),
+                args.cpu_weight.is_some(),
+                args.memory_max.is_some(),
+                args.io_weight.is_some(),
+            ];
+            if limits.contains(&true) {
+                anyhow::bail!("--cpu-max, --cpu-weight, --memory-max and --io-weight need --cgroup")
+            }
+
+            None
+        }
+    };
+
+    let mut kernel = args.kernel;
+    let mut initrd = args.initrd;
+    if let Some(jail_args) = jail_args {
+        let jail = jail::JailConfig {
+            id: jail_args.id,
+            uid: jail_args.uid,
+            gid: jail_args.gid,
+            chroot_base: jail_args
+                .chroot_base
+                .unwrap_or_else(|| PathBuf::from(jail::DEFAULT_CHROOT_BASE)),
+            netns: jail_args.netns,
+            rlimits: jail_args.rlimits,
+        };
+
+        let files: Vec<_> = kernel
+            .iter()
+            .chain(initrd.iter())
+            .chain(jail_args.files.iter())
+            .map(PathBuf::as_path)
+            .collect();
+        let keep_fds = cgroup.as_ref().map(cgroup::Cgroup::fds).unwrap_or_default();
+        match jail
+            .enter(&files, &keep_fds)
+            .context("failed to set up the jail")?
+        {
+            jail::JailProcess::Launcher(exit_code) => {
+                // The jailed vmm is gone, its cgroup can be removed.
+                drop(cgroup);
+                std::process::exit(exit_code);
+            }
+            jail::JailProcess::Jailed => {
+                if let Some(cgroup) = cgroup.as_mut() {
+                    cgroup.disown();
+                }
+            }
+        }
+
+        kernel = kernel.as_deref().map(jail::jailed_path).transpose()?;
+        initrd = initrd.as_deref().map(jail::jailed_path).transpose()?;
+    }
+
+    let sources = [
+        args.migrate_from.is_some(),
+        args.restore_from.is_some(),
+        clone_args.is_some(),
+    ];
+    if sources.iter().filter(|&&source| source).count() > 1 {
+        anyhow::bail!("--migrate-from, --restore-from and clone are mutually exclusive")
+    }
 
-    let boot_source_cfg = arch::BootSourceConfig {
-        kernel_image_path: kernel.to_string_lossy().to_string(),
-        initrd_path: args.initrd.map(|p| p.to_string_lossy().to_string()),
-        boot_args: args.boot_cmdline,
+    let mem_backend = match &clone_args {
+        Some(clone_args) => {
+            if args.mem_backend.is_some() {
+                anyhow::bail!("clone maps the snapshot memory, --mem-backend cannot be used")
+            }
+
+            arch::MemoryBackend::Template(snapshot::memory_file_path(&clone_args.from))
+        }
+        None => args.mem_backend.unwrap_or_default(),
     };
 
-    vm.load_image(&boot_source_cfg)
-        .context("failed to load image")?;
+    let lazy_restore = args.restore_from.is_some()
+        && args.restore_memory.unwrap_or_default() != snapshot::MemoryRestoreMode::Eager;
+    if lazy_restore && (args.mem_prefault || args.mem_lock) {
+        anyhow::bail!("--mem-prefault and --mem-lock populate the memory a lazy restore loads")
+    }
+
+    let mem_config = arch::MemoryConfig {
+        ram_size: 0x8000_0000, // 2G
+        backend: mem_backend,
+        hugepages: args.hugepages,
+        prefault: args.mem_prefault,
+        mlock: args.mem_lock,
+        merge: args.mem_merge,
+        dontdump: args.mem_dontdump,
+    };
 
-    vm.run().context("failed to vmm.run")?;
+    let vcpu_config = arch::VcpuConfig {
+        topology: args.topology.unwrap_or_default(),
+        pv_features: args.pv_features.unwrap_or_default(),
+        tsc_khz: args.tsc_khz,
+    };
+
+    let irqchip_mode = args.irqchip.unwrap_or_default();
+    let mut vm = Vmm::new(mem_config, vcpu_config, irqchip_mode, start_time)
+        .context("failed to create vmm")?;
+    vm.init().context("failed to vmm.init")?;
+    vm.setup_devices().context("failed to set up devices")?;
+    vm.coredump_path = args.coredump;
+    vm.pause_on_panic = args.pause_on_panic;
+    vm.metrics_sink = args.metrics;
+    vm.seccomp_level = args.seccomp.unwrap_or_default();
+    vm.trace_unhandled_io = args.trace_unhandled_io;
+    vm.sched_config = sched::SchedConfig {
+        vcpu_affinity: args.cpu_affinity.unwrap_or_default(),
+        vcpu_policy: args.vcpu_sched,
+        vmm_affinity: args.vmm_cpu_affinity,
+    };
+    vm.vcpu_cgroup = cgroup
+        .as_ref()
+        .map(cgroup::Cgroup::vcpu_threads)
+        .transpose()?
+        .map(Arc::new);
+    if let Some(interval_ms) = args.metrics_interval_ms {
+        if interval_ms == 0 {
+            anyhow::bail!("--metrics-interval-ms must not be zero")
+        }
+        vm.metrics_interval = std::time::Duration::from_millis(interval_ms);
+    }
+
+    if let Some(url) = &args.migrate_from {
+        vm.receive_migration(url)
+            .context("failed to receive migration")?;
+    } else if let Some(clone_args) = &clone_args {
+        let identity =
+            identity::VmIdentity::random()?.with_overrides(clone_args.mac, clone_args.vsock_cid)?;
+
+        vm.clone_snapshot(&clone_args.from, identity)
+            .context("failed to clone snapshot")?;
+    } else if let Some(snapshot_dir) = &args.restore_from {
+        vm.restore_snapshot(snapshot_dir, args.restore_memory.unwrap_or_default())
+            .context("failed to restore snapshot")?;
+    } else {
+        let kernel = kernel.ok_or(anyhow::anyhow!("kernel argument required"))?;
+
+        let boot_source_cfg = arch::BootSourceConfig {
+            kernel_image_path: kernel.to_string_lossy().to_string(),
+            initrd_path: initrd.map(|p| p.to_string_lossy().to_string()),
+            boot_args: args.boot_cmdline,
+        };
+
+        vm.load_image(&boot_source_cfg)
+            .context("failed to load image")?;
+    }
+
+    let exit = vm.run(args.api_sock.as_deref(), args.gdb.as_ref());
 
     std::io::stdin()
         .lock()
         .set_canon_mode()
         .context("failed to reset stdin to canonical mode")?;
+    let exit = exit.context("failed to vmm.run")?;
+
+    if exit.exit_code() != 0 {
+        drop(cgroup);
+        std::process::exit(exit.exit_code());
+    }
 
     Ok(())
 }
@@ -0,0 +1,337 @@
+use std::collections::BTreeMap;
+use std::fmt::Write as _;
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use anyhow::{Context, Result};
+use kvm_ioctls::{VcpuExit, VcpuFd, VmFd};
+use log::{error, info, warn};
+use seccompiler::BpfProgram;
+
+use crate::devices::BootTimes;
+use crate::kvm_stats::KvmStats;
+use crate::socket::SocketUrl;
+
+/// Latency buckets are powers of two in nanoseconds, the last one takes everything longer.
+const LATENCY_BUCKETS: usize = 32;
+
+/// What a vCPU exit was for, the key of the exit counters.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum ExitReason {
+    IoIn(u16),
+    IoOut(u16),
+    /// Page of the MMIO access.
+    MmioRead(u64),
+    MmioWrite(u64),
+    Hlt,
+    Shutdown,
+    Debug,
+    SystemEvent,
+    /// KVM_RUN interrupted by a signal.
+    Interrupted,
+    Other,
+}
+
+impl ExitReason {
+    pub fn from_exit(exit: &VcpuExit) -> ExitReason {
+        let page = |addr: u64| addr & !(crate::arch::PAGE_SIZE as u64 - 1);
+
+        match exit {
+            VcpuExit::IoIn(port, _) => ExitReason::IoIn(*port),
+            VcpuExit::IoOut(port, _) => ExitReason::IoOut(*port),
+            VcpuExit::MmioRead(addr, _) => ExitReason::MmioRead(page(*addr)),
+            VcpuExit::MmioWrite(addr, _) => ExitReason::MmioWrite(page(*addr)),
+            VcpuExit::Hlt => ExitReason::Hlt,
+            VcpuExit::Shutdown => ExitReason::Shutdown,
+            VcpuExit::Debug(_) => ExitReason::Debug,
+            VcpuExit::SystemEvent(_, _) => ExitReason::SystemEvent,
+            _ => ExitReason::Other,
+        }
+    }
+}
+
+impl std::fmt::Display for ExitReason {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ExitReason::IoIn(port) => write!(f, "io_in:{:#x}", port),
+            ExitReason::IoOut(port) => write!(f, "io_out:{:#x}", port),
+            ExitReason::MmioRead(addr) => write!(f, "mmio_read:{:#x}", addr),
+            ExitReason::MmioWrite(addr) => write!(f, "mmio_write:{:#x}", addr),
+            ExitReason::Hlt => write!(f, "hlt"),
+            ExitReason::Shutdown => write!(f, "shutdown"),
+            ExitReason::Debug => write!(f, "debug"),
+            ExitReason::SystemEvent => write!(f, "system_event"),
+            ExitReason::Interrupted => write!(f, "interrupted"),
+            ExitReason::Other => write!(f, "other"),
+        }
+    }
+}
+
+/// Count and handling latency of one exit reason.
+#[derive(Debug, Clone)]
+struct ExitStats {
+    count: u64,
+    total_ns: u64,
+    max_ns: u64,
+    /// Bucket `i` counts latencies below `2^i` ns.
+    histogram: [u64; LATENCY_BUCKETS],
+}
+
+impl Default for ExitStats {
+    fn default() -> Self {
+        ExitStats {
+            count: 0,
+            total_ns: 0,
+            max_ns: 0,
+            histogram: [0; LATENCY_BUCKETS],
+        }
+    }
+}
+
+impl ExitStats {
+    fn record(&mut self, latency: Duration) {
+        let ns = u64::try_from(latency.as_nanos()).unwrap_or(u64::MAX);
+        let bucket = (u64::BITS - ns.leading_zeros()) as usize;
+
+        self.count += 1;
+        self.total_ns = self.total_ns.saturating_add(ns);
+        self.max_ns = self.max_ns.max(ns);
+        self.histogram[bucket.min(LATENCY_BUCKETS - 1)] += 1;
+    }
+
+    fn write_json(&self, out: &mut String) -> std::fmt::Result {
+        write!(
+            out,
+            "{{\"count\":{},\"total_ns\":{},\"max_ns\":{},\"latency_ns\":{{",
+            self.count, self.total_ns, self.max_ns
+        )?;
+
+        let buckets = self
+            .histogram
+            .iter()
+            .enumerate()
+            .filter(|(_, &count)| count != 0);
+        for (i, (bucket, count)) in buckets.enumerate() {
+            let bound = if bucket == LATENCY_BUCKETS - 1 {
+                String::from("inf")
+            } else {
+                (1u64 << bucket).to_string()
+            };
+            let sep = if i == 0 { "" } else { "," };
+            write!(out, "{}\"lt_{}\":{}", sep, bound, count)?;
+        }
+
+        write!(out, "}}}}")
+    }
+}
+
+/// Exit counters of one vCPU, updated by its thread.
+#[derive(Debug, Default)]
+pub struct VcpuMetrics {
+    exits: BTreeMap<ExitReason, ExitStats>,
+}
+
+impl VcpuMetrics {
+    pub fn record_exit(&mut self, reason: ExitReason, latency: Duration) {
+        self.exits.entry(reason).or_default().record(latency);
+    }
+
+    fn write_json(&self, out: &mut String) -> std::fmt::Result {
+        write!(out, "{{")?;
+        for (i, (reason, stats)) in self.exits.iter().enumerate() {
+            let sep = if i == 0 { "" } else { "," };
+            write!(out, "{}\"{}\":", sep, reason)?;
+            stats.write_json(out)?;
+        }
+
+        write!(out, "}}")
+    }
+}
+
+/// The KVM binary statistics of the VM and of each vCPU.
+#[derive(Debug)]
+struct KvmStatsSet {
+    vm: KvmStats,
+    vcpus: Vec<KvmStats>,
+}
+
+impl KvmStatsSet {
+    fn open(vm: &VmFd, vcpus: &[Arc<VcpuFd>]) -> Result<KvmStatsSet> {
+        Ok(KvmStatsSet {
+            vm: KvmStats::open(vm)?,
+            vcpus: vcpus
+                .iter()
+                .map(|vcpu| KvmStats::open(vcpu.as_ref()))
+                .collect::<Result<_>>()?,
+        })
+    }
+}
+
+/// Counters of the whole VM.
+#[derive(Debug)]
+pub struct Metrics {
+    pub vcpus: Vec<Mutex<VcpuMetrics>>,
+    /// Missing on kernels without KVM_CAP_BINARY_STATS_FD.
+    kvm_stats: Option<KvmStatsSet>,
+    boot_times: Arc<BootTimes>,
+}
+
+impl Metrics {
+    pub fn new(vm: &VmFd, vcpus: &[Arc<VcpuFd>], boot_times: Arc<BootTimes>) -> Self {
+        let kvm_stats = KvmStatsSet::open(vm, vcpus)
+            .map_err(|e| warn!("kvm binary stats are not available: {:?}", e))
+            .ok();
+
+        Metrics {
+            vcpus: vcpus.iter().map(|_| Mutex::default()).collect(),
+            kvm_stats,
+            boot_times,
+        }
+    }
+
+    /// One JSON object with the cumulative counters, without trailing newline.
+    pub fn to_json(&self) -> String {
+        let timestamp_ms = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |time| time.as_millis());
+
+        let mut out = String::new();
+        // Writing to a String cannot fail.
+        let _ = write!(out, "{{\"timestamp_ms\":{},\"vcpus\":[", timestamp_ms);
+        for (cpu_index, vcpu) in self.vcpus.iter().enumerate() {
+            let sep = if cpu_index == 0 { "" } else { "," };
+            let _ = write!(out, "{}{{\"cpu\":{},\"exits\":", sep, cpu_index);
+            let _ = vcpu.lock().expect("Poisoned lock").write_json(&mut out);
+            if let Some(kvm_stats) = &self.kvm_stats {
+                write_kvm_stats(&mut out, &kvm_stats.vcpus[cpu_index]);
+            }
+            let _ = write!(out, "}}");
+        }
+        let _ = write!(out, "]");
+        match self.boot_times.boot_duration() {
+            Some(boot) => {
+                let _ = write!(
+                    out,
+                    ",\"boot\":{{\"since_start_us\":{},\"since_first_run_us\":{}}}",
+                    boot.since_start.as_micros(),
+                    boot.since_first_run.as_micros()
+                );
+            }
+            None => {
+                let _ = write!(out, ",\"boot\":null");
+            }
+        }
+        if let Some(kvm_stats) = &self.kvm_stats {
+            write_kvm_stats(&mut out, &kvm_stats.vm);
+        }
+        let _ = write!(out, "}}");
+
+        out
+    }
+}
+
+/// Appends a `"kvm"` member, null when the values cannot be read.
+fn write_kvm_stats(out: &mut String, stats: &KvmStats) {
+    let mut values = String::new();
+    if let Err(e) = stats.write_json(&mut values) {
+        error!("failed to read kvm stats: {:?}", e);
+        values = String::from("null");
+    }
+
+    let _ = write!(out, ",\"kvm\":{}", values);
+}
+
+/// Destination of the metrics, a file or `unix:PATH`/`tcp:HOST:PORT` socket that is connected
+/// to.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MetricsSink {
+    File(PathBuf),
+    Socket(SocketUrl),
+}
+
+impl std::str::FromStr for MetricsSink {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        if s.starts_with("unix:") || s.starts_with("tcp:") {
+            Ok(MetricsSink::Socket(s.parse()?))
+        } else {
+            Ok(MetricsSink::File(s.into()))
+        }
+    }
+}
+
+impl MetricsSink {
+    fn open(&self) -> Result<Box<dyn Write + Send>> {
+        match self {
+            MetricsSink::File(path) => {
+                let file = OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(path)
+                    .with_context(|| format!("failed to open metrics file {}", path.display()))?;
+
+                Ok(Box::new(file))
+            }
+            MetricsSink::Socket(url) => Ok(Box::new(url.connect()?)),
+        }
+    }
+}
+
+/// Appends the metrics as one JSON line to `sink` every `interval`, from a dedicated thread.
+/// A sink that fails is opened again at the next interval. The thread installs
+/// `seccomp_filter` first.
+pub fn start_reporter(
+    sink: MetricsSink,
+    interval: Duration,
+    metrics: Arc<Metrics>,
+    seccomp_filter: Option<Arc<BpfProgram>>,
+) -> Result<()> {
+    let writer = Some(sink.open()?);
+    info!("metrics reported every {:?}", interval);
+
+    std::thread::Builder::new()
+        .name(String::from("metrics"))
+        .spawn(move || {
+            if let Some(filter) = &seccomp_filter {
+                if let Err(e) = crate::seccomp::apply(filter, "metrics") {
+                    error!("metrics not reported: {:?}", e);
+                    return;
+                }
+            }
+
+            report(&sink, interval, &metrics, writer)
+        })
+        .context("failed to spawn metrics thread")?;
+
+    Ok(())
+}
+
+fn report(
+    sink: &MetricsSink,
+    interval: Duration,
+    metrics: &Metrics,
+    mut writer: Option<Box<dyn Write + Send>>,
+) {
+    loop {
+        std::thread::sleep(interval);
+
+        if writer.is_none() {
+            writer = sink
+                .open()
+                .map_err(|e| error!("failed to open metrics sink: {:?}", e))
+                .ok();
+        }
+
+        if let Some(w) = writer.as_mut() {
+            let line = metrics.to_json() + "\n";
+            if let Err(e) = w.write_all(line.as_bytes()).and_then(|_| w.flush()) {
+                error!("failed to write metrics: {:?}", e);
+                writer = None;
+            }
+        }
+    }
+}
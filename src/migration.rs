@@ -0,0 +1,266 @@
+use std::io::{BufReader, BufWriter, Write};
+
+use anyhow::{Context, Result};
+use log::info;
+use vm_memory::{Bytes, GuestMemory, GuestMemoryRegion, MemoryRegionAddress};
+
+use crate::arch::memory::GuestMemoryMmap;
+use crate::snapshot::persist::{StateReader, StateWriter};
+use crate::snapshot::{CpuModel, DirtyBitmap, MicrovmState};
+use crate::socket::SocketStream;
+
+const MAGIC: u64 = 0x4b56_4d42_4f58_4d47; // "KVMBOXMG"
+const VERSION: u32 = 3;
+
+/// Guest memory is sent in chunks of at most this size.
+const CHUNK_SIZE: usize = 4 << 20;
+
+const TAG_MEMORY: u8 = 1;
+const TAG_STATE: u8 = 2;
+
+const ACK_OK: u8 = 1;
+const ACK_ERROR: u8 = 0;
+
+/// Sending half of a migration, see `Vmm::migrate` for the order of the messages.
+pub struct MigrationSender {
+    writer: StateWriter<BufWriter<SocketStream>>,
+    reader: StateReader<SocketStream>,
+    buf: Vec<u8>,
+    /// Set by the destination when its guest memory starts out zeroed.
+    skip_zeroes: bool,
+}
+
+impl MigrationSender {
+    pub fn new(stream: SocketStream) -> Result<MigrationSender> {
+        Ok(MigrationSender {
+            reader: StateReader::new(stream.try_clone()?),
+            writer: StateWriter::new(BufWriter::new(stream)),
+            buf: vec![0; CHUNK_SIZE],
+            skip_zeroes: false,
+        })
+    }
+
+    /// Describes the VM layout and its vCPUs, the destination has to be configured the same way.
+    /// It answers once it checked them, telling whether its guest memory starts out zeroed.
+    pub fn send_header(&mut self, guest_mem: &GuestMemoryMmap, cpu: &CpuModel) -> Result<()> {
+        self.writer.write_u64(MAGIC)?;
+        self.writer.write_u32(VERSION)?;
+
+        self.writer
+            .write_u32(u32::try_from(guest_mem.num_regions())?)?;
+        for region in guest_mem.iter() {
+            self.writer.write_u64(region.start_addr().0)?;
+            self.writer.write_u64(region.len())?;
+        }
+
+        cpu.persist(&mut self.writer)?;
+        self.writer
+            .flush()
+            .context("failed to flush migration stream")?;
+
+        match self.reader.read_u8() {
+            Ok(ACK_OK) => {}
+            _ => anyhow::bail!("destination rejected the vm layout"),
+        }
+        self.skip_zeroes = self.reader.read_u8()? != 0;
+
+        Ok(())
+    }
+
+    /// Sends the guest memory, or only the pages set in `dirty`, and returns the number of
+    /// bytes sent. Without `dirty`, chunks that are all zeroes are skipped when the destination
+    /// said its memory starts out zeroed.
+    pub fn send_memory(
+        &mut self,
+        guest_mem: &GuestMemoryMmap,
+        dirty: Option<&[DirtyBitmap]>,
+    ) -> Result<u64> {
+        let mut sent = 0;
+
+        for (index, region) in guest_mem.iter().enumerate() {
+            match dirty {
+                None => {
+                    let skip_zeroes = self.skip_zeroes;
+                    sent +=
+                        self.send_range(index, region, 0, region.len() as usize, skip_zeroes)?;
+                }
+                Some(bitmaps) => {
+                    let bitmap = bitmaps
+                        .get(index)
+                        .ok_or(anyhow::anyhow!("no dirty bitmap for region {}", index))?;
+
+                    for (page, count) in bitmap.dirty_runs() {
+                        let offset = (page * crate::arch::PAGE_SIZE) as u64;
+                        let len =
+                            (count * crate::arch::PAGE_SIZE).min((region.len() - offset) as usize);
+
+                        sent += self.send_range(index, region, offset, len, false)?;
+                    }
+                }
+            }
+        }
+
+        self.writer
+            .flush()
+            .context("failed to flush migration stream")?;
+
+        Ok(sent)
+    }
+
+    fn send_range<R: GuestMemoryRegion>(
+        &mut self,
+        index: usize,
+        region: &R,
+        offset: u64,
+        len: usize,
+        skip_zeroes: bool,
+    ) -> Result<u64> {
+        let mut sent = 0;
+
+        let end = offset + len as u64;
+        let mut chunk_offset = offset;
+        while chunk_offset < end {
+            let chunk_len = CHUNK_SIZE.min((end - chunk_offset) as usize);
+            let chunk = &mut self.buf[..chunk_len];
+            region
+                .read_slice(chunk, MemoryRegionAddress(chunk_offset))
+                .context("failed to read guest memory")?;
+
+            if !(skip_zeroes && chunk.iter().all(|&b| b == 0)) {
+                self.writer.write_u8(TAG_MEMORY)?;
+                self.writer.write_u32(u32::try_from(index)?)?;
+                self.writer.write_u64(chunk_offset)?;
+                self.writer.write_vec(chunk)?;
+                sent += chunk_len as u64;
+            }
+
+            chunk_offset += chunk_len as u64;
+        }
+
+        Ok(sent)
+    }
+
+    /// Sends the device and vCPU state and waits for the destination to restore it.
+    pub fn send_state(&mut self, state: &MicrovmState) -> Result<()> {
+        self.writer.write_u8(TAG_STATE)?;
+        state.persist(&mut self.writer)?;
+        self.writer
+            .flush()
+            .context("failed to flush migration stream")?;
+
+        match self.reader.read_u8()? {
+            ACK_OK => Ok(()),
+            _ => anyhow::bail!("destination failed to restore the vm state"),
+        }
+    }
+}
+
+/// Receiving half of a migration.
+pub struct MigrationReceiver {
+    reader: StateReader<BufReader<SocketStream>>,
+    stream: SocketStream,
+}
+
+impl MigrationReceiver {
+    pub fn new(stream: SocketStream) -> Result<MigrationReceiver> {
+        Ok(MigrationReceiver {
+            reader: StateReader::new(BufReader::new(stream.try_clone()?)),
+            stream,
+        })
+    }
+
+    /// Checks that the source VM has the same layout and vCPUs as the local one, and tells the
+    /// source whether `guest_mem` is still all zeroes, so that it can skip sending zero pages.
+    pub fn receive_header(
+        &mut self,
+        guest_mem: &GuestMemoryMmap,
+        cpu: &CpuModel,
+        memory_zeroed: bool,
+    ) -> Result<()> {
+        if self.reader.read_u64()? != MAGIC {
+            anyhow::bail!("not a kvm-box migration stream")
+        }
+
+        let version = self.reader.read_u32()?;
+        if version != VERSION {
+            anyhow::bail!("unsupported migration stream version {}", version)
+        }
+
+        let region_count = self.reader.read_u32()? as usize;
+        if region_count != guest_mem.num_regions() {
+            anyhow::bail!(
+                "source has {} memory regions, destination has {}",
+                region_count,
+                guest_mem.num_regions()
+            )
+        }
+
+        for region in guest_mem.iter() {
+            let start = self.reader.read_u64()?;
+            let len = self.reader.read_u64()?;
+            if start != region.start_addr().0 || len != region.len() {
+                anyhow::bail!(
+                    "source memory region {:#x}+{:#x} does not match destination {:#x}+{:#x}",
+                    start,
+                    len,
+                    region.start_addr().0,
+                    region.len()
+                )
+            }
+        }
+
+        CpuModel::load(&mut self.reader)?
+            .check_matches(cpu)
+            .context("source vcpus do not match")?;
+
+        self.stream
+            .write_all(&[ACK_OK, u8::from(memory_zeroed)])
+            .context("failed to accept the vm layout")
+    }
+
+    /// Writes the incoming memory into `guest_mem` until the vm state arrives, which is
+    /// returned.
+    pub fn receive_memory_and_state(
+        &mut self,
+        guest_mem: &GuestMemoryMmap,
+    ) -> Result<MicrovmState> {
+        let mut received: u64 = 0;
+
+        loop {
+            match self.reader.read_u8()? {
+                TAG_MEMORY => {
+                    let index = self.reader.read_u32()? as usize;
+                    let offset = self.reader.read_u64()?;
+                    let data = self.reader.read_vec()?;
+
+                    let region = guest_mem
+                        .iter()
+                        .nth(index)
+                        .ok_or(anyhow::anyhow!("invalid memory region {}", index))?;
+                    region
+                        .write_slice(&data, MemoryRegionAddress(offset))
+                        .context("failed to write guest memory")?;
+
+                    received += data.len() as u64;
+                }
+                TAG_STATE => {
+                    info!("received {} MiB of guest memory", received >> 20);
+                    return MicrovmState::load(&mut self.reader);
+                }
+                tag => anyhow::bail!("invalid migration message {}", tag),
+            }
+        }
+    }
+
+    /// Tells the source whether the state got restored, the source VM stops on success.
+    pub fn send_ack(&mut self, restored: bool) -> Result<()> {
+        let ack = match restored {
+            true => ACK_OK,
+            false => ACK_ERROR,
+        };
+
+        self.stream
+            .write_all(&[ack])
+            .context("failed to acknowledge migration")
+    }
+}
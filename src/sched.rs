@@ -0,0 +1,219 @@
+use std::collections::BTreeMap;
+
+use anyhow::{Context, Result};
+use log::info;
+
+/// Valid priorities of the real-time policies.
+const MIN_RT_PRIORITY: i32 = 1;
+const MAX_RT_PRIORITY: i32 = 99;
+
+/// A set of host CPUs, given as a list of CPUs and ranges such as `0,2-3`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CpuSet(Vec<usize>);
+
+impl std::str::FromStr for CpuSet {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let mut cpus = Vec::new();
+        for part in s.split(',') {
+            let (first, last) = match part.split_once('-') {
+                Some((first, last)) => (first, last),
+                None => (part, part),
+            };
+            let first: usize = first
+                .parse()
+                .with_context(|| format!("invalid cpu list: {}", s))?;
+            let last: usize = last
+                .parse()
+                .with_context(|| format!("invalid cpu list: {}", s))?;
+
+            if first > last || last >= libc::CPU_SETSIZE as usize {
+                anyhow::bail!("invalid cpu range in {}: {}", s, part)
+            }
+            cpus.extend(first..=last);
+        }
+
+        cpus.sort_unstable();
+        cpus.dedup();
+
+        Ok(CpuSet(cpus))
+    }
+}
+
+impl std::fmt::Display for CpuSet {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let cpus: Vec<_> = self.0.iter().map(|cpu| cpu.to_string()).collect();
+        write!(f, "{}", cpus.join(","))
+    }
+}
+
+impl CpuSet {
+    /// Restricts the calling thread to these CPUs.
+    pub fn apply(&self) -> Result<()> {
+        // SAFETY: an all-zero cpu_set_t is the empty set.
+        let mut set: libc::cpu_set_t = unsafe { std::mem::zeroed() };
+        for &cpu in &self.0 {
+            // SAFETY: CPU numbers are checked to be below CPU_SETSIZE when parsed.
+            unsafe { libc::CPU_SET(cpu, &mut set) };
+        }
+
+        // SAFETY: `set` is a valid cpu_set_t of the given size, pid 0 is the calling thread.
+        let ret =
+            unsafe { libc::sched_setaffinity(0, std::mem::size_of::<libc::cpu_set_t>(), &set) };
+        if ret < 0 {
+            return Err(std::io::Error::last_os_error())
+                .with_context(|| format!("failed to set cpu affinity to {}", self));
+        }
+
+        Ok(())
+    }
+}
+
+/// Host CPUs of each vCPU thread, given as `VCPU=CPUS,...` such as `0=2,1=3`, where `CPUS` is
+/// one CPU or a range. vCPUs left out run anywhere.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct VcpuAffinity(BTreeMap<usize, CpuSet>);
+
+impl std::str::FromStr for VcpuAffinity {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let mut affinity = BTreeMap::new();
+        for part in s.split(',') {
+            let (vcpu, cpus) = part.split_once('=').ok_or(anyhow::anyhow!(
+                "invalid cpu affinity: {}, expected VCPU=CPUS",
+                s
+            ))?;
+            let vcpu = vcpu
+                .parse()
+                .with_context(|| format!("invalid vcpu index in cpu affinity: {}", part))?;
+
+            if affinity.insert(vcpu, cpus.parse()?).is_some() {
+                anyhow::bail!("vcpu {} is pinned more than once: {}", vcpu, s)
+            }
+        }
+
+        Ok(VcpuAffinity(affinity))
+    }
+}
+
+impl VcpuAffinity {
+    pub fn get(&self, cpu_index: usize) -> Option<&CpuSet> {
+        self.0.get(&cpu_index)
+    }
+
+    /// Fails when a vCPU index is not below `vcpu_count`.
+    pub fn check(&self, vcpu_count: usize) -> Result<()> {
+        match self.0.keys().find(|&&vcpu| vcpu >= vcpu_count) {
+            Some(vcpu) => anyhow::bail!(
+                "cpu affinity given for vcpu {}, the vm has {} vcpus",
+                vcpu,
+                vcpu_count
+            ),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Real-time scheduling policy of the vCPU threads, given as `fifo:PRIORITY` or
+/// `rr:PRIORITY` with a priority from 1 to 99.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SchedPolicy {
+    Fifo(i32),
+    RoundRobin(i32),
+}
+
+impl std::str::FromStr for SchedPolicy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (policy, priority) = s.split_once(':').ok_or(anyhow::anyhow!(
+            "invalid scheduling policy: {}, expected fifo:PRIORITY or rr:PRIORITY",
+            s
+        ))?;
+        let priority: i32 = priority
+            .parse()
+            .with_context(|| format!("invalid scheduling priority: {}", s))?;
+        if !(MIN_RT_PRIORITY..=MAX_RT_PRIORITY).contains(&priority) {
+            anyhow::bail!(
+                "invalid scheduling priority: {}, expected {} to {}",
+                priority,
+                MIN_RT_PRIORITY,
+                MAX_RT_PRIORITY
+            )
+        }
+
+        match policy {
+            "fifo" => Ok(SchedPolicy::Fifo(priority)),
+            "rr" => Ok(SchedPolicy::RoundRobin(priority)),
+            _ => anyhow::bail!("unknown scheduling policy: {}, expected fifo or rr", policy),
+        }
+    }
+}
+
+impl std::fmt::Display for SchedPolicy {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SchedPolicy::Fifo(priority) => write!(f, "SCHED_FIFO priority {}", priority),
+            SchedPolicy::RoundRobin(priority) => write!(f, "SCHED_RR priority {}", priority),
+        }
+    }
+}
+
+impl SchedPolicy {
+    /// Switches the calling thread to this policy. Needs CAP_SYS_NICE or a high enough
+    /// RLIMIT_RTPRIO.
+    pub fn apply(&self) -> Result<()> {
+        let (policy, priority) = match *self {
+            SchedPolicy::Fifo(priority) => (libc::SCHED_FIFO, priority),
+            SchedPolicy::RoundRobin(priority) => (libc::SCHED_RR, priority),
+        };
+        let param = libc::sched_param {
+            sched_priority: priority,
+        };
+
+        // SAFETY: `param` is a valid sched_param, pid 0 is the calling thread.
+        if unsafe { libc::sched_setscheduler(0, policy, &param) } < 0 {
+            return Err(std::io::Error::last_os_error())
+                .with_context(|| format!("failed to switch to {}", self));
+        }
+
+        Ok(())
+    }
+}
+
+/// Where the vCPU threads and the event loop thread run, and how the vCPU threads are
+/// scheduled. Threads keep the defaults they inherit when unset.
+#[derive(Debug, Clone, Default)]
+pub struct SchedConfig {
+    pub vcpu_affinity: VcpuAffinity,
+    pub vcpu_policy: Option<SchedPolicy>,
+    pub vmm_affinity: Option<CpuSet>,
+}
+
+impl SchedConfig {
+    /// Applies the affinity and policy of vCPU `cpu_index` to the calling thread.
+    pub fn apply_vcpu(&self, cpu_index: usize) -> Result<()> {
+        if let Some(cpus) = self.vcpu_affinity.get(cpu_index) {
+            cpus.apply()?;
+            info!("vcpu{} pinned to host cpus {}", cpu_index, cpus);
+        }
+        if let Some(policy) = &self.vcpu_policy {
+            policy.apply()?;
+            info!("vcpu{} scheduled with {}", cpu_index, policy);
+        }
+
+        Ok(())
+    }
+
+    /// Applies the event loop affinity to the calling thread.
+    pub fn apply_vmm(&self) -> Result<()> {
+        if let Some(cpus) = &self.vmm_affinity {
+            cpus.apply()?;
+            info!("vmm thread pinned to host cpus {}", cpus);
+        }
+
+        Ok(())
+    }
+}
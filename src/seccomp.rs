@@ -0,0 +1,341 @@
+use std::collections::BTreeMap;
+use std::sync::Arc;
+
+use anyhow::{Context, Result};
+use log::info;
+use seccompiler::{
+    BpfProgram, SeccompAction, SeccompCmpArgLen, SeccompCmpOp, SeccompCondition, SeccompFilter,
+    SeccompRule,
+};
+use vmm_sys_util::signal::register_signal_handler;
+
+/// Exit status of a process killed for a seccomp violation.
+const SECCOMP_EXIT_CODE: i32 = 31;
+
+/// Ioctl numbers carry their type in bits 8..15.
+const IOCTL_TYPE_MASK: u64 = 0xff00;
+const KVMIO_TYPE: u64 = (kvm_bindings::KVMIO as u64) << 8;
+/// Terminal ioctls, TCGETS, TCSETS and FIONBIO among them.
+const TTY_IOCTL_TYPE: u64 = (b'T' as u64) << 8;
+const TCGETS: u64 = 0x5401;
+/// Userfaultfd ioctls, see the kernel's include/uapi/linux/userfaultfd.h.
+const UFFDIO_TYPE: u64 = 0xaa << 8;
+
+/// How the syscall allowlists are enforced.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum SeccompLevel {
+    /// A syscall outside the allowlist kills the process with a report of the syscall.
+    Strict,
+    /// Syscalls outside the allowlist are allowed and logged by the kernel audit log.
+    #[default]
+    Log,
+    /// No filter.
+    Off,
+}
+
+impl std::str::FromStr for SeccompLevel {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "strict" => Ok(SeccompLevel::Strict),
+            "log" => Ok(SeccompLevel::Log),
+            "off" => Ok(SeccompLevel::Off),
+            _ => anyhow::bail!("invalid seccomp level: {}, expected strict, log or off", s),
+        }
+    }
+}
+
+/// The compiled filters of the vCPU threads, of the VMM event loop thread and of the helper
+/// threads: the GDB server, the metrics reporter and the userfaultfd handlers.
+#[derive(Debug)]
+pub struct SeccompFilters {
+    pub vcpu: Option<Arc<BpfProgram>>,
+    pub vmm: Option<Arc<BpfProgram>>,
+    pub helper: Option<Arc<BpfProgram>>,
+}
+
+impl SeccompFilters {
+    pub fn new(level: SeccompLevel) -> Result<SeccompFilters> {
+        let mismatch_action = match level {
+            SeccompLevel::Strict => SeccompAction::Trap,
+            SeccompLevel::Log => SeccompAction::Log,
+            SeccompLevel::Off => {
+                return Ok(SeccompFilters {
+                    vcpu: None,
+                    vmm: None,
+                    helper: None,
+                })
+            }
+        };
+
+        if level == SeccompLevel::Strict {
+            register_sigsys_handler()?;
+        }
+
+        Ok(SeccompFilters {
+            vcpu: Some(Arc::new(compile(vcpu_rules()?, mismatch_action.clone())?)),
+            vmm: Some(Arc::new(compile(vmm_rules()?, mismatch_action.clone())?)),
+            helper: Some(Arc::new(compile(helper_rules()?, mismatch_action)?)),
+        })
+    }
+}
+
+/// Installs `filter` on the calling thread. Threads it spawns afterwards inherit it.
+pub fn apply(filter: &BpfProgram, thread: &str) -> Result<()> {
+    seccompiler::apply_filter(filter)
+        .with_context(|| format!("failed to install seccomp filter on {} thread", thread))?;
+    info!("seccomp filter installed on {} thread", thread);
+
+    Ok(())
+}
+
+fn compile(
+    rules: BTreeMap<i64, Vec<SeccompRule>>,
+    mismatch_action: SeccompAction,
+) -> Result<BpfProgram> {
+    let filter = SeccompFilter::new(
+        rules,
+        mismatch_action,
+        SeccompAction::Allow,
+        std::env::consts::ARCH
+            .try_into()
+            .context("unsupported seccomp architecture")?,
+    )
+    .context("failed to create seccomp filter")?;
+
+    filter
+        .try_into()
+        .context("failed to compile seccomp filter")
+}
+
+/// Allows `ioctl` for the given ioctl types only, and TCGETS which the logger uses to
+/// detect a terminal.
+fn ioctl_rules(types: &[u64]) -> Result<Vec<SeccompRule>> {
+    let tcgets = SeccompRule::new(vec![SeccompCondition::new(
+        1,
+        SeccompCmpArgLen::Dword,
+        SeccompCmpOp::Eq,
+        TCGETS,
+    )?]);
+
+    types
+        .iter()
+        .map(|&ioctl_type| {
+            SeccompRule::new(vec![SeccompCondition::new(
+                1,
+                SeccompCmpArgLen::Dword,
+                SeccompCmpOp::MaskedEq(IOCTL_TYPE_MASK),
+                ioctl_type,
+            )?])
+        })
+        .chain(std::iter::once(tcgets))
+        .collect::<std::result::Result<_, _>>()
+        .context("failed to build ioctl seccomp rules")
+}
+
+fn allow(syscalls: &[libc::c_long]) -> BTreeMap<i64, Vec<SeccompRule>> {
+    syscalls.iter().map(|&nr| (nr, Vec::new())).collect()
+}
+
+/// The common syscalls, and `prctl(PR_GET_NAME)` for the violation report.
+fn common_rules() -> Result<BTreeMap<i64, Vec<SeccompRule>>> {
+    let mut rules = allow(COMMON_SYSCALLS);
+    rules.extend(allow(BACKTRACE_SYSCALLS));
+    rules.insert(
+        libc::SYS_prctl,
+        vec![SeccompRule::new(vec![SeccompCondition::new(
+            0,
+            SeccompCmpArgLen::Dword,
+            SeccompCmpOp::Eq,
+            libc::PR_GET_NAME as u64,
+        )?])?],
+    );
+
+    Ok(rules)
+}
+
+/// Syscalls needed by every thread: memory allocation, locks, signals, logging, dropping file
+/// descriptors and exit.
+const COMMON_SYSCALLS: &[libc::c_long] = &[
+    libc::SYS_brk,
+    libc::SYS_mmap,
+    libc::SYS_munmap,
+    libc::SYS_mremap,
+    libc::SYS_madvise,
+    libc::SYS_mprotect,
+    libc::SYS_futex,
+    libc::SYS_sched_yield,
+    libc::SYS_rt_sigreturn,
+    libc::SYS_rt_sigprocmask,
+    libc::SYS_sigaltstack,
+    libc::SYS_restart_syscall,
+    libc::SYS_clock_gettime,
+    libc::SYS_getpid,
+    libc::SYS_gettid,
+    libc::SYS_write,
+    libc::SYS_writev,
+    libc::SYS_close,
+    // Checks the descriptor when it is closed in debug builds.
+    libc::SYS_fcntl,
+    libc::SYS_exit,
+    libc::SYS_exit_group,
+];
+
+/// Symbolizing the backtrace of an error or of a device panic caught by the bus reads the
+/// debug info of the binary, on any thread.
+const BACKTRACE_SYSCALLS: &[libc::c_long] = &[
+    libc::SYS_openat,
+    libc::SYS_read,
+    libc::SYS_lseek,
+    libc::SYS_fstat,
+    libc::SYS_newfstatat,
+    libc::SYS_statx,
+    libc::SYS_readlink,
+    libc::SYS_readlinkat,
+    libc::SYS_getcwd,
+];
+
+/// vCPU threads run KVM_RUN, emulate port I/O and the IOAPIC, which sets GSI routes, write
+/// eventfds and stdout, and read vCPU state for the debugger, crash reports and core dumps.
+fn vcpu_rules() -> Result<BTreeMap<i64, Vec<SeccompRule>>> {
+    let mut rules = common_rules()?;
+    rules.insert(libc::SYS_ioctl, ioctl_rules(&[KVMIO_TYPE])?);
+
+    Ok(rules)
+}
+
+/// The event loop handles stdin, the API socket and its requests: pause and resume,
+/// snapshots, core dumps and live migration.
+fn vmm_rules() -> Result<BTreeMap<i64, Vec<SeccompRule>>> {
+    let mut rules = common_rules()?;
+    rules.extend(allow(&[
+        // Event loop and eventfds.
+        libc::SYS_epoll_ctl,
+        libc::SYS_epoll_wait,
+        libc::SYS_epoll_pwait,
+        libc::SYS_read,
+        libc::SYS_readv,
+        // Snapshot, memory and core files.
+        libc::SYS_openat,
+        libc::SYS_lseek,
+        libc::SYS_pread64,
+        libc::SYS_pwrite64,
+        libc::SYS_fstat,
+        libc::SYS_newfstatat,
+        libc::SYS_statx,
+        libc::SYS_mkdir,
+        libc::SYS_mkdirat,
+        libc::SYS_getcwd,
+        libc::SYS_ftruncate,
+        libc::SYS_fsync,
+        libc::SYS_unlink,
+        libc::SYS_unlinkat,
+        // Removing the VM cgroup on exit.
+        libc::SYS_rmdir,
+        // API connections and migration sockets.
+        libc::SYS_accept4,
+        libc::SYS_socket,
+        libc::SYS_connect,
+        libc::SYS_setsockopt,
+        libc::SYS_getsockopt,
+        libc::SYS_sendto,
+        libc::SYS_recvfrom,
+        libc::SYS_sendmsg,
+        libc::SYS_recvmsg,
+        libc::SYS_shutdown,
+        // Kicking the vCPU threads out of KVM_RUN.
+        libc::SYS_tgkill,
+        libc::SYS_getrandom,
+    ]));
+    rules.insert(libc::SYS_ioctl, ioctl_rules(&[KVMIO_TYPE, TTY_IOCTL_TYPE])?);
+
+    Ok(rules)
+}
+
+/// Helper threads accept the debugger and connect or open the metrics sink, poll and sleep,
+/// read the vCPU state and KVM stats, kick the vCPUs for the debugger and serve the guest page
+/// faults of a lazy restore.
+fn helper_rules() -> Result<BTreeMap<i64, Vec<SeccompRule>>> {
+    let mut rules = common_rules()?;
+    rules.extend(allow(&[
+        // Polling the debugger connection and the stop eventfd.
+        libc::SYS_epoll_create1,
+        libc::SYS_epoll_ctl,
+        libc::SYS_epoll_wait,
+        libc::SYS_epoll_pwait,
+        // KVM stats.
+        libc::SYS_pread64,
+        // Debugger connections and metrics sinks.
+        libc::SYS_socket,
+        libc::SYS_bind,
+        libc::SYS_listen,
+        libc::SYS_accept4,
+        libc::SYS_connect,
+        libc::SYS_setsockopt,
+        libc::SYS_sendto,
+        libc::SYS_recvfrom,
+        libc::SYS_unlink,
+        libc::SYS_unlinkat,
+        // The metrics interval.
+        libc::SYS_nanosleep,
+        libc::SYS_clock_nanosleep,
+        // Kicking the vCPU threads out of KVM_RUN.
+        libc::SYS_tgkill,
+    ]));
+    rules.insert(libc::SYS_ioctl, ioctl_rules(&[KVMIO_TYPE, UFFDIO_TYPE])?);
+
+    Ok(rules)
+}
+
+/// Reports the syscall that violated the filter and exits, the default SIGSYS action would
+/// dump core without saying why.
+fn register_sigsys_handler() -> Result<()> {
+    extern "C" fn handle_sigsys(_: libc::c_int, info: *mut libc::siginfo_t, _: *mut libc::c_void) {
+        // SAFETY: the kernel passes a valid siginfo, for SIGSYS `si_syscall` is the int at
+        // offset 24 on 64-bit, after the signal header and `si_call_addr`.
+        let syscall = unsafe { *(info as *const u8).add(24).cast::<libc::c_int>() };
+
+        // Only async-signal-safe calls from here on, the message is built on the stack.
+        let mut thread = [0u8; 16];
+        // SAFETY: PR_GET_NAME writes at most 16 bytes.
+        unsafe { libc::prctl(libc::PR_GET_NAME, thread.as_mut_ptr()) };
+
+        let mut msg = [0u8; 128];
+        let mut len = 0;
+        let mut push = |bytes: &[u8]| {
+            for &byte in bytes.iter().take_while(|&&byte| byte != 0) {
+                if len < msg.len() {
+                    msg[len] = byte;
+                    len += 1;
+                }
+            }
+        };
+
+        push(b"\nkvm-box: seccomp violation: syscall ");
+        let mut digits = [0u8; 12];
+        let mut n = syscall.unsigned_abs();
+        let mut start = digits.len();
+        loop {
+            start -= 1;
+            digits[start] = b'0' + (n % 10) as u8;
+            n /= 10;
+            if n == 0 {
+                break;
+            }
+        }
+        push(&digits[start..]);
+        push(b" on thread ");
+        push(&thread);
+        push(b"\n");
+
+        // SAFETY: `msg` is valid for `len` bytes.
+        unsafe {
+            libc::write(libc::STDERR_FILENO, msg.as_ptr().cast(), len);
+            libc::_exit(SECCOMP_EXIT_CODE);
+        }
+    }
+
+    register_signal_handler(libc::SIGSYS, handle_sigsys)
+        .context("failed to register SIGSYS handler")
+}
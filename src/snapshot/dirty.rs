@@ -0,0 +1,129 @@
+use anyhow::{Context, Result};
+use kvm_ioctls::VmFd;
+use vm_memory::{GuestMemory, GuestMemoryRegion};
+
+use crate::arch::memory::GuestMemoryMmap;
+
+/// Bitmap of the dirtied pages of one guest memory region, one bit per `PAGE_SIZE` page and
+/// laid out like the KVM dirty log.
+#[derive(Debug, Clone, Default)]
+pub struct DirtyBitmap {
+    words: Vec<u64>,
+    pages: usize,
+}
+
+impl DirtyBitmap {
+    fn new(region_size: usize) -> Self {
+        let pages = region_size.div_ceil(crate::arch::PAGE_SIZE);
+
+        DirtyBitmap {
+            words: vec![0; pages.div_ceil(64)],
+            pages,
+        }
+    }
+
+    /// ORs another bitmap with the same layout into this one. Extra trailing words are ignored.
+    fn merge(&mut self, words: &[u64]) {
+        for (word, other) in self.words.iter_mut().zip(words) {
+            *word |= *other;
+        }
+    }
+
+    pub fn is_dirty(&self, page: usize) -> bool {
+        page < self.pages && self.words[page / 64] & (1 << (page % 64)) != 0
+    }
+
+    /// Number of dirty pages.
+    pub fn count(&self) -> usize {
+        self.words.iter().map(|w| w.count_ones() as usize).sum()
+    }
+
+    /// Iterates over runs of consecutive dirty pages as `(first_page, page_count)`.
+    pub fn dirty_runs(&self) -> impl Iterator<Item = (usize, usize)> + '_ {
+        let mut page = 0;
+
+        std::iter::from_fn(move || {
+            while page < self.pages && !self.is_dirty(page) {
+                page += 1;
+            }
+            if page >= self.pages {
+                return None;
+            }
+
+            let start = page;
+            while page < self.pages && self.is_dirty(page) {
+                page += 1;
+            }
+
+            Some((start, page - start))
+        })
+    }
+}
+
+/// Accumulates the guest pages dirtied between two checkpoints.
+///
+/// The guest writes are taken from the KVM dirty log of each memory slot, and the writes done
+/// by the VMM through `GuestMemoryMmap` from the bitmap of each region. Memory slot `n` is the
+/// `n`-th region of the guest memory.
+#[derive(Debug, Default)]
+pub struct DirtyTracker {
+    bitmaps: Vec<DirtyBitmap>,
+}
+
+impl DirtyTracker {
+    pub fn new(guest_mem: &GuestMemoryMmap) -> Self {
+        DirtyTracker {
+            bitmaps: guest_mem
+                .iter()
+                .map(|region| DirtyBitmap::new(region.len() as usize))
+                .collect(),
+        }
+    }
+
+    /// Fetches and clears the KVM and VMM logs, adding them to the pages accumulated since the
+    /// last checkpoint.
+    pub fn sync(&mut self, vm: &VmFd, guest_mem: &GuestMemoryMmap) -> Result<()> {
+        for (slot, (region, bitmap)) in guest_mem.iter().zip(self.bitmaps.iter_mut()).enumerate() {
+            let kvm_log = vm
+                .get_dirty_log(u32::try_from(slot)?, region.len() as usize)
+                .context("failed to get dirty log")?;
+            bitmap.merge(&kvm_log);
+
+            bitmap.merge(&region.bitmap().get_and_reset());
+        }
+
+        Ok(())
+    }
+
+    /// Number of pages dirtied since the last checkpoint, as of the last `sync`.
+    pub fn dirty_pages(&self) -> usize {
+        self.bitmaps.iter().map(DirtyBitmap::count).sum()
+    }
+
+    /// Returns the pages dirtied since the last checkpoint and starts a new one.
+    ///
+    /// The vCPUs should be paused, otherwise pages dirtied while the bitmaps are being
+    /// consumed are only reported at the next checkpoint.
+    pub fn checkpoint(
+        &mut self,
+        vm: &VmFd,
+        guest_mem: &GuestMemoryMmap,
+    ) -> Result<Vec<DirtyBitmap>> {
+        self.sync(vm, guest_mem)?;
+
+        let fresh = guest_mem
+            .iter()
+            .map(|region| DirtyBitmap::new(region.len() as usize))
+            .collect();
+
+        Ok(std::mem::replace(&mut self.bitmaps, fresh))
+    }
+
+    /// Gives back the bitmaps returned by `checkpoint`, e.g. when writing them out failed, so
+    /// that the pages are reported again at the next checkpoint.
+    pub fn rollback(&mut self, bitmaps: Vec<DirtyBitmap>) {
+        for (bitmap, old) in self.bitmaps.iter_mut().zip(bitmaps) {
+            bitmap.merge(&old.words);
+        }
+    }
+}
@@ -0,0 +1,156 @@
+use std::fs::{File, OpenOptions};
+use std::io::{Seek, SeekFrom};
+use std::path::Path;
+
+use anyhow::{Context, Result};
+use vm_memory::{GuestMemory, GuestMemoryRegion, MemoryRegionAddress, ReadVolatile, WriteVolatile};
+
+use crate::arch::memory::{GuestMemoryMmap, GuestRegionMmap};
+use crate::snapshot::{DirtyBitmap, MemoryLayer};
+
+/// Writes the guest memory to `path`. The regions are laid out back to back in the file.
+///
+/// With `dirty` set only those pages are written and the rest of the file is left as holes,
+/// which makes the file a diff layer for the memory file of the previous snapshot. Returns the
+/// `(offset, len)` ranges of the file that were written.
+pub fn save_memory(
+    guest_mem: &GuestMemoryMmap,
+    path: &Path,
+    dirty: Option<&[DirtyBitmap]>,
+) -> Result<Vec<(u64, u64)>> {
+    let mut file = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .open(path)
+        .with_context(|| format!("failed to create memory file {}", path.display()))?;
+
+    let total_size: u64 = guest_mem.iter().map(|region| region.len()).sum();
+    file.set_len(total_size)
+        .context("failed to set memory file size")?;
+
+    let mut written = Vec::new();
+    let mut file_offset = 0;
+    for (index, region) in guest_mem.iter().enumerate() {
+        match dirty {
+            None => {
+                write_range(&mut file, region, file_offset, 0, region.len() as usize)?;
+                written.push((file_offset, region.len()));
+            }
+            Some(bitmaps) => {
+                let bitmap = bitmaps
+                    .get(index)
+                    .ok_or(anyhow::anyhow!("no dirty bitmap for region {}", index))?;
+
+                for (page, count) in bitmap.dirty_runs() {
+                    let offset = (page * crate::arch::PAGE_SIZE) as u64;
+                    let len =
+                        (count * crate::arch::PAGE_SIZE).min((region.len() - offset) as usize);
+
+                    write_range(&mut file, region, file_offset, offset, len)?;
+                    written.push((file_offset + offset, len as u64));
+                }
+            }
+        }
+
+        file_offset += region.len();
+    }
+
+    file.sync_all().context("failed to sync memory file")?;
+
+    Ok(written)
+}
+
+fn write_range<R: GuestMemoryRegion>(
+    file: &mut File,
+    region: &R,
+    file_offset: u64,
+    region_offset: u64,
+    len: usize,
+) -> Result<()> {
+    let slice = region
+        .get_slice(MemoryRegionAddress(region_offset), len)
+        .context("failed to get guest memory slice")?;
+
+    file.seek(SeekFrom::Start(file_offset + region_offset))?;
+    file.write_all_volatile(&slice)
+        .context("failed to write guest memory")?;
+
+    Ok(())
+}
+
+/// Loads a memory file written by `save_memory` into `guest_mem`: all of it for a full snapshot,
+/// or only the ranges a diff snapshot wrote, on top of the memory of its base.
+pub fn restore_memory(guest_mem: &GuestMemoryMmap, path: &Path, layer: &MemoryLayer) -> Result<()> {
+    let mut file = File::open(path)
+        .with_context(|| format!("failed to open memory file {}", path.display()))?;
+
+    let total_size: u64 = guest_mem.iter().map(|region| region.len()).sum();
+    check_file_size(&file, total_size)?;
+
+    let ranges = match layer {
+        MemoryLayer::Full => return read_all(&mut file, guest_mem),
+        MemoryLayer::Diff { ranges, .. } => ranges,
+    };
+
+    for &(offset, len) in ranges.iter() {
+        let (region, region_offset) = region_at(guest_mem, offset)?;
+        let mut slice = usize::try_from(len)
+            .ok()
+            .and_then(|len| {
+                region
+                    .get_slice(MemoryRegionAddress(region_offset), len)
+                    .ok()
+            })
+            .ok_or(anyhow::anyhow!(
+                "memory range {:#x}+{:#x} is past its region",
+                offset,
+                len
+            ))?;
+
+        file.seek(SeekFrom::Start(offset))?;
+        file.read_exact_volatile(&mut slice)
+            .context("failed to read guest memory")?;
+    }
+
+    Ok(())
+}
+
+/// The region at `file_offset` of a memory file, and the offset in that region.
+fn region_at(guest_mem: &GuestMemoryMmap, file_offset: u64) -> Result<(&GuestRegionMmap, u64)> {
+    let mut region_start = 0;
+    for region in guest_mem.iter() {
+        if file_offset < region_start + region.len() {
+            return Ok((region, file_offset - region_start));
+        }
+        region_start += region.len();
+    }
+
+    anyhow::bail!("memory range {:#x} is past the guest memory", file_offset)
+}
+
+fn read_all(file: &mut File, guest_mem: &GuestMemoryMmap) -> Result<()> {
+    for region in guest_mem.iter() {
+        let mut slice = region
+            .get_slice(MemoryRegionAddress(0), region.len() as usize)
+            .context("failed to get guest memory slice")?;
+
+        file.read_exact_volatile(&mut slice)
+            .context("failed to read guest memory")?;
+    }
+
+    Ok(())
+}
+
+pub fn check_file_size(file: &File, expected: u64) -> Result<()> {
+    let size = file.metadata().context("failed to stat memory file")?.len();
+    if size != expected {
+        anyhow::bail!(
+            "memory file has {:#x} bytes, guest memory has {:#x}",
+            size,
+            expected
+        )
+    }
+
+    Ok(())
+}
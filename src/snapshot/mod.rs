@@ -0,0 +1,135 @@
+pub mod dirty;
+pub use dirty::{DirtyBitmap, DirtyTracker};
+
+pub mod memory;
+pub mod persist;
+
+pub mod state;
+pub use state::{CpuModel, MicrovmState};
+
+pub mod uffd;
+
+use std::path::{Path, PathBuf};
+
+use anyhow::Context;
+
+/// Name of the guest memory image inside a snapshot directory.
+pub const MEMORY_FILE_NAME: &str = "memory";
+
+/// Name of the device and vCPU state file inside a snapshot directory.
+pub const VMSTATE_FILE_NAME: &str = "vmstate";
+
+/// Upper bound of the diff snapshots restored on top of a full one.
+const MAX_DIFF_LAYERS: usize = 1024;
+
+/// Kind of snapshot to take.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SnapshotType {
+    /// Every guest page is written.
+    Full,
+    /// Only the pages dirtied since the previous snapshot are written, as a layer to apply on
+    /// top of the memory file of that snapshot.
+    Diff,
+}
+
+impl std::str::FromStr for SnapshotType {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        match s {
+            "full" => Ok(SnapshotType::Full),
+            "diff" => Ok(SnapshotType::Diff),
+            _ => anyhow::bail!("invalid snapshot type: {}, expected full|diff", s),
+        }
+    }
+}
+
+/// A snapshot other snapshots depend on, identified by the random ID it got when written so that
+/// a snapshot overwritten in the same directory is not taken for it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SnapshotRef {
+    pub dir: PathBuf,
+    pub id: u64,
+}
+
+/// What the memory file of a snapshot holds.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub enum MemoryLayer {
+    /// The whole guest memory.
+    #[default]
+    Full,
+    /// Only the `(offset, len)` ranges of the file, the rest of the guest memory is the memory of
+    /// the `base` snapshot.
+    Diff {
+        base: SnapshotRef,
+        ranges: Vec<(u64, u64)>,
+    },
+}
+
+/// The memory files making up the guest memory of the snapshot in `snapshot_dir`, whose state
+/// is `state`: the full snapshot it is based on first, then the diffs to apply in order.
+pub fn memory_layers(
+    snapshot_dir: &Path,
+    state: &MicrovmState,
+) -> anyhow::Result<Vec<(PathBuf, MemoryLayer)>> {
+    let mut layers = vec![(memory_file_path(snapshot_dir), state.memory.clone())];
+
+    while let (_, MemoryLayer::Diff { base, .. }) = &layers[layers.len() - 1] {
+        let base_state = MicrovmState::load_file(&vmstate_file_path(&base.dir))
+            .with_context(|| format!("failed to load base snapshot {}", base.dir.display()))?;
+        if base_state.id != base.id {
+            anyhow::bail!(
+                "base snapshot {} was overwritten since the diff was taken",
+                base.dir.display()
+            )
+        }
+        // Every snapshot gets a new ID, only a corrupted chain loops.
+        if layers.len() > MAX_DIFF_LAYERS {
+            anyhow::bail!(
+                "more than {} diff snapshots on top of each other",
+                MAX_DIFF_LAYERS
+            )
+        }
+
+        layers.push((memory_file_path(&base.dir), base_state.memory));
+    }
+
+    layers.reverse();
+    Ok(layers)
+}
+
+pub fn memory_file_path(snapshot_dir: &Path) -> PathBuf {
+    snapshot_dir.join(MEMORY_FILE_NAME)
+}
+
+pub fn vmstate_file_path(snapshot_dir: &Path) -> PathBuf {
+    snapshot_dir.join(VMSTATE_FILE_NAME)
+}
+
+/// How the guest memory of a snapshot is loaded on restore.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum MemoryRestoreMode {
+    /// The whole memory file is read before the VM starts.
+    #[default]
+    Eager,
+    /// Pages are read from the memory file when the guest first touches them.
+    Lazy,
+    /// Like `Lazy`, with the remaining pages loaded in the background.
+    LazyPrefetch,
+}
+
+impl std::str::FromStr for MemoryRestoreMode {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        match s {
+            "eager" => Ok(MemoryRestoreMode::Eager),
+            "lazy" => Ok(MemoryRestoreMode::Lazy),
+            "lazy-prefetch" => Ok(MemoryRestoreMode::LazyPrefetch),
+            _ => anyhow::bail!(
+                "invalid memory restore mode: {}, expected eager|lazy|lazy-prefetch",
+                s
+            ),
+        }
+    }
+}
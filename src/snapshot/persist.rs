@@ -0,0 +1,146 @@
+use std::io::{Read, Write};
+
+use anyhow::{Context, Result};
+use kvm_bindings::{
+    kvm_clock_data, kvm_debugregs, kvm_fpu, kvm_irqchip, kvm_lapic_state, kvm_mp_state,
+    kvm_msr_entry, kvm_pit_state2, kvm_regs, kvm_sregs, kvm_vcpu_events, kvm_xcrs,
+};
+
+/// Plain old data that can be persisted as its in-memory representation.
+///
+/// # Safety
+///
+/// Implementors must be `repr(C)` types without pointers, for which every bit pattern is valid.
+pub unsafe trait Pod: Copy + Default {}
+
+// SAFETY: bindgen generated C structs made of integers and arrays.
+unsafe impl Pod for kvm_regs {}
+unsafe impl Pod for kvm_sregs {}
+unsafe impl Pod for kvm_fpu {}
+unsafe impl Pod for kvm_xcrs {}
+unsafe impl Pod for kvm_lapic_state {}
+unsafe impl Pod for kvm_mp_state {}
+unsafe impl Pod for kvm_vcpu_events {}
+unsafe impl Pod for kvm_debugregs {}
+unsafe impl Pod for kvm_msr_entry {}
+unsafe impl Pod for kvm_irqchip {}
+unsafe impl Pod for kvm_pit_state2 {}
+unsafe impl Pod for kvm_clock_data {}
+
+/// Serializes state into a little endian, length checked byte stream.
+pub struct StateWriter<W: Write> {
+    inner: W,
+}
+
+impl<W: Write> StateWriter<W> {
+    pub fn new(inner: W) -> Self {
+        StateWriter { inner }
+    }
+
+    pub fn write_u8(&mut self, v: u8) -> Result<()> {
+        self.write_bytes(&[v])
+    }
+
+    pub fn write_u32(&mut self, v: u32) -> Result<()> {
+        self.write_bytes(&v.to_le_bytes())
+    }
+
+    pub fn write_u64(&mut self, v: u64) -> Result<()> {
+        self.write_bytes(&v.to_le_bytes())
+    }
+
+    pub fn write_bytes(&mut self, bytes: &[u8]) -> Result<()> {
+        self.inner.write_all(bytes).context("failed to write state")
+    }
+
+    /// Writes a length prefixed byte vector.
+    pub fn write_vec(&mut self, bytes: &[u8]) -> Result<()> {
+        self.write_u64(bytes.len() as u64)?;
+        self.write_bytes(bytes)
+    }
+
+    /// Writes a size prefixed plain old data value.
+    pub fn write_pod<T: Pod>(&mut self, v: &T) -> Result<()> {
+        // SAFETY: `T` is plain old data, viewing it as bytes is fine.
+        let bytes = unsafe {
+            std::slice::from_raw_parts((v as *const T).cast::<u8>(), std::mem::size_of::<T>())
+        };
+        self.write_vec(bytes)
+    }
+
+    /// Writes a count prefixed list of plain old data values.
+    pub fn write_pod_slice<T: Pod>(&mut self, values: &[T]) -> Result<()> {
+        self.write_u32(u32::try_from(values.len())?)?;
+        values.iter().try_for_each(|v| self.write_pod(v))
+    }
+
+    pub fn flush(&mut self) -> Result<()> {
+        self.inner.flush().context("failed to flush state")
+    }
+}
+
+/// Deserializes state written by `StateWriter`.
+pub struct StateReader<R: Read> {
+    inner: R,
+}
+
+impl<R: Read> StateReader<R> {
+    /// Upper bound of a single length prefixed item, protects against corrupted streams.
+    const MAX_VEC_LEN: u64 = 1 << 24;
+
+    pub fn new(inner: R) -> Self {
+        StateReader { inner }
+    }
+
+    pub fn read_u8(&mut self) -> Result<u8> {
+        let mut buf = [0u8; 1];
+        self.read_bytes(&mut buf)?;
+        Ok(buf[0])
+    }
+
+    pub fn read_u32(&mut self) -> Result<u32> {
+        let mut buf = [0u8; 4];
+        self.read_bytes(&mut buf)?;
+        Ok(u32::from_le_bytes(buf))
+    }
+
+    pub fn read_u64(&mut self) -> Result<u64> {
+        let mut buf = [0u8; 8];
+        self.read_bytes(&mut buf)?;
+        Ok(u64::from_le_bytes(buf))
+    }
+
+    pub fn read_bytes(&mut self, buf: &mut [u8]) -> Result<()> {
+        self.inner.read_exact(buf).context("failed to read state")
+    }
+
+    pub fn read_vec(&mut self) -> Result<Vec<u8>> {
+        let len = self.read_u64()?;
+        if len > Self::MAX_VEC_LEN {
+            anyhow::bail!("state item of {} bytes is too large", len)
+        }
+
+        let mut buf = vec![0u8; len as usize];
+        self.read_bytes(&mut buf)?;
+        Ok(buf)
+    }
+
+    pub fn read_pod<T: Pod>(&mut self) -> Result<T> {
+        let bytes = self.read_vec()?;
+        if bytes.len() != std::mem::size_of::<T>() {
+            anyhow::bail!(
+                "state item size mismatch: expected {}, got {}",
+                std::mem::size_of::<T>(),
+                bytes.len()
+            )
+        }
+
+        // SAFETY: the size matches and any bit pattern is a valid `T`.
+        Ok(unsafe { std::ptr::read_unaligned(bytes.as_ptr().cast::<T>()) })
+    }
+
+    pub fn read_pod_vec<T: Pod>(&mut self) -> Result<Vec<T>> {
+        let count = self.read_u32()?;
+        (0..count).map(|_| self.read_pod()).collect()
+    }
+}
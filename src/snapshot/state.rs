@@ -0,0 +1,256 @@
+use std::ffi::OsString;
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+use vm_superio::serial::SerialState;
+
+use crate::arch::state::{VcpuState, VmState};
+use crate::arch::{CpuTopology, KvmPvFeatures};
+use crate::identity::{MacAddr, VmIdentity};
+use crate::snapshot::persist::{StateReader, StateWriter};
+use crate::snapshot::{MemoryLayer, SnapshotRef};
+
+/// What the guest sees of its vCPUs besides their registers. A VM resuming the guest has to
+/// expose the same, the guest does not expect its CPUs to change under it.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct CpuModel {
+    pub topology: CpuTopology,
+    pub pv_features: KvmPvFeatures,
+    /// TSC frequency of the vCPUs in kHz.
+    pub tsc_khz: u32,
+}
+
+impl CpuModel {
+    pub fn persist<W: Write>(&self, w: &mut StateWriter<W>) -> Result<()> {
+        w.write_bytes(&[
+            self.topology.sockets,
+            self.topology.cores,
+            self.topology.threads,
+        ])?;
+        w.write_u32(self.pv_features.bits())?;
+        w.write_u32(self.tsc_khz)
+    }
+
+    pub fn load<R: Read>(r: &mut StateReader<R>) -> Result<CpuModel> {
+        let mut topology = [0u8; 3];
+        r.read_bytes(&mut topology)?;
+
+        Ok(CpuModel {
+            topology: CpuTopology {
+                sockets: topology[0],
+                cores: topology[1],
+                threads: topology[2],
+            },
+            pv_features: KvmPvFeatures::from_bits(r.read_u32()?)?,
+            tsc_khz: r.read_u32()?,
+        })
+    }
+
+    /// Fails unless the local vCPUs, described by `local`, are the same as these.
+    pub fn check_matches(&self, local: &CpuModel) -> Result<()> {
+        let topology = |t: &CpuTopology| {
+            format!(
+                "sockets={},cores={},threads={}",
+                t.sockets, t.cores, t.threads
+            )
+        };
+        if self.topology != local.topology {
+            anyhow::bail!(
+                "vm has the topology {}, local vcpus have {}",
+                topology(&self.topology),
+                topology(&local.topology)
+            )
+        }
+        if self.pv_features != local.pv_features {
+            anyhow::bail!(
+                "vm has the pv features {}, local vcpus have {}",
+                self.pv_features.enabled_names().join(","),
+                local.pv_features.enabled_names().join(",")
+            )
+        }
+        if self.tsc_khz != local.tsc_khz {
+            anyhow::bail!(
+                "vm has a tsc frequency of {} kHz, local vcpus have {} kHz, see --tsc-khz",
+                self.tsc_khz,
+                local.tsc_khz
+            )
+        }
+
+        Ok(())
+    }
+}
+
+/// Everything but the guest memory needed to resume a paused microvm.
+#[derive(Debug, Default, Clone)]
+pub struct MicrovmState {
+    pub vm: VmState,
+    pub cpu: CpuModel,
+    pub vcpus: Vec<VcpuState>,
+    /// State of the stdio serial port.
+    pub serial: SerialState,
+    pub identity: VmIdentity,
+    /// Random ID of the snapshot the state belongs to, diff snapshots refer to their base by it.
+    pub id: u64,
+    /// What the memory file next to the state holds.
+    pub memory: MemoryLayer,
+}
+
+impl MicrovmState {
+    const MAGIC: u64 = 0x4b56_4d42_4f58_5354; // "KVMBOXST"
+    const VERSION: u32 = 4;
+
+    /// Writes the state into its own file, see `snapshot::vmstate_file_path`.
+    pub fn save_file(&self, path: &Path) -> Result<()> {
+        let file = File::create(path)
+            .with_context(|| format!("failed to create vm state file {}", path.display()))?;
+
+        let mut writer = StateWriter::new(BufWriter::new(file));
+        self.persist(&mut writer)?;
+        writer.flush()
+    }
+
+    pub fn load_file(path: &Path) -> Result<MicrovmState> {
+        let file = File::open(path)
+            .with_context(|| format!("failed to open vm state file {}", path.display()))?;
+
+        MicrovmState::load(&mut StateReader::new(BufReader::new(file)))
+    }
+
+    pub fn persist<W: Write>(&self, w: &mut StateWriter<W>) -> Result<()> {
+        w.write_u64(Self::MAGIC)?;
+        w.write_u32(Self::VERSION)?;
+
+        self.vm.persist(w)?;
+        self.cpu.persist(w)?;
+
+        w.write_u32(u32::try_from(self.vcpus.len())?)?;
+        for vcpu in self.vcpus.iter() {
+            vcpu.persist(w)?;
+        }
+
+        persist_serial(&self.serial, w)?;
+
+        w.write_bytes(&self.identity.mac.0)?;
+        w.write_u32(self.identity.vsock_cid)?;
+
+        w.write_u64(self.id)?;
+        persist_memory_layer(&self.memory, w)
+    }
+
+    pub fn load<R: Read>(r: &mut StateReader<R>) -> Result<MicrovmState> {
+        if r.read_u64()? != Self::MAGIC {
+            anyhow::bail!("not a kvm-box vm state")
+        }
+
+        let version = r.read_u32()?;
+        if version != Self::VERSION {
+            anyhow::bail!("unsupported vm state version {}", version)
+        }
+
+        let vm = VmState::load(r)?;
+        let cpu = CpuModel::load(r)?;
+
+        let vcpu_count = r.read_u32()?;
+        let vcpus = (0..vcpu_count)
+            .map(|_| VcpuState::load(r))
+            .collect::<Result<Vec<_>>>()?;
+
+        let serial = load_serial(r)?;
+
+        let mut mac = [0u8; 6];
+        r.read_bytes(&mut mac)?;
+        let identity = VmIdentity {
+            mac: MacAddr(mac),
+            vsock_cid: r.read_u32()?,
+        };
+
+        let id = r.read_u64()?;
+        let memory = load_memory_layer(r)?;
+
+        Ok(MicrovmState {
+            vm,
+            cpu,
+            vcpus,
+            serial,
+            identity,
+            id,
+            memory,
+        })
+    }
+}
+
+fn persist_serial<W: Write>(state: &SerialState, w: &mut StateWriter<W>) -> Result<()> {
+    w.write_bytes(&[
+        state.baud_divisor_low,
+        state.baud_divisor_high,
+        state.interrupt_enable,
+        state.interrupt_identification,
+        state.line_control,
+        state.line_status,
+        state.modem_control,
+        state.modem_status,
+        state.scratch,
+    ])?;
+    w.write_vec(&state.in_buffer)
+}
+
+fn load_serial<R: Read>(r: &mut StateReader<R>) -> Result<SerialState> {
+    let mut regs = [0u8; 9];
+    r.read_bytes(&mut regs)?;
+
+    Ok(SerialState {
+        baud_divisor_low: regs[0],
+        baud_divisor_high: regs[1],
+        interrupt_enable: regs[2],
+        interrupt_identification: regs[3],
+        line_control: regs[4],
+        line_status: regs[5],
+        modem_control: regs[6],
+        modem_status: regs[7],
+        scratch: regs[8],
+        in_buffer: r.read_vec()?,
+    })
+}
+
+fn persist_memory_layer<W: Write>(layer: &MemoryLayer, w: &mut StateWriter<W>) -> Result<()> {
+    match layer {
+        MemoryLayer::Full => w.write_u8(0),
+        MemoryLayer::Diff { base, ranges } => {
+            w.write_u8(1)?;
+            w.write_vec(base.dir.as_os_str().as_bytes())?;
+            w.write_u64(base.id)?;
+
+            w.write_u64(ranges.len() as u64)?;
+            for &(offset, len) in ranges.iter() {
+                w.write_u64(offset)?;
+                w.write_u64(len)?;
+            }
+
+            Ok(())
+        }
+    }
+}
+
+fn load_memory_layer<R: Read>(r: &mut StateReader<R>) -> Result<MemoryLayer> {
+    match r.read_u8()? {
+        0 => Ok(MemoryLayer::Full),
+        1 => {
+            let base = SnapshotRef {
+                dir: PathBuf::from(OsString::from_vec(r.read_vec()?)),
+                id: r.read_u64()?,
+            };
+
+            let count = r.read_u64()?;
+            let mut ranges = Vec::new();
+            for _ in 0..count {
+                ranges.push((r.read_u64()?, r.read_u64()?));
+            }
+
+            Ok(MemoryLayer::Diff { base, ranges })
+        }
+        kind => anyhow::bail!("unknown memory layer {}", kind),
+    }
+}
@@ -0,0 +1,334 @@
+use std::fs::File;
+use std::os::fd::{AsRawFd, FromRawFd};
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Instant;
+
+use anyhow::{Context, Result};
+use log::{error, info};
+use seccompiler::BpfProgram;
+use vm_memory::mmap::{MmapRegion, MmapRegionBuilder};
+use vm_memory::{FileOffset, GuestMemory, GuestMemoryRegion};
+use vm_superio::Trigger;
+use vmm_sys_util::ioctl::ioctl_with_mut_ref;
+use vmm_sys_util::{ioctl_ioc_nr, ioctl_iowr_nr};
+
+use crate::arch::memory::GuestMemoryMmap;
+use crate::devices::EventFdTrigger;
+use crate::snapshot::memory::check_file_size;
+
+// The userfaultfd uapi, see the kernel's include/uapi/linux/userfaultfd.h.
+const UFFD_API: u64 = 0xaa;
+const UFFDIO: u32 = 0xaa;
+const UFFDIO_REGISTER_MODE_MISSING: u64 = 1;
+const UFFD_EVENT_PAGEFAULT: u8 = 0x12;
+/// Size of `struct uffd_msg`.
+const UFFD_MSG_SIZE: usize = 32;
+
+#[repr(C)]
+#[derive(Default)]
+struct UffdioApi {
+    api: u64,
+    features: u64,
+    ioctls: u64,
+}
+
+#[repr(C)]
+#[derive(Default)]
+struct UffdioRange {
+    start: u64,
+    len: u64,
+}
+
+#[repr(C)]
+#[derive(Default)]
+struct UffdioRegister {
+    range: UffdioRange,
+    mode: u64,
+    ioctls: u64,
+}
+
+#[repr(C)]
+#[derive(Default)]
+struct UffdioCopy {
+    dst: u64,
+    src: u64,
+    len: u64,
+    mode: u64,
+    copy: i64,
+}
+
+ioctl_iowr_nr!(UFFDIO_API, UFFDIO, 0x3f, UffdioApi);
+ioctl_iowr_nr!(UFFDIO_REGISTER, UFFDIO, 0x00, UffdioRegister);
+ioctl_iowr_nr!(UFFDIO_COPY, UFFDIO, 0x03, UffdioCopy);
+
+/// Guest memory region whose missing pages are filled from the memory file.
+#[derive(Debug, Clone, Copy)]
+struct LazyRegion {
+    host_addr: u64,
+    len: u64,
+    file_offset: u64,
+}
+
+/// Serves the guest memory faults of a restored VM from its snapshot memory file.
+#[derive(Debug)]
+struct LazyMemory {
+    uffd: File,
+    snapshot: MmapRegion,
+    regions: Vec<LazyRegion>,
+    page_size: u64,
+}
+
+impl LazyMemory {
+    fn region_of(&self, addr: u64) -> Option<&LazyRegion> {
+        self.regions
+            .iter()
+            .find(|region| addr >= region.host_addr && addr - region.host_addr < region.len)
+    }
+
+    /// Fills the page at `addr` with the snapshot content. A page that got filled in the
+    /// meantime, by the fault handler or the prefetcher, is left alone.
+    fn populate(&self, region: &LazyRegion, addr: u64) -> Result<()> {
+        let page = addr & !(self.page_size - 1);
+        let offset = region.file_offset + (page - region.host_addr);
+
+        let mut copy = UffdioCopy {
+            dst: page,
+            src: self.snapshot.as_ptr() as u64 + offset,
+            len: self.page_size,
+            ..Default::default()
+        };
+
+        loop {
+            // SAFETY: `dst` is a page of a registered guest region and `src` a page of the
+            // snapshot mapping, both stay mapped for the lifetime of `self`.
+            let ret = unsafe { ioctl_with_mut_ref(&self.uffd, UFFDIO_COPY(), &mut copy) };
+            if ret == 0 {
+                return Ok(());
+            }
+
+            match std::io::Error::last_os_error().raw_os_error() {
+                Some(libc::EEXIST) => return Ok(()),
+                Some(libc::EAGAIN) => continue,
+                _ => {
+                    return Err(std::io::Error::last_os_error())
+                        .with_context(|| format!("failed to populate guest page {:#x}", page))
+                }
+            }
+        }
+    }
+
+    fn handle_faults(&self) -> Result<()> {
+        let mut msg = [0u8; UFFD_MSG_SIZE];
+
+        loop {
+            // SAFETY: `msg` is writable and large enough for one message.
+            let ret = unsafe {
+                libc::read(
+                    self.uffd.as_raw_fd(),
+                    msg.as_mut_ptr().cast(),
+                    UFFD_MSG_SIZE,
+                )
+            };
+            if ret < 0 {
+                match std::io::Error::last_os_error().raw_os_error() {
+                    Some(libc::EINTR) | Some(libc::EAGAIN) => continue,
+                    _ => {
+                        return Err(std::io::Error::last_os_error())
+                            .context("failed to read userfaultfd")
+                    }
+                }
+            }
+
+            if msg[0] != UFFD_EVENT_PAGEFAULT {
+                continue;
+            }
+
+            let addr = u64::from_ne_bytes(msg[16..24].try_into().unwrap());
+            let region = self.region_of(addr).ok_or(anyhow::anyhow!(
+                "page fault outside guest memory at {:#x}",
+                addr
+            ))?;
+            self.populate(region, addr)?;
+        }
+    }
+
+    fn prefetch(&self) -> Result<()> {
+        let start = Instant::now();
+
+        for region in self.regions.iter() {
+            for offset in (0..region.len).step_by(self.page_size as usize) {
+                self.populate(region, region.host_addr + offset)?;
+            }
+        }
+
+        info!(
+            "prefetched guest memory in {} ms",
+            start.elapsed().as_millis()
+        );
+
+        Ok(())
+    }
+}
+
+/// Registers the guest memory with userfaultfd so that pages are loaded from the memory file
+/// at `path` on first access, by a dedicated thread. With `prefetch` another thread loads the
+/// remaining pages in the background. `failed_evt` is written if a page cannot be loaded, the
+/// vCPU touching it would wait forever. Both threads install `seccomp_filter` first.
+///
+/// The guest memory must be untouched, and backed by anonymous memory, memfd or hugetlbfs.
+/// `page_size` is the page size of that backing.
+pub fn restore_memory_lazy(
+    guest_mem: &GuestMemoryMmap,
+    path: &Path,
+    page_size: usize,
+    prefetch: bool,
+    failed_evt: EventFdTrigger,
+    seccomp_filter: Option<Arc<BpfProgram>>,
+) -> Result<()> {
+    let file = File::open(path)
+        .with_context(|| format!("failed to open memory file {}", path.display()))?;
+    let total_size: u64 = guest_mem.iter().map(|region| region.len()).sum();
+    check_file_size(&file, total_size)?;
+
+    let snapshot = MmapRegionBuilder::new(usize::try_from(total_size)?)
+        .with_file_offset(FileOffset::new(file, 0))
+        .with_mmap_prot(libc::PROT_READ)
+        .with_mmap_flags(libc::MAP_PRIVATE | libc::MAP_NORESERVE)
+        .build()
+        .context("failed to mmap memory file")?;
+
+    let uffd = create_userfaultfd()?;
+
+    let mut regions = Vec::new();
+    let mut file_offset = 0;
+    for region in guest_mem.iter() {
+        let lazy_region = LazyRegion {
+            host_addr: region.as_ptr() as u64,
+            len: region.len(),
+            file_offset,
+        };
+        check_untouched(&lazy_region)?;
+        register(&uffd, &lazy_region)?;
+
+        regions.push(lazy_region);
+        file_offset += region.len();
+    }
+
+    let lazy_memory = Arc::new(LazyMemory {
+        uffd,
+        snapshot,
+        regions,
+        page_size: page_size as u64,
+    });
+
+    let handler = lazy_memory.clone();
+    let handler_filter = seccomp_filter.clone();
+    std::thread::Builder::new()
+        .name(String::from("uffd"))
+        .spawn(move || {
+            let handled = handler_filter
+                .as_ref()
+                .map_or(Ok(()), |filter| crate::seccomp::apply(filter, "uffd"))
+                .and_then(|_| handler.handle_faults());
+            if let Err(e) = handled {
+                error!("guest memory fault handler failed: {:?}", e);
+                failed_evt.trigger().expect("failed to write to failed_evt");
+            }
+        })
+        .context("failed to spawn userfaultfd thread")?;
+
+    if prefetch {
+        std::thread::Builder::new()
+            .name(String::from("uffd-prefetch"))
+            .spawn(move || {
+                let prefetched = seccomp_filter
+                    .as_ref()
+                    .map_or(Ok(()), |filter| {
+                        crate::seccomp::apply(filter, "uffd-prefetch")
+                    })
+                    .and_then(|_| lazy_memory.prefetch());
+                if let Err(e) = prefetched {
+                    error!("guest memory prefetch failed: {:?}", e);
+                }
+            })
+            .context("failed to spawn prefetch thread")?;
+    }
+
+    info!(
+        "guest memory is loaded on demand from {}, prefetch {}",
+        path.display(),
+        prefetch
+    );
+
+    Ok(())
+}
+
+fn create_userfaultfd() -> Result<File> {
+    // SAFETY: no pointers are passed, the returned fd is checked.
+    let fd = unsafe { libc::syscall(libc::SYS_userfaultfd, libc::O_CLOEXEC) };
+    if fd < 0 {
+        return Err(std::io::Error::last_os_error()).context("failed to create userfaultfd");
+    }
+
+    // SAFETY: `fd` is a freshly created descriptor that nothing else owns.
+    let uffd = unsafe { File::from_raw_fd(fd as i32) };
+
+    let mut api = UffdioApi {
+        api: UFFD_API,
+        ..Default::default()
+    };
+    // SAFETY: `api` matches the layout expected by UFFDIO_API.
+    if unsafe { ioctl_with_mut_ref(&uffd, UFFDIO_API(), &mut api) } < 0 {
+        return Err(std::io::Error::last_os_error()).context("failed to enable userfaultfd api");
+    }
+
+    Ok(uffd)
+}
+
+/// Fails if a page of `region` is present already: userfaultfd only reports the faults on
+/// missing pages, the guest would see that page instead of the one of the snapshot.
+fn check_untouched(region: &LazyRegion) -> Result<()> {
+    let mut present = vec![0u8; usize::try_from(region.len)?.div_ceil(crate::arch::PAGE_SIZE)];
+
+    // SAFETY: the range is mapped by a guest memory region and `present` has a byte per page.
+    let ret = unsafe {
+        libc::mincore(
+            region.host_addr as *mut libc::c_void,
+            region.len as usize,
+            present.as_mut_ptr(),
+        )
+    };
+    if ret < 0 {
+        return Err(std::io::Error::last_os_error()).context("failed to check guest memory pages");
+    }
+
+    let count = present.iter().filter(|&&page| page & 1 != 0).count();
+    if count > 0 {
+        anyhow::bail!(
+            "{} guest pages are present before the lazy restore, they would not be loaded",
+            count
+        )
+    }
+
+    Ok(())
+}
+
+fn register(uffd: &File, region: &LazyRegion) -> Result<()> {
+    let mut register = UffdioRegister {
+        range: UffdioRange {
+            start: region.host_addr,
+            len: region.len,
+        },
+        mode: UFFDIO_REGISTER_MODE_MISSING,
+        ..Default::default()
+    };
+
+    // SAFETY: `register` matches the layout expected by UFFDIO_REGISTER.
+    if unsafe { ioctl_with_mut_ref(uffd, UFFDIO_REGISTER(), &mut register) } < 0 {
+        return Err(std::io::Error::last_os_error())
+            .context("failed to register guest memory with userfaultfd");
+    }
+
+    Ok(())
+}
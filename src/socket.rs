@@ -0,0 +1,165 @@
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::os::fd::{AsRawFd, RawFd};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::PathBuf;
+
+use anyhow::{Context, Result};
+use log::info;
+
+/// Address of a stream socket, `unix:PATH` or `tcp:HOST:PORT`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SocketUrl {
+    Unix(PathBuf),
+    /// Resolved when parsed, the seccomp filters do not allow name lookups later on.
+    Tcp(SocketAddr),
+}
+
+impl std::str::FromStr for SocketUrl {
+    type Err = anyhow::Error;
+
+    /// Resolves the host name of a tcp address, to be done before the seccomp filters are
+    /// installed.
+    fn from_str(s: &str) -> Result<Self> {
+        SocketUrl::parse(s, |addr| {
+            addr.to_socket_addrs()
+                .with_context(|| format!("failed to resolve {}", addr))?
+                .next()
+                .ok_or(anyhow::anyhow!("{} has no address", addr))
+        })
+    }
+}
+
+impl std::fmt::Display for SocketUrl {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SocketUrl::Unix(path) => write!(f, "unix:{}", path.display()),
+            SocketUrl::Tcp(addr) => write!(f, "tcp:{}", addr),
+        }
+    }
+}
+
+impl SocketUrl {
+    /// Parses like `from_str` without resolving host names, tcp addresses take an IP address.
+    /// For the requests handled under the seccomp filters.
+    pub fn parse_numeric(s: &str) -> Result<SocketUrl> {
+        SocketUrl::parse(s, |addr| {
+            addr.parse().with_context(|| {
+                format!(
+                    "invalid address {}, expected IP:PORT, names are not resolved",
+                    addr
+                )
+            })
+        })
+    }
+
+    fn parse<F>(s: &str, resolve: F) -> Result<SocketUrl>
+    where
+        F: FnOnce(&str) -> Result<SocketAddr>,
+    {
+        match s.split_once(':') {
+            Some(("unix", path)) if !path.is_empty() => Ok(SocketUrl::Unix(path.into())),
+            Some(("tcp", addr)) if addr.contains(':') => Ok(SocketUrl::Tcp(resolve(addr)?)),
+            _ => anyhow::bail!(
+                "invalid socket address: {}, expected unix:PATH or tcp:HOST:PORT",
+                s
+            ),
+        }
+    }
+
+    /// Connects to a peer waiting in `accept`.
+    pub fn connect(&self) -> Result<SocketStream> {
+        let stream = match self {
+            SocketUrl::Unix(path) => {
+                SocketStream::Unix(UnixStream::connect(path).context("failed to connect socket")?)
+            }
+            SocketUrl::Tcp(addr) => {
+                SocketStream::Tcp(TcpStream::connect(addr).context("failed to connect socket")?)
+            }
+        };
+
+        info!("connected to {}", self);
+        Ok(stream)
+    }
+
+    /// Listens on the address until one peer connects. A unix socket file is removed again
+    /// once the peer is connected.
+    pub fn accept(&self) -> Result<SocketStream> {
+        info!("waiting for a connection on {}", self);
+
+        let stream = match self {
+            SocketUrl::Unix(path) => {
+                if path.exists() {
+                    std::fs::remove_file(path).context("failed to remove stale socket")?;
+                }
+
+                let listener = UnixListener::bind(path).context("failed to bind socket")?;
+                let (stream, _) = listener.accept().context("failed to accept connection")?;
+                let _ = std::fs::remove_file(path);
+
+                SocketStream::Unix(stream)
+            }
+            SocketUrl::Tcp(addr) => {
+                let listener = TcpListener::bind(addr).context("failed to bind socket")?;
+                let (stream, peer) = listener.accept().context("failed to accept connection")?;
+                info!("connection from {}", peer);
+
+                SocketStream::Tcp(stream)
+            }
+        };
+
+        Ok(stream)
+    }
+}
+
+/// Connected stream socket.
+#[derive(Debug)]
+pub enum SocketStream {
+    Unix(UnixStream),
+    Tcp(TcpStream),
+}
+
+impl SocketStream {
+    pub fn try_clone(&self) -> Result<SocketStream> {
+        let stream = match self {
+            SocketStream::Unix(s) => SocketStream::Unix(s.try_clone()?),
+            SocketStream::Tcp(s) => SocketStream::Tcp(s.try_clone()?),
+        };
+
+        Ok(stream)
+    }
+}
+
+impl Read for SocketStream {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        match self {
+            SocketStream::Unix(s) => s.read(buf),
+            SocketStream::Tcp(s) => s.read(buf),
+        }
+    }
+}
+
+impl Write for SocketStream {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        match self {
+            SocketStream::Unix(s) => s.write(buf),
+            SocketStream::Tcp(s) => s.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        match self {
+            SocketStream::Unix(s) => s.flush(),
+            SocketStream::Tcp(s) => s.flush(),
+        }
+    }
+}
+
+impl AsRawFd for SocketStream {
+    fn as_raw_fd(&self) -> RawFd {
+        match self {
+            SocketStream::Unix(s) => s.as_raw_fd(),
+            SocketStream::Tcp(s) => s.as_raw_fd(),
+        }
+    }
+}
@@ -0,0 +1,176 @@
+use std::fs::File;
+use std::os::unix::fs::FileExt;
+use std::path::Path;
+
+use anyhow::{Context, Result};
+
+// ELF64 layout, see the System V ABI and the kernel's include/uapi/linux/elf.h.
+const ELFMAG: &[u8; 4] = b"\x7fELF";
+const ELFCLASS64: u8 = 2;
+const EHDR_SIZE: usize = 64;
+const SHDR_SIZE: usize = 64;
+const SYM_SIZE: usize = 24;
+const SHT_SYMTAB: u32 = 2;
+const SHN_UNDEF: u16 = 0;
+const SHN_ABS: u16 = 0xfff1;
+const STT_NOTYPE: u8 = 0;
+const STT_FUNC: u8 = 2;
+
+/// Symbols without a size, mostly labels in assembly code, extend up to the next symbol when
+/// it is at most this far. Further away, the addresses in between are not theirs to claim.
+const MAX_UNSIZED_SPAN: u64 = 0x1000;
+
+#[derive(Debug)]
+struct Symbol {
+    addr: u64,
+    size: u64,
+    name: String,
+}
+
+/// Code symbols of the guest kernel, used to make addresses in crash reports readable.
+#[derive(Debug, Default)]
+pub struct KernelSymbols {
+    /// Sorted by address.
+    symbols: Vec<Symbol>,
+}
+
+impl KernelSymbols {
+    /// Reads the symbol table of the ELF kernel image at `path`. A stripped image gives an
+    /// empty table.
+    pub fn load(path: &Path) -> Result<KernelSymbols> {
+        let file = File::open(path)
+            .with_context(|| format!("failed to open kernel image {}", path.display()))?;
+
+        let file_len = file
+            .metadata()
+            .context("failed to stat kernel image")?
+            .len();
+
+        let ehdr = read_at(&file, file_len, 0, EHDR_SIZE as u64)?;
+        if &ehdr[0..4] != ELFMAG || ehdr[4] != ELFCLASS64 {
+            anyhow::bail!("{} is not an ELF64 image", path.display())
+        }
+
+        let shoff = le(&ehdr, 40, 8);
+        let shnum = le(&ehdr, 60, 2) as usize;
+        let shdrs = read_at(&file, file_len, shoff, (shnum * SHDR_SIZE) as u64)?;
+        let shdr = |index: usize| &shdrs[index * SHDR_SIZE..(index + 1) * SHDR_SIZE];
+
+        let mut symbols = Vec::new();
+        for index in 0..shnum {
+            let symtab = shdr(index);
+            if le(symtab, 4, 4) as u32 != SHT_SYMTAB {
+                continue;
+            }
+
+            let strtab_index = le(symtab, 40, 4) as usize;
+            if strtab_index >= shnum {
+                anyhow::bail!("symbol table links to missing section {}", strtab_index)
+            }
+            let strtab = shdr(strtab_index);
+            let strings = read_at(&file, file_len, le(strtab, 24, 8), le(strtab, 32, 8))?;
+            let entries = read_at(&file, file_len, le(symtab, 24, 8), le(symtab, 32, 8))?;
+
+            for sym in entries.chunks_exact(SYM_SIZE) {
+                let kind = sym[4] & 0xf;
+                let shndx = le(sym, 6, 2) as u16;
+                let addr = le(sym, 8, 8);
+                if !matches!(kind, STT_NOTYPE | STT_FUNC)
+                    || shndx == SHN_UNDEF
+                    || shndx == SHN_ABS
+                    || addr == 0
+                {
+                    continue;
+                }
+
+                let name = symbol_name(&strings, le(sym, 0, 4) as usize);
+                if name.is_empty() {
+                    continue;
+                }
+
+                symbols.push(Symbol {
+                    addr,
+                    size: le(sym, 16, 8),
+                    name,
+                });
+            }
+        }
+
+        symbols.sort_by_key(|symbol| symbol.addr);
+        size_unsized(&mut symbols);
+
+        Ok(KernelSymbols { symbols })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.symbols.is_empty()
+    }
+
+    /// Formats `addr` as `name+offset/size`, the way the kernel prints code addresses.
+    /// Addresses past the end of the closest symbol below them give `None`.
+    pub fn symbolize(&self, addr: u64) -> Option<String> {
+        let index = self.symbols.partition_point(|symbol| symbol.addr <= addr);
+        let symbol = &self.symbols[index.checked_sub(1)?];
+
+        let offset = addr - symbol.addr;
+        if offset >= symbol.size {
+            return None;
+        }
+
+        Some(format!("{}+{:#x}/{:#x}", symbol.name, offset, symbol.size))
+    }
+}
+
+/// Sizes the symbols without one up to the next symbol, and drops those too far from it.
+/// `symbols` is sorted by address.
+fn size_unsized(symbols: &mut Vec<Symbol>) {
+    for index in 0..symbols.len() {
+        if symbols[index].size != 0 {
+            continue;
+        }
+
+        let addr = symbols[index].addr;
+        let next = symbols[index + 1..]
+            .iter()
+            .map(|symbol| symbol.addr)
+            .find(|&next| next > addr);
+        if let Some(next) = next.filter(|next| next - addr <= MAX_UNSIZED_SPAN) {
+            symbols[index].size = next - addr;
+        }
+    }
+
+    symbols.retain(|symbol| symbol.size != 0);
+}
+
+/// Reads `len` bytes at `offset`, both taken from the image headers, so they are checked
+/// against the `file_len` of the image before anything is allocated.
+fn read_at(file: &File, file_len: u64, offset: u64, len: u64) -> Result<Vec<u8>> {
+    if offset.checked_add(len).is_none_or(|end| end > file_len) {
+        anyhow::bail!(
+            "kernel image range {:#x}+{:#x} is past its end {:#x}",
+            offset,
+            len,
+            file_len
+        )
+    }
+
+    let mut buf = vec![0u8; usize::try_from(len)?];
+    file.read_exact_at(&mut buf, offset)
+        .context("failed to read kernel image")?;
+
+    Ok(buf)
+}
+
+fn le(data: &[u8], offset: usize, len: usize) -> u64 {
+    data[offset..offset + len]
+        .iter()
+        .rev()
+        .fold(0, |value, &byte| (value << 8) | u64::from(byte))
+}
+
+fn symbol_name(strings: &[u8], offset: usize) -> String {
+    let name = strings.get(offset..).unwrap_or_default();
+    let end = name.iter().position(|&c| c == 0).unwrap_or(name.len());
+
+    String::from_utf8_lossy(&name[..end]).into_owned()
+}
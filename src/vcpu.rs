@@ -0,0 +1,124 @@
+use std::sync::{Condvar, Mutex};
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use vmm_sys_util::signal::{register_signal_handler, SIGRTMIN};
+
+/// Signal used to kick vCPU threads out of KVM_RUN.
+pub fn vcpu_kick_signal() -> libc::c_int {
+    SIGRTMIN()
+}
+
+/// Installs a no-op handler for the kick signal. Interrupting the KVM_RUN ioctl with EINTR is
+/// all we need from it.
+pub fn register_kick_signal_handler() -> Result<()> {
+    extern "C" fn handle_signal(_: libc::c_int, _: *mut libc::siginfo_t, _: *mut libc::c_void) {}
+
+    register_signal_handler(vcpu_kick_signal(), handle_signal)
+        .context("failed to register vcpu kick signal handler")
+}
+
+/// Interrupts the given vCPU threads out of KVM_RUN.
+pub fn kick_threads(threads: &[libc::pthread_t]) {
+    for &thread in threads {
+        // SAFETY: the threads are owned by join handles that are never dropped, the ids stay
+        // valid even after the threads exit.
+        unsafe { libc::pthread_kill(thread, vcpu_kick_signal()) };
+    }
+}
+
+/// Who asked the vCPUs to stay parked. Each holder releases only its own hold, the vCPUs run
+/// again once none is left.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PauseHolder {
+    /// The VMM: API pauses, snapshots, migration and guest crashes.
+    Vmm,
+    /// The GDB server while the debugger has the VM stopped.
+    Debugger,
+}
+
+impl PauseHolder {
+    fn bit(self) -> u8 {
+        match self {
+            PauseHolder::Vmm => 1 << 0,
+            PauseHolder::Debugger => 1 << 1,
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct PauseState {
+    /// `PauseHolder` bits of the holders asking the vCPUs to stay out of KVM_RUN.
+    holders: u8,
+    /// Number of vCPU threads currently parked.
+    parked: usize,
+    /// Number of vCPU threads still running their loop.
+    running: usize,
+}
+
+/// Rendezvous point used to stop all vCPU threads outside of KVM_RUN and to let them go again.
+#[derive(Debug, Default)]
+pub struct VcpuPause {
+    state: Mutex<PauseState>,
+    cond: Condvar,
+}
+
+impl VcpuPause {
+    /// Registers a vCPU thread that is about to enter its run loop.
+    pub fn vcpu_started(&self) {
+        self.state.lock().expect("Poisoned lock").running += 1;
+    }
+
+    /// Unregisters a vCPU thread that left its run loop.
+    pub fn vcpu_stopped(&self) {
+        self.state.lock().expect("Poisoned lock").running -= 1;
+        self.cond.notify_all();
+    }
+
+    /// Called by a vCPU thread between two KVM_RUN calls, blocks while any holder asks for a
+    /// pause.
+    pub fn park_if_requested(&self) {
+        let mut state = self.state.lock().expect("Poisoned lock");
+        if state.holders == 0 {
+            return;
+        }
+
+        state.parked += 1;
+        self.cond.notify_all();
+
+        while state.holders != 0 {
+            state = self.cond.wait(state).expect("Poisoned lock");
+        }
+
+        state.parked -= 1;
+    }
+
+    /// Asks all vCPU threads to park at their next chance on behalf of `holder`, without
+    /// waiting for them.
+    pub fn request(&self, holder: PauseHolder) {
+        self.state.lock().expect("Poisoned lock").holders |= holder.bit();
+    }
+
+    /// Asks all vCPU threads to park on behalf of `holder` and waits until they did. `kick` is
+    /// called repeatedly to interrupt threads that are still inside KVM_RUN.
+    pub fn pause<F: Fn()>(&self, holder: PauseHolder, kick: F) {
+        let mut state = self.state.lock().expect("Poisoned lock");
+        state.holders |= holder.bit();
+
+        while state.parked < state.running {
+            kick();
+
+            state = self
+                .cond
+                .wait_timeout(state, Duration::from_millis(10))
+                .expect("Poisoned lock")
+                .0;
+        }
+    }
+
+    /// Releases the hold of `holder`, the vCPU threads run again if nobody else holds them.
+    pub fn resume(&self, holder: PauseHolder) {
+        self.state.lock().expect("Poisoned lock").holders &= !holder.bit();
+        self.cond.notify_all();
+    }
+}
@@ -1,58 +1,247 @@
+use std::os::unix::thread::JoinHandleExt;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
 use anyhow::{Context, Result};
+use kvm_bindings::{kvm_clock_data, KVM_SYSTEM_EVENT_CRASH};
 use kvm_ioctls::{Kvm, VcpuExit, VcpuFd, VmFd};
-use log::{error, info};
-use vm_memory::GuestMemoryMmap;
-use vm_superio::Trigger;
+use log::{error, info, warn};
+use seccompiler::BpfProgram;
+use vm_superio::{Serial, Trigger};
+use vmm_sys_util::signal::Killable;
 use vmm_sys_util::{poll::PollContext, terminal::Terminal};
 
-use crate::devices::{setup_serial_device, Bus, EventFdTrigger, PortIODeviceManager};
+use crate::api::{ApiRequest, ApiServer};
+use crate::arch::gdb::DebugExit;
+use crate::arch::irq::GsiRouting;
+use crate::arch::memory::GuestMemoryMmap;
+use crate::arch::state::{VcpuState, VmState};
+use crate::arch::{IrqChipMode, MemoryConfig, VcpuConfig};
+use crate::cgroup::ThreadCgroup;
+use crate::devices::ioapic::{IOAPIC_EOI, IOAPIC_MMIO_SIZE};
+use crate::devices::pvpanic::{PVPANIC_CRASH_LOADED, PVPANIC_PANICKED};
+use crate::devices::{
+    setup_serial_device, BootTimes, Bus, BusDevice, EventFdTrigger, IdentityDevice, IoApic,
+    PortIODeviceManager, SerialEventsWrapper, SerialOut,
+};
+use crate::gdb::GdbStub;
+use crate::identity::VmIdentity;
+use crate::io_trace::{IoAccess, UnhandledIo};
+use crate::metrics::{ExitReason, Metrics, MetricsSink};
+use crate::migration::{MigrationReceiver, MigrationSender};
+use crate::sched::SchedConfig;
+use crate::seccomp::{SeccompFilters, SeccompLevel};
+use crate::snapshot::{
+    CpuModel, DirtyBitmap, DirtyTracker, MemoryLayer, MemoryRestoreMode, MicrovmState, SnapshotRef,
+    SnapshotType,
+};
+use crate::socket::SocketUrl;
+use crate::symbols::KernelSymbols;
+use crate::vcpu::{PauseHolder, VcpuPause};
 
 pub struct Vmm {
     pub kvm: Kvm,
-    pub vm: VmFd,
+    pub vm: Arc<VmFd>,
     pub guest_mem: GuestMemoryMmap,
-    pub vcpu: Option<VcpuFd>,
+    pub vcpu_config: VcpuConfig,
+    pub vcpus: Vec<Arc<VcpuFd>>,
+    // TSC frequency the vCPUs were set up with, the host one unless configured.
+    tsc_khz: u32,
     pub pio_device_manager: Option<PortIODeviceManager>,
+    // Where the interrupt controllers are emulated.
+    irqchip_mode: IrqChipMode,
+    // The userspace IOAPIC with the split irqchip, empty otherwise.
+    mmio_bus: Arc<Bus>,
+    // BusDevice::IoApic of the split irqchip, the event loop drives its pins.
+    ioapic: Option<Arc<BusDevice>>,
+    vcpu_handles: Vec<JoinHandle<()>>,
+    vcpu_pause: Arc<VcpuPause>,
+    // kvmclock saved when the VM got paused.
+    paused_clock: Option<kvm_clock_data>,
+    dirty_tracker: DirtyTracker,
+    // Also exposed to the guest by the identity device, changed through `set_identity`.
+    identity: VmIdentity,
+    // Page size of the guest memory backing.
+    page_size: usize,
+    // Whether the guest memory started out zeroed, an incoming migration then skips zero pages.
+    memory_zeroed: bool,
+    // The last snapshot taken or restored, the base of the next diff snapshot.
+    last_snapshot: Option<SnapshotRef>,
+    // Set once the VM got migrated away, which stops the main loop.
+    migrated: bool,
+    // Symbols of the booted kernel, for crash reports.
+    kernel_symbols: Option<Arc<KernelSymbols>>,
+    // Written by vCPU threads when the guest reports a crash.
+    crash_evt: EventFdTrigger,
+    // Written when the guest memory of a lazy restore cannot be loaded anymore.
+    memory_failed_evt: EventFdTrigger,
+    /// Where the guest core is dumped when it crashes.
+    pub coredump_path: Option<PathBuf>,
+    /// Keep a panicked guest paused for inspection instead of stopping the VM.
+    pub pause_on_panic: bool,
+    /// Where the exit metrics are reported, and how often.
+    pub metrics_sink: Option<MetricsSink>,
+    pub metrics_interval: Duration,
+    /// How the syscall allowlists of the vCPU and event loop threads are enforced.
+    pub seccomp_level: SeccompLevel,
+    // Compiled from `seccomp_level` when the first thread needs them.
+    seccomp_filters: Option<SeccompFilters>,
+    /// Where the vCPU threads account their CPU time, when the VM has a cgroup.
+    pub vcpu_cgroup: Option<Arc<ThreadCgroup>>,
+    /// Host CPUs and scheduling policy of the vCPU and event loop threads.
+    pub sched_config: SchedConfig,
+    // Boot milestones, timestamped by the vCPU threads and the boot timer device.
+    boot_times: Arc<BootTimes>,
+    /// Log the port and MMIO accesses no device handles.
+    pub trace_unhandled_io: bool,
+}
+
+/// Why the main loop of a VM returned.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VmExit {
+    /// The vCPUs stopped.
+    Stopped,
+    /// The VM now runs in the migration destination.
+    Migrated,
+    /// The guest kernel panicked or reported a crash.
+    GuestPanic,
+}
+
+impl VmExit {
+    /// Process exit status, a guest panic is told apart from a normal stop or reboot.
+    pub fn exit_code(self) -> i32 {
+        match self {
+            VmExit::Stopped | VmExit::Migrated => 0,
+            VmExit::GuestPanic => 2,
+        }
+    }
 }
 
+/// Pre-copy stops iterating once fewer pages than this got dirtied during one pass.
+const MIGRATION_DIRTY_THRESHOLD: usize = 1024;
+/// How often metrics are reported unless configured otherwise.
+pub const DEFAULT_METRICS_INTERVAL: Duration = Duration::from_secs(1);
+/// Upper bound of pre-copy passes, for guests dirtying memory faster than it can be sent.
+const MIGRATION_MAX_PASSES: usize = 10;
+/// Event loop token of the first device interrupt of the split irqchip, the others follow.
+const IRQ_LINE_TOKEN: u8 = 6;
+/// Event loop token of the first doorbell, the others follow.
+const DOORBELL_TOKEN: u8 = 9;
+
 impl Vmm {
-    pub fn new(ram_size: u64) -> Result<Vmm> {
+    /// `start_time` is when the process started, boot times are measured from it.
+    pub fn new(
+        mem_config: MemoryConfig,
+        vcpu_config: VcpuConfig,
+        irqchip_mode: IrqChipMode,
+        start_time: Instant,
+    ) -> Result<Vmm> {
         let kvm = Kvm::new().context("failed to create kvm")?;
-        let vm = kvm.create_vm().context("failed to create vm")?;
+        let vm = Arc::new(kvm.create_vm().context("failed to create vm")?);
 
-        crate::arch::irq::init_irqchip(&vm).context("failed to init irq chip")?;
+        crate::arch::irq::init_irqchip(&vm, irqchip_mode).context("failed to init irq chip")?;
 
-        let guest_mem = crate::arch::memory::create_guest_memory(&vm, ram_size)?;
+        let guest_mem = crate::arch::memory::create_guest_memory(&vm, &mem_config)?;
+        let dirty_tracker = DirtyTracker::new(&guest_mem);
+        let page_size = mem_config
+            .hugepages
+            .map_or(crate::arch::PAGE_SIZE, |hugepages| hugepages.bytes());
 
         Ok(Vmm {
             kvm,
             vm,
             guest_mem,
-            vcpu: None,
+            vcpu_config,
+            vcpus: Vec::new(),
+            tsc_khz: 0,
             pio_device_manager: None,
+            irqchip_mode,
+            mmio_bus: Arc::new(Bus::new()),
+            ioapic: None,
+            vcpu_handles: Vec::new(),
+            vcpu_pause: Arc::new(VcpuPause::default()),
+            paused_clock: None,
+            dirty_tracker,
+            identity: VmIdentity::random()?,
+            page_size,
+            memory_zeroed: mem_config.backend.starts_zeroed(),
+            last_snapshot: None,
+            migrated: false,
+            kernel_symbols: None,
+            crash_evt: EventFdTrigger::new(),
+            memory_failed_evt: EventFdTrigger::new(),
+            coredump_path: None,
+            pause_on_panic: false,
+            metrics_sink: None,
+            metrics_interval: DEFAULT_METRICS_INTERVAL,
+            seccomp_level: SeccompLevel::default(),
+            seccomp_filters: None,
+            vcpu_cgroup: None,
+            sched_config: SchedConfig::default(),
+            boot_times: Arc::new(BootTimes::new(start_time)),
+            trace_unhandled_io: false,
         })
     }
 
     pub fn init(&mut self) -> Result<()> {
-        let vcpu = self.vm.create_vcpu(0).context("failed to create vcpu")?;
+        let topology = &self.vcpu_config.topology;
 
-        crate::arch::vcpu::init_cpu_id(&self.kvm, &vcpu)?;
+        for cpu_index in 0..topology.vcpu_count() {
+            let vcpu = self
+                .vm
+                .create_vcpu(u64::from(topology.apic_id(cpu_index)))
+                .context("failed to create vcpu")?;
 
-        // TODO: init msrs
+            let tsc_khz = crate::arch::clock::init_tsc(&vcpu, self.vcpu_config.tsc_khz)?;
+            if cpu_index == 0 {
+                info!("guest tsc frequency: {} kHz", tsc_khz);
+                self.tsc_khz = tsc_khz;
+            }
+
+            crate::arch::vcpu::init_cpu_id(
+                &self.kvm,
+                &vcpu,
+                cpu_index,
+                &self.vcpu_config,
+                tsc_khz,
+            )?;
 
-        crate::arch::regs::init_regs(&vcpu, crate::arch::layout::KERNEL_START_ADDRESS)?;
-        crate::arch::regs::init_fpu(&vcpu)?;
-        crate::arch::regs::init_sregs(&self.guest_mem, &vcpu)?;
+            // TODO: init msrs
 
-        self.vcpu = Some(vcpu);
+            crate::arch::regs::init_regs(&vcpu, crate::arch::layout::KERNEL_START_ADDRESS)?;
+            crate::arch::regs::init_fpu(&vcpu)?;
+            crate::arch::regs::init_sregs(&vcpu)?;
+
+            self.vcpus.push(Arc::new(vcpu));
+        }
 
         Ok(())
     }
 
-    pub fn load_image(&self, boot_source_cfg: &crate::arch::BootSourceConfig) -> Result<()> {
+    /// What the guest sees of the vCPUs besides their registers.
+    fn cpu_model(&self) -> CpuModel {
+        CpuModel {
+            topology: self.vcpu_config.topology,
+            pv_features: self.vcpu_config.pv_features,
+            tsc_khz: self.tsc_khz,
+        }
+    }
+
+    /// Loads the kernel, initrd and boot tables of a booting guest. Restored and cloned guests
+    /// keep the memory of their snapshot, nothing is written to it before.
+    pub fn load_image(&mut self, boot_source_cfg: &crate::arch::BootSourceConfig) -> Result<()> {
+        crate::arch::regs::setup_boot_tables(&self.guest_mem)?;
         crate::arch::system::load_kernel(&boot_source_cfg.kernel_image_path, &self.guest_mem)
             .context("failed to load kernel")?;
 
+        match KernelSymbols::load(Path::new(&boot_source_cfg.kernel_image_path)) {
+            Ok(symbols) if symbols.is_empty() => info!("kernel has no symbols"),
+            Ok(symbols) => self.kernel_symbols = Some(Arc::new(symbols)),
+            Err(e) => warn!("failed to load kernel symbols: {:?}", e),
+        }
+
         let initrd = match &boot_source_cfg.initrd_path {
             Some(p) => Some(
                 crate::arch::system::load_initrd(p, &self.guest_mem)
@@ -61,26 +250,130 @@ impl Vmm {
             None => None,
         };
 
-        let (cmdline_addr, cmdline_size) =
-            crate::arch::system::load_boot_cmdline(&self.guest_mem, &boot_source_cfg)
-                .context("failed to load boot cmdline")?;
+        let (cmdline_addr, cmdline_size) = crate::arch::system::load_boot_cmdline(
+            &self.guest_mem,
+            boot_source_cfg,
+            self.irqchip_mode,
+        )
+        .context("failed to load boot cmdline")?;
 
         crate::arch::system::configure_system(
             &self.guest_mem,
             cmdline_addr,
             cmdline_size,
             &initrd,
+            &self.vcpu_config.topology,
         )?;
 
         Ok(())
     }
 
-    pub fn run(&mut self) -> Result<()> {
-        let serial_device = setup_serial_device(std::io::stdin(), std::io::stdout())?;
-        let mut pio_device_manager = PortIODeviceManager::new(serial_device.clone())?;
+    /// Creates the legacy devices and registers them on the port I/O bus.
+    pub fn setup_devices(&mut self) -> Result<()> {
+        let serial_device = setup_serial_device(std::io::stdout())?;
+        let identity = IdentityDevice::new(self.identity.mac.0, self.identity.vsock_cid);
+        let mut pio_device_manager =
+            PortIODeviceManager::new(serial_device, self.boot_times.clone(), identity)?;
         pio_device_manager.register_devices(&self.vm)?;
 
-        let vcpu_exit_evt = self.start_threaded(pio_device_manager.io_bus.clone())?;
+        match self.irqchip_mode {
+            IrqChipMode::Kernel => pio_device_manager.register_irqfds(&self.vm)?,
+            IrqChipMode::Split => {
+                let gsi_routing = Arc::new(GsiRouting::new(self.vm.clone()));
+                let ioapic = Arc::new(BusDevice::IoApic(IoApic::new(gsi_routing)));
+                let mut mmio_bus = Bus::new();
+                mmio_bus.insert(
+                    ioapic.clone(),
+                    crate::arch::layout::IO_APIC_DEFAULT_PHYS_BASE.into(),
+                    IOAPIC_MMIO_SIZE,
+                )?;
+                self.mmio_bus = Arc::new(mmio_bus);
+                self.ioapic = Some(ioapic);
+                info!("split irqchip, ioapic emulated in the vmm");
+            }
+        }
+
+        self.pio_device_manager = Some(pio_device_manager);
+
+        Ok(())
+    }
+
+    pub fn run(
+        &mut self,
+        api_sock: Option<&Path>,
+        gdb_socket: Option<&SocketUrl>,
+    ) -> Result<VmExit> {
+        let pio_device_manager = self
+            .pio_device_manager
+            .as_ref()
+            .ok_or(anyhow::anyhow!("devices are not set up"))?;
+        let serial_device = pio_device_manager.stdio_serial.clone();
+        let pio_bus = pio_device_manager.io_bus.clone();
+        let pvpanic_evt = pio_device_manager.pvpanic_evt.try_clone()?;
+        let doorbells = pio_device_manager.doorbells.clone();
+        // Without the in-kernel irqchip, the device interrupts go through the userspace IOAPIC.
+        let irq_lines = match &self.ioapic {
+            Some(_) => pio_device_manager
+                .irq_lines()
+                .into_iter()
+                .map(|(evt, gsi)| Ok((evt.try_clone()?, gsi)))
+                .collect::<Result<Vec<_>>>()?,
+            None => Vec::new(),
+        };
+        let ioapic = self.ioapic.clone();
+
+        let api_server = api_sock.map(ApiServer::bind).transpose()?;
+
+        crate::vcpu::register_kick_signal_handler()?;
+
+        let gdb_stub = match gdb_socket {
+            Some(_) => {
+                // The vCPUs wait for the debugger before their first instruction.
+                self.vcpu_pause.request(PauseHolder::Debugger);
+                Some(Arc::new(GdbStub::new()?))
+            }
+            None => None,
+        };
+
+        let seccomp_filters = self.seccomp_filters()?;
+        let vcpu_filter = seccomp_filters.vcpu.clone();
+        let vmm_filter = seccomp_filters.vmm.clone();
+        let helper_filter = seccomp_filters.helper.clone();
+
+        let metrics = match &self.metrics_sink {
+            Some(sink) => {
+                let metrics =
+                    Arc::new(Metrics::new(&self.vm, &self.vcpus, self.boot_times.clone()));
+                crate::metrics::start_reporter(
+                    sink.clone(),
+                    self.metrics_interval,
+                    metrics.clone(),
+                    helper_filter.clone(),
+                )?;
+                Some(metrics)
+            }
+            None => None,
+        };
+
+        let vcpu_exit_evt = self.start_threaded(
+            pio_bus,
+            self.mmio_bus.clone(),
+            gdb_stub.clone(),
+            metrics,
+            vcpu_filter,
+        )?;
+
+        if let (Some(url), Some(stub)) = (gdb_socket, gdb_stub) {
+            crate::gdb::start_server(
+                url.clone(),
+                stub,
+                self.vcpus.clone(),
+                self.vcpu_handles.iter().map(|h| h.as_pthread_t()).collect(),
+                self.vcpu_pause.clone(),
+                self.guest_mem.clone(),
+                helper_filter,
+            )?;
+        }
 
         let stdin = std::io::stdin().lock();
         stdin
@@ -95,8 +388,29 @@ impl Vmm {
 
         poll_ctx.add(&vcpu_exit_evt.0, 0)?;
         poll_ctx.add(&stdin, 1)?;
+        if let Some(api_server) = &api_server {
+            poll_ctx.add(api_server.listener(), 2)?;
+        }
+        poll_ctx.add(&self.crash_evt.0, 3)?;
+        poll_ctx.add(&pvpanic_evt.0, 4)?;
+        poll_ctx.add(&self.memory_failed_evt.0, 5)?;
+        for (token, (evt, _)) in (IRQ_LINE_TOKEN..).zip(irq_lines.iter()) {
+            poll_ctx.add(evt, token)?;
+        }
+        for (index, evt) in doorbells.evts().enumerate() {
+            let token = u8::try_from(index)
+                .ok()
+                .and_then(|index| DOORBELL_TOKEN.checked_add(index))
+                .ok_or(anyhow::anyhow!("too many doorbells"))?;
+            poll_ctx.add(evt, token)?;
+        }
 
-        self.pio_device_manager = Some(pio_device_manager);
+        // Everything the event loop needs is set up, the helper threads spawned before install
+        // their own filter.
+        self.sched_config.apply_vmm()?;
+        if let Some(filter) = &vmm_filter {
+            crate::seccomp::apply(filter, "vmm")?;
+        }
 
         loop {
             let events = poll_ctx.wait().context("failed to wait for events")?;
@@ -104,86 +418,744 @@ impl Vmm {
                 match ev.token() {
                     0 => {
                         info!("vcpu stopped, main loop exit");
-                        return Ok(());
+                        return Ok(VmExit::Stopped);
                     }
                     1 => {
                         let mut out = [0u8; 64];
                         match stdin.read_raw(&mut out[..]) {
                             Ok(0) => {}
                             Ok(n) => {
-                                serial_device
-                                    .lock()
-                                    .expect("Poisoned lock")
-                                    .serial_mut()
-                                    .unwrap()
-                                    .serial
-                                    .enqueue_raw_bytes(&out[..n])
-                                    .expect("enqueue bytes failed");
+                                // A poisoned serial was isolated from the guest, input is dropped.
+                                match serial_device.serial().unwrap().lock() {
+                                    Ok(mut serial) => {
+                                        serial
+                                            .serial
+                                            .enqueue_raw_bytes(&out[..n])
+                                            .expect("enqueue bytes failed");
+                                    }
+                                    Err(_) => error!("serial device failed, dropping input"),
+                                }
                             }
                             Err(e) => {
                                 error!("error while reading stdin: {:?}", e);
                             }
                         }
                     }
-                    _ => unreachable!(),
+                    2 => {
+                        if let Some(api_server) = &api_server {
+                            if let Err(e) = api_server
+                                .handle_connection(|request| self.handle_api_request(request))
+                            {
+                                error!("error while handling api connection: {:?}", e);
+                            }
+                        }
+
+                        if self.migrated {
+                            info!("vm migrated, main loop exit");
+                            return Ok(VmExit::Migrated);
+                        }
+                    }
+                    3 => {
+                        self.crash_evt.read()?;
+                        self.handle_guest_crash();
+
+                        info!("guest crashed, main loop exit");
+                        return Ok(VmExit::GuestPanic);
+                    }
+                    4 => {
+                        pvpanic_evt.read()?;
+                        if self.handle_pvpanic()? {
+                            info!("guest panicked, main loop exit");
+                            return Ok(VmExit::GuestPanic);
+                        }
+                    }
+                    5 => {
+                        self.memory_failed_evt.read()?;
+                        anyhow::bail!("guest memory cannot be loaded from the snapshot anymore")
+                    }
+                    token if token < DOORBELL_TOKEN => {
+                        let Some((evt, gsi)) = irq_lines.get(usize::from(token - IRQ_LINE_TOKEN))
+                        else {
+                            continue;
+                        };
+                        evt.read()?;
+                        if let Some(ioapic) = ioapic.as_ref().and_then(|device| device.ioapic()) {
+                            // Each write of a device pulses its line.
+                            ioapic.set_irq(*gsi, true);
+                            ioapic.set_irq(*gsi, false);
+                        }
+                    }
+                    token => {
+                        doorbells.service((token - DOORBELL_TOKEN).into())?;
+                    }
                 }
             }
         }
     }
 
-    fn start_threaded(&mut self, pio_bus: Bus) -> Result<EventFdTrigger> {
-        let vcpu = match std::mem::take(&mut self.vcpu) {
-            Some(vcpu) => vcpu,
-            None => return Err(anyhow::anyhow!("vcpu is not initialized")),
+    fn handle_api_request(&mut self, request: ApiRequest) -> Result<String> {
+        match request {
+            ApiRequest::Pause => self.pause()?,
+            ApiRequest::Resume => self.resume()?,
+            ApiRequest::Snapshot {
+                path,
+                snapshot_type,
+            } => self.snapshot(&path, snapshot_type)?,
+            ApiRequest::Migrate { url } => self.migrate(&url)?,
+            ApiRequest::Identity => return Ok(self.identity.to_string()),
+            ApiRequest::CoreDump { path } => self.core_dump(&path)?,
+            ApiRequest::DirtyPages => {
+                self.dirty_tracker.sync(&self.vm, &self.guest_mem)?;
+                return Ok(self.dirty_tracker.dirty_pages().to_string());
+            }
+        }
+
+        Ok(String::new())
+    }
+
+    /// Writes a snapshot of the paused VM into the `snapshot_dir` directory. Any snapshot starts
+    /// a new dirty page checkpoint, which the next diff snapshot is relative to: a diff snapshot
+    /// records the snapshot taken or restored before as its base.
+    pub fn snapshot(&mut self, snapshot_dir: &Path, snapshot_type: SnapshotType) -> Result<()> {
+        if !self.is_paused() {
+            anyhow::bail!("vm must be paused to take a snapshot")
+        }
+
+        std::fs::create_dir_all(snapshot_dir).context("failed to create snapshot directory")?;
+        // Diff snapshots refer to their base by path, from any working directory.
+        let snapshot_dir =
+            std::path::absolute(snapshot_dir).context("failed to resolve snapshot directory")?;
+
+        let base = match snapshot_type {
+            SnapshotType::Full => None,
+            SnapshotType::Diff => {
+                let base = self.last_snapshot.clone().ok_or(anyhow::anyhow!(
+                    "a diff snapshot needs a base, take a full snapshot first"
+                ))?;
+                if base.dir == snapshot_dir {
+                    anyhow::bail!("a diff snapshot cannot overwrite its base snapshot")
+                }
+                Some(base)
+            }
         };
 
+        let mut state = self.save_state()?;
+        state.id = crate::identity::random_u64()?;
+
+        let dirty = self.dirty_tracker.checkpoint(&self.vm, &self.guest_mem)?;
+        let dirty_pages = base.as_ref().map(|_| &dirty[..]);
+
+        let memory_path = crate::snapshot::memory_file_path(&snapshot_dir);
+        let written =
+            crate::snapshot::memory::save_memory(&self.guest_mem, &memory_path, dirty_pages)
+                .and_then(|ranges| {
+                    state.memory = match base {
+                        Some(base) => MemoryLayer::Diff { base, ranges },
+                        None => MemoryLayer::Full,
+                    };
+                    state.save_file(&crate::snapshot::vmstate_file_path(&snapshot_dir))
+                });
+        if let Err(e) = written {
+            self.dirty_tracker.rollback(dirty);
+            return Err(e);
+        }
+
+        self.last_snapshot = Some(SnapshotRef {
+            dir: snapshot_dir.clone(),
+            id: state.id,
+        });
+        info!(
+            "{:?} snapshot written to {}, {} dirty pages",
+            snapshot_type,
+            snapshot_dir.display(),
+            dirty.iter().map(|bitmap| bitmap.count()).sum::<usize>()
+        );
+
+        Ok(())
+    }
+
+    /// Writes an ELF core file of the guest to `path`. A VM that is not paused is paused
+    /// meanwhile, so that all vCPUs are parked and the clock frozen during the dump.
+    pub fn core_dump(&mut self, path: &Path) -> Result<()> {
+        let running = !self.is_paused();
+        if running {
+            self.pause()?;
+        }
+
+        let result = crate::arch::coredump::write_core_dump(path, &self.guest_mem, &self.vcpus);
+
+        if running {
+            self.resume()?;
+        }
+        result?;
+        info!("guest core dumped to {}", path.display());
+
+        Ok(())
+    }
+
+    /// Stops the vCPUs after a vCPU reported a guest crash, and dumps the guest core when
+    /// `coredump_path` is set.
+    fn handle_guest_crash(&mut self) {
+        self.park_vcpus();
+
+        if let Some(path) = &self.coredump_path {
+            match crate::arch::coredump::write_core_dump(path, &self.guest_mem, &self.vcpus) {
+                Ok(()) => info!("guest core dumped to {}", path.display()),
+                Err(e) => error!("failed to dump guest core: {:?}", e),
+            }
+        }
+    }
+
+    /// Handles the events reported by the pvpanic device. Returns whether the VM has to stop.
+    fn handle_pvpanic(&mut self) -> Result<bool> {
+        let events = self
+            .pio_device_manager
+            .as_ref()
+            .ok_or(anyhow::anyhow!("devices are not set up"))?
+            .pvpanic
+            .pvpanic()
+            .unwrap()
+            .take_events();
+
+        if events & PVPANIC_CRASH_LOADED != 0 {
+            // The crash kernel takes over, the guest keeps running.
+            warn!("guest kernel panicked, booting its crash kernel");
+        }
+        if events & PVPANIC_PANICKED == 0 {
+            return Ok(false);
+        }
+        error!("guest kernel panicked");
+
+        if !self.pause_on_panic {
+            self.handle_guest_crash();
+            return Ok(true);
+        }
+
+        // Waits for every vCPU to park even when the debugger or a crash already asked them to,
+        // so that the dump sees a stopped guest.
+        if !self.is_paused() {
+            self.pause()?;
+        }
+        if let Some(path) = self.coredump_path.clone() {
+            if let Err(e) = self.core_dump(&path) {
+                error!("failed to dump guest core: {:?}", e);
+            }
+        }
+        info!("vm paused for inspection");
+
+        Ok(false)
+    }
+
+    /// Restores a snapshot taken by `snapshot` into this initialized but not started VM. The
+    /// memory of a diff snapshot is loaded on top of the memory of its bases.
+    pub fn restore_snapshot(
+        &mut self,
+        snapshot_dir: &Path,
+        restore_mode: MemoryRestoreMode,
+    ) -> Result<()> {
+        let snapshot_dir =
+            std::path::absolute(snapshot_dir).context("failed to resolve snapshot directory")?;
+        let state = MicrovmState::load_file(&crate::snapshot::vmstate_file_path(&snapshot_dir))?;
+        let layers = crate::snapshot::memory_layers(&snapshot_dir, &state)?;
+
+        match restore_mode {
+            MemoryRestoreMode::Eager => {
+                for (memory_path, layer) in layers.iter() {
+                    crate::snapshot::memory::restore_memory(&self.guest_mem, memory_path, layer)?
+                }
+            }
+            MemoryRestoreMode::Lazy | MemoryRestoreMode::LazyPrefetch => {
+                if state.memory != MemoryLayer::Full {
+                    anyhow::bail!(
+                        "lazy memory restore needs a full snapshot, restore diffs eagerly"
+                    )
+                }
+                let helper_filter = self.seccomp_filters()?.helper.clone();
+                crate::snapshot::uffd::restore_memory_lazy(
+                    &self.guest_mem,
+                    &crate::snapshot::memory_file_path(&snapshot_dir),
+                    self.page_size,
+                    restore_mode == MemoryRestoreMode::LazyPrefetch,
+                    self.memory_failed_evt.try_clone()?,
+                    helper_filter,
+                )?
+            }
+        }
+
+        self.restore_state(&state)?;
+
+        // The memory now matches the snapshot, the next diff snapshot is relative to it.
+        self.dirty_tracker.checkpoint(&self.vm, &self.guest_mem)?;
+        self.last_snapshot = Some(SnapshotRef {
+            dir: snapshot_dir.clone(),
+            id: state.id,
+        });
+        info!(
+            "vm restored from {}, {} memory layers, memory {:?}",
+            snapshot_dir.display(),
+            layers.len(),
+            restore_mode
+        );
+
+        Ok(())
+    }
+
+    /// Turns this VM, whose memory is mapped from the memory file of a full snapshot with the
+    /// template backend, into a clone of the snapshotted VM with the given identity.
+    pub fn clone_snapshot(&mut self, snapshot_dir: &Path, identity: VmIdentity) -> Result<()> {
+        let snapshot_dir =
+            std::path::absolute(snapshot_dir).context("failed to resolve snapshot directory")?;
+        let state = MicrovmState::load_file(&crate::snapshot::vmstate_file_path(&snapshot_dir))?;
+        if state.memory != MemoryLayer::Full {
+            anyhow::bail!("only full snapshots can be cloned")
+        }
+        self.restore_state(&state)?;
+        self.set_identity(identity)?;
+
+        // Only the pages written from now on differ from the template.
+        self.dirty_tracker.checkpoint(&self.vm, &self.guest_mem)?;
+        self.last_snapshot = Some(SnapshotRef {
+            dir: snapshot_dir.clone(),
+            id: state.id,
+        });
+        info!(
+            "vm cloned from {}, identity {}",
+            snapshot_dir.display(),
+            self.identity
+        );
+
+        Ok(())
+    }
+
+    /// Captures the vCPU, irqchip, PIT, clock and serial state of the paused VM.
+    pub fn save_state(&self) -> Result<MicrovmState> {
+        if self.irqchip_mode == IrqChipMode::Split {
+            anyhow::bail!("saving the vm state is not supported with the split irqchip")
+        }
+
+        let clock = self
+            .paused_clock
+            .ok_or(anyhow::anyhow!("vm must be paused to save its state"))?;
+
+        let msr_indices = crate::arch::state::supported_msr_indices(&self.kvm)?;
+        let vcpus = self
+            .vcpus
+            .iter()
+            .map(|vcpu| VcpuState::save(vcpu, &msr_indices))
+            .collect::<Result<Vec<_>>>()?;
+
+        let serial = self
+            .stdio_serial()?
+            .lock()
+            .map_err(|_| anyhow::anyhow!("serial device failed"))?
+            .serial
+            .state();
+
+        Ok(MicrovmState {
+            vm: VmState::save(&self.vm, clock)?,
+            cpu: self.cpu_model(),
+            vcpus,
+            serial,
+            identity: self.identity,
+            ..Default::default()
+        })
+    }
+
+    /// Loads a saved state into a VM that was initialized but not started yet.
+    pub fn restore_state(&mut self, state: &MicrovmState) -> Result<()> {
+        if self.irqchip_mode == IrqChipMode::Split {
+            anyhow::bail!("restoring a vm state is not supported with the split irqchip")
+        }
+        if state.vcpus.len() != self.vcpus.len() {
+            anyhow::bail!(
+                "vm state has {} vcpus, expected {}",
+                state.vcpus.len(),
+                self.vcpus.len()
+            )
+        }
+        state
+            .cpu
+            .check_matches(&self.cpu_model())
+            .context("vm state does not match the vcpus")?;
+
+        state.vm.restore(&self.vm)?;
+        for (vcpu, vcpu_state) in self.vcpus.iter().zip(state.vcpus.iter()) {
+            vcpu_state.restore(vcpu)?;
+        }
+
+        let interrupt_evt = self
+            .pio_device_manager
+            .as_ref()
+            .ok_or(anyhow::anyhow!("devices are not set up"))?
+            .com_evt_1_3
+            .try_clone()?;
+        let serial = Serial::from_state(
+            &state.serial,
+            interrupt_evt,
+            SerialEventsWrapper,
+            SerialOut::Stdout(std::io::stdout()),
+        )
+        .map_err(|e| anyhow::anyhow!("failed to restore serial: {:?}", e))?;
+
+        self.stdio_serial()?
+            .lock()
+            .map_err(|_| anyhow::anyhow!("serial device failed"))?
+            .serial = serial;
+
+        self.set_identity(state.identity)
+    }
+
+    /// Changes the identity of the VM, and the one its guest reads from the identity device.
+    fn set_identity(&mut self, identity: VmIdentity) -> Result<()> {
+        self.pio_device_manager
+            .as_ref()
+            .and_then(|manager| manager.identity.identity())
+            .ok_or(anyhow::anyhow!("devices are not set up"))?
+            .set_identity(identity.mac.0, identity.vsock_cid);
+        self.identity = identity;
+
+        Ok(())
+    }
+
+    fn stdio_serial(&self) -> Result<&std::sync::Mutex<crate::devices::SerialDevice>> {
+        self.pio_device_manager
+            .as_ref()
+            .and_then(|manager| manager.stdio_serial.serial())
+            .ok_or(anyhow::anyhow!("devices are not set up"))
+    }
+
+    /// Live migrates the running VM to a destination started with `--migrate-from`.
+    ///
+    /// Guest memory is first copied while the vCPUs keep running, then the pages they dirtied
+    /// meanwhile are copied again until few enough are left. The VM is then paused, the last
+    /// dirty pages and the device state are sent, and the VM stops once the destination
+    /// restored it. On failure the VM resumes and no dirty page is lost for later snapshots.
+    pub fn migrate(&mut self, url: &SocketUrl) -> Result<()> {
+        if self.is_paused() {
+            anyhow::bail!("vm must be running to be migrated")
+        }
+
+        let mut consumed = Vec::new();
+        let result = self.migrate_to(url, &mut consumed);
+
+        if let Err(e) = result {
+            for dirty in consumed {
+                self.dirty_tracker.rollback(dirty);
+            }
+            if self.is_paused() {
+                self.resume()?;
+            }
+
+            return Err(e);
+        }
+
+        self.migrated = true;
+        info!("vm migrated to {}", url);
+
+        Ok(())
+    }
+
+    fn migrate_to(&mut self, url: &SocketUrl, consumed: &mut Vec<Vec<DirtyBitmap>>) -> Result<()> {
+        let mut sender = MigrationSender::new(url.connect()?)?;
+        sender.send_header(&self.guest_mem, &self.cpu_model())?;
+
+        // Pages dirtied from here on are sent again by the following passes.
+        consumed.push(self.dirty_tracker.checkpoint(&self.vm, &self.guest_mem)?);
+        let sent = sender.send_memory(&self.guest_mem, None)?;
+        info!("migration: sent {} MiB of guest memory", sent >> 20);
+
+        for pass in 1..=MIGRATION_MAX_PASSES {
+            let dirty = self.dirty_tracker.checkpoint(&self.vm, &self.guest_mem)?;
+            let dirty_pages: usize = dirty.iter().map(DirtyBitmap::count).sum();
+
+            sender.send_memory(&self.guest_mem, Some(&dirty))?;
+            consumed.push(dirty);
+            info!("migration: pass {} sent {} dirty pages", pass, dirty_pages);
+
+            if dirty_pages < MIGRATION_DIRTY_THRESHOLD {
+                break;
+            }
+        }
+
+        self.pause()?;
+
+        let dirty = self.dirty_tracker.checkpoint(&self.vm, &self.guest_mem)?;
+        sender.send_memory(&self.guest_mem, Some(&dirty))?;
+        info!(
+            "migration: sent {} dirty pages with the vm paused",
+            dirty.iter().map(DirtyBitmap::count).sum::<usize>()
+        );
+        consumed.push(dirty);
+
+        sender.send_state(&self.save_state()?)
+    }
+
+    /// Receives a VM live migrated by `migrate` into this initialized but not started VM.
+    pub fn receive_migration(&mut self, url: &SocketUrl) -> Result<()> {
+        let mut receiver = MigrationReceiver::new(url.accept()?)?;
+        receiver.receive_header(&self.guest_mem, &self.cpu_model(), self.memory_zeroed)?;
+
+        let state = receiver.receive_memory_and_state(&self.guest_mem)?;
+        if let Err(e) = self.restore_state(&state) {
+            let _ = receiver.send_ack(false);
+            return Err(e);
+        }
+        receiver.send_ack(true)?;
+
+        // The received pages went through the VMM, they are not dirty for the next snapshot.
+        self.dirty_tracker.checkpoint(&self.vm, &self.guest_mem)?;
+        info!("incoming migration complete");
+
+        Ok(())
+    }
+
+    /// Whether `pause` stopped the vCPUs and froze the clock. A pause that was only requested,
+    /// by a vCPU reporting a crash or by the debugger, does not count: vCPUs may still be in
+    /// KVM_RUN then.
+    fn is_paused(&self) -> bool {
+        self.paused_clock.is_some()
+    }
+
+    /// Stops all vCPUs outside of KVM_RUN and freezes the guest kvmclock.
+    pub fn pause(&mut self) -> Result<()> {
+        if self.is_paused() {
+            anyhow::bail!("vm is already paused")
+        }
+
+        self.park_vcpus();
+
+        self.paused_clock = Some(crate::arch::clock::save_clock(&self.vm)?);
+        info!("vm paused");
+
+        Ok(())
+    }
+
+    /// The filters of `seccomp_level`, compiled when the first thread needs them.
+    fn seccomp_filters(&mut self) -> Result<&SeccompFilters> {
+        if self.seccomp_filters.is_none() {
+            self.seccomp_filters = Some(SeccompFilters::new(self.seccomp_level)?);
+        }
+
+        Ok(self.seccomp_filters.as_ref().unwrap())
+    }
+
+    /// Stops all vCPUs outside of KVM_RUN, without touching the clock.
+    fn park_vcpus(&self) {
+        let handles = &self.vcpu_handles;
+        self.vcpu_pause.pause(PauseHolder::Vmm, || {
+            for handle in handles.iter() {
+                // The thread may be gone already, there is nothing to kick then.
+                let _ = handle.kill(crate::vcpu::vcpu_kick_signal());
+            }
+        });
+    }
+
+    /// Restores the kvmclock saved by `pause` and lets the vCPUs run again.
+    pub fn resume(&mut self) -> Result<()> {
+        let clock = self
+            .paused_clock
+            .take()
+            .ok_or(anyhow::anyhow!("vm is not paused"))?;
+
+        crate::arch::clock::restore_clock(&self.vm, &clock)?;
+        self.vcpu_pause.resume(PauseHolder::Vmm);
+        info!("vm resumed");
+
+        Ok(())
+    }
+
+    fn start_threaded(
+        &mut self,
+        pio_bus: Arc<Bus>,
+        mmio_bus: Arc<Bus>,
+        gdb_stub: Option<Arc<GdbStub>>,
+        metrics: Option<Arc<Metrics>>,
+        seccomp_filter: Option<Arc<BpfProgram>>,
+    ) -> Result<EventFdTrigger> {
+        if self.vcpus.is_empty() {
+            return Err(anyhow::anyhow!("vcpu is not initialized"));
+        }
+
+        self.sched_config.vcpu_affinity.check(self.vcpus.len())?;
+
         let exit_evt = EventFdTrigger::new();
-        let vcpu_exit_evt = exit_evt.try_clone().context("failed to clone eventfd")?;
-
-        let builder = std::thread::Builder::new();
-        let _ = builder
-            .name(String::from("vcpu0"))
-            .spawn(move || {
-                loop {
-                    match vcpu.run() {
-                        Ok(run) => match run {
-                            VcpuExit::IoIn(addr, data) => {
-                                pio_bus.read(addr.into(), data);
-                            }
-                            VcpuExit::IoOut(addr, data) => {
-                                pio_bus.write(addr.into(), data);
-                            }
-                            VcpuExit::MmioRead(_, _) => {
-                                info!("mmio read");
-                            }
-                            VcpuExit::MmioWrite(_, _) => {
-                                info!("mmio write");
-                            }
-                            VcpuExit::Hlt => {
-                                info!("KVM_EXIT_HLT");
-                                break;
+        let unhandled_io = self
+            .trace_unhandled_io
+            .then(|| Arc::new(UnhandledIo::default()));
+
+        for (cpu_index, vcpu) in self.vcpus.iter().cloned().enumerate() {
+            let pio_bus = pio_bus.clone();
+            let mmio_bus = mmio_bus.clone();
+            let exit_evt = exit_evt.try_clone().context("failed to clone eventfd")?;
+            let vcpu_pause = self.vcpu_pause.clone();
+            let gdb_stub = gdb_stub.clone();
+            let metrics = metrics.clone();
+            let seccomp_filter = seccomp_filter.clone();
+            let cgroup = self.vcpu_cgroup.clone();
+            let sched_config = self.sched_config.clone();
+            let boot_times = self.boot_times.clone();
+            let unhandled_io = unhandled_io.clone();
+            let guest_mem = self.guest_mem.clone();
+            let kernel_symbols = self.kernel_symbols.clone();
+            let crash_evt = self
+                .crash_evt
+                .try_clone()
+                .context("failed to clone eventfd")?;
+
+            vcpu_pause.vcpu_started();
+
+            let builder = std::thread::Builder::new();
+            let handle = builder
+                .name(format!("vcpu{}", cpu_index))
+                .spawn(move || {
+                    let report_crash = |reason: &str| match crate::arch::crash::crash_report(
+                        &vcpu,
+                        &guest_mem,
+                        kernel_symbols.as_deref(),
+                    ) {
+                        Ok(report) => error!("vcpu{} {}\n{}", cpu_index, reason, report),
+                        Err(e) => error!("vcpu{} {}, no crash report: {:?}", cpu_index, reason, e),
+                    };
+                    let trace_unhandled = |access: IoAccess| {
+                        if let Some(unhandled_io) = &unhandled_io {
+                            unhandled_io.record(access);
+                        }
+                    };
+
+                    if let Some(cgroup) = &cgroup {
+                        if let Err(e) = cgroup.join() {
+                            error!("vcpu{} stays in the vmm cgroup: {:?}", cpu_index, e);
+                        }
+                    }
+
+                    let setup = sched_config.apply_vcpu(cpu_index).and_then(|_| {
+                        seccomp_filter.as_ref().map_or(Ok(()), |filter| {
+                            crate::seccomp::apply(filter, &format!("vcpu{}", cpu_index))
+                        })
+                    });
+                    if let Err(e) = setup {
+                        error!("vcpu{} not started: {:?}", cpu_index, e);
+                        vcpu_pause.vcpu_stopped();
+                        exit_evt.trigger().expect("failed to write to exit_evt");
+                        return;
+                    }
+
+                    loop {
+                        vcpu_pause.park_if_requested();
+
+                        boot_times.record_first_run();
+                        match vcpu.run() {
+                            Ok(run) => {
+                                let reason = ExitReason::from_exit(&run);
+                                let start = Instant::now();
+
+                                match run {
+                                    VcpuExit::IoIn(port, data) => {
+                                        if !pio_bus.io_in(port, data) {
+                                            trace_unhandled(IoAccess::PioRead {
+                                                port,
+                                                size: data.len(),
+                                            });
+                                        }
+                                    }
+                                    VcpuExit::IoOut(port, data) => {
+                                        if !pio_bus.io_out(port, data) {
+                                            trace_unhandled(IoAccess::PioWrite {
+                                                port,
+                                                size: data.len(),
+                                            });
+                                        }
+                                    }
+                                    VcpuExit::MmioRead(addr, data) => {
+                                        if !mmio_bus.read(addr, data) {
+                                            data.fill(0xff);
+                                            trace_unhandled(IoAccess::MmioRead {
+                                                addr,
+                                                size: data.len(),
+                                            });
+                                        }
+                                    }
+                                    VcpuExit::MmioWrite(addr, data) => {
+                                        if !mmio_bus.write(addr, data) {
+                                            trace_unhandled(IoAccess::MmioWrite {
+                                                addr,
+                                                size: data.len(),
+                                            });
+                                        }
+                                    }
+                                    VcpuExit::IoapicEoi(vector) => {
+                                        // The guest acknowledged a level triggered interrupt of
+                                        // the userspace IOAPIC.
+                                        let eoi = u64::from(
+                                            crate::arch::layout::IO_APIC_DEFAULT_PHYS_BASE,
+                                        ) + IOAPIC_EOI;
+                                        mmio_bus.write(eoi, &u32::from(vector).to_le_bytes());
+                                    }
+                                    VcpuExit::Debug(debug) => match &gdb_stub {
+                                        Some(gdb_stub) => gdb_stub.vcpu_debug_exit(
+                                            cpu_index,
+                                            &vcpu,
+                                            DebugExit::from(&debug),
+                                            &vcpu_pause,
+                                        ),
+                                        None => info!("KVM_EXIT_DEBUG"),
+                                    },
+                                    VcpuExit::Hlt => {
+                                        info!("KVM_EXIT_HLT");
+                                        break;
+                                    }
+                                    VcpuExit::SystemEvent(KVM_SYSTEM_EVENT_CRASH, _) => {
+                                        report_crash("guest crashed (KVM_SYSTEM_EVENT_CRASH)");
+
+                                        // Parks right after, the main loop stops the VM.
+                                        vcpu_pause.request(PauseHolder::Vmm);
+                                        crash_evt.trigger().expect("failed to write to crash_evt");
+                                    }
+                                    VcpuExit::Shutdown => {
+                                        // Triple fault, or a reset the guest asked for.
+                                        report_crash("shut down (KVM_EXIT_SHUTDOWN)");
+                                        break;
+                                    }
+                                    r => {
+                                        let reason = format!("unexpected exit {:?}", r);
+                                        report_crash(&reason);
+                                        break;
+                                    }
+                                }
+
+                                if let Some(metrics) = &metrics {
+                                    metrics.vcpus[cpu_index]
+                                        .lock()
+                                        .expect("Poisoned lock")
+                                        .record_exit(reason, start.elapsed());
+                                }
                             }
-                            VcpuExit::Shutdown => {
-                                info!("KVM_EXIT_SHUTDOWN");
-                                break;
+
+                            // Kicked out of KVM_RUN, most likely to be paused.
+                            Err(e) if e.errno() == libc::EINTR => {
+                                if let Some(metrics) = &metrics {
+                                    metrics.vcpus[cpu_index]
+                                        .lock()
+                                        .expect("Poisoned lock")
+                                        .record_exit(ExitReason::Interrupted, Duration::ZERO);
+                                }
                             }
-                            r => {
-                                info!("KVM_EXIT: {:?}", r);
+
+                            Err(e) => {
+                                report_crash(&format!("run error {:?}", e));
                                 break;
                             }
-                        },
-
-                        Err(e) => {
-                            error!("vm run error: {:?}", e);
-                            break;
                         }
                     }
-                }
 
-                exit_evt.trigger().expect("failed to write to exit_evt");
-            })
-            .context("failed to spawn vcpu thread");
+                    vcpu_pause.vcpu_stopped();
+                    exit_evt.trigger().expect("failed to write to exit_evt");
+                })
+                .context("failed to spawn vcpu thread")?;
+
+            self.vcpu_handles.push(handle);
+        }
 
-        Ok(vcpu_exit_evt)
+        Ok(exit_evt)
     }
 }